@@ -0,0 +1,73 @@
+//! Copies of the stubs taken once they are converted, see `--backup-dir`.
+
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use restore_symlink::{convert::create_temp_sibling, paths, platform};
+
+/// Copy `content` of the file at `file_path` below `backup_dir`, with the
+/// mode and modification time of `metadata`
+///
+/// The copy mirrors the path of the file relative to the first of `roots`
+/// it is under, or its absolute path if none. It is renamed into place
+/// once complete, replacing an earlier copy of the same path.
+pub fn back_up(
+    backup_dir: &Path,
+    roots: &[PathBuf],
+    file_path: &Path,
+    content: &[u8],
+    metadata: &Metadata,
+) -> io::Result<()> {
+    let backup = backup_dir.join(mirrored(roots, file_path)?);
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = create_temp_sibling(&backup, |temp| {
+        File::options().write(true).create_new(true).open(temp)?;
+        Ok(())
+    })?;
+    let written = write(&temp, content, metadata).and_then(|()| fs::rename(&temp, &backup));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+fn write(path: &Path, content: &[u8], metadata: &Metadata) -> io::Result<()> {
+    let mut file = File::options().write(true).open(path)?;
+    file.write_all(content)?;
+    platform::set_mode(path, platform::mode(metadata))?;
+    file.set_modified(metadata.modified()?)
+}
+
+/// Path of `file_path` relative to the first of `roots` holding it, compared lexically
+fn mirrored(roots: &[PathBuf], file_path: &Path) -> io::Result<PathBuf> {
+    let file_path = paths::normalize(&std::path::absolute(file_path)?);
+    for root in roots {
+        let root = paths::normalize(&std::path::absolute(root)?);
+        match file_path.strip_prefix(&root) {
+            // The root is the file itself.
+            Ok(relative) if relative.as_os_str().is_empty() => {
+                return Ok(file_path.file_name().map(PathBuf::from).unwrap_or_default())
+            }
+            Ok(relative) => return Ok(relative.to_owned()),
+            Err(_) => {}
+        }
+    }
+    Ok(file_path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect())
+}
+
+/// Whether `backup_dir` is `root` or below it, where the walk would convert the copies too
+pub fn is_inside(backup_dir: &Path, root: &Path) -> bool {
+    let absolute = |path: &Path| std::path::absolute(path).map(|path| paths::normalize(&path));
+    match (absolute(backup_dir), absolute(root)) {
+        (Ok(backup_dir), Ok(root)) => backup_dir.starts_with(root),
+        _ => false,
+    }
+}
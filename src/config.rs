@@ -0,0 +1,111 @@
+//! Default options read from TOML config files.
+//!
+//! Every `key = value` becomes the `--key value` option, `true` becomes the
+//! flag `--key` and arrays repeat the option. Files are read in the order of
+//! [`files`], the command line coming last, so later values win.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::Parser;
+use toml::{Table, Value};
+
+use crate::{Args, EXIT_USAGE};
+
+/// Name of the per-directory config file, looked for in the first path.
+const DIR_CONFIG: &str = ".restore-symlink.toml";
+
+/// The program arguments with the options of the config files before them
+pub fn parse() -> Args {
+    let argv: Vec<OsString> = env::args_os().collect();
+    let (program, argv) = match argv.split_first() {
+        Some((program, argv)) => (vec![program.clone()], argv),
+        None => (Vec::new(), &argv[..]),
+    };
+    let args = Args::parse_from(program.iter().chain(argv));
+    if args.no_config {
+        return args;
+    }
+
+    let mut options = Vec::new();
+    for file in files(&args) {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(_) if !file.exists() => continue,
+            Err(error) => fail(&file, &error.to_string()),
+        };
+        let table: Table = match content.parse() {
+            Ok(table) => table,
+            Err(error) => fail(&file, error.message()),
+        };
+        let file_options = match to_options(&table) {
+            Ok(file_options) => file_options,
+            Err(reason) => fail(&file, &reason),
+        };
+        if let Err(error) = Args::try_parse_from(program.iter().chain(&file_options)) {
+            let message = error.to_string();
+            let reason = message.lines().next().unwrap_or_default();
+            fail(&file, reason.trim_start_matches("error: "))
+        }
+        options.extend(file_options);
+    }
+    if options.is_empty() {
+        return args;
+    }
+    Args::parse_from(program.iter().chain(&options).chain(argv))
+}
+
+/// Config files to read: the user's, then the one in the first path if it is a dir
+fn files(args: &Args) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(config_home) = config_home {
+        files.push(config_home.join("restore-symlink").join("config.toml"));
+    }
+    if let Some(root) = args.paths.first().filter(|root| root.is_dir()) {
+        files.push(root.join(DIR_CONFIG));
+    }
+    files
+}
+
+fn to_options(table: &Table) -> Result<Vec<OsString>, String> {
+    let mut options = Vec::new();
+    for (key, value) in table {
+        let option = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => options.push(option.clone().into()),
+                Value::Boolean(false) => {}
+                Value::String(text) => options.extend([option.clone().into(), text.into()]),
+                Value::Integer(number) => {
+                    options.extend([option.clone().into(), number.to_string().into()])
+                }
+                Value::Float(number) => {
+                    options.extend([option.clone().into(), number.to_string().into()])
+                }
+                _ => return Err(format!("unsupported value for '{key}'")),
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn fail(file: &Path, reason: &str) -> ! {
+    eprintln!(
+        "Invalid config file '{}': {}",
+        file.to_string_lossy(),
+        reason
+    );
+    process::exit(EXIT_USAGE)
+}
@@ -0,0 +1,946 @@
+//! Converting stubs into symlinks, for programs other than the command line tool.
+
+use std::{
+    error,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, Metadata},
+    io::{self, ErrorKind, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use globset::Glob;
+
+use crate::{
+    fs_ops::{FsOps, RealFs},
+    paths, platform,
+    scan::{
+        classify_target, inspect, scan_with, target_from_content, Candidate, Classification,
+        InvalidOptions, ScanOptions, ScanOptionsBuilder,
+    },
+    summary::{Outcome, SkipReason},
+};
+
+/// Delay before the first retry, growing linearly with each attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Bytes of a file name kept in its temporary sibling, leaving room for the suffix within `NAME_MAX`.
+const TEMP_NAME_KEPT: usize = 200;
+
+/// Temporary names tried by [`create_temp_sibling`] before giving up.
+const TEMP_NAME_ATTEMPTS: u32 = 8;
+
+/// Number of the next temporary sibling, so no two in the process share a name
+static TEMP_SIBLINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Asked with the stub path and its target before each conversion
+type Confirm = dyn Fn(&Path, &OsStr) -> bool + Send + Sync;
+
+/// What a stub is replaced with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replacement {
+    Symlink,
+    /// A hardlink of this file
+    Hardlink(PathBuf),
+    /// A copy of this file or directory
+    Copy(PathBuf),
+}
+
+/// Why [`Converter::replace`] left a stub as it was
+#[derive(Debug)]
+pub enum ReplaceError {
+    /// Its dir is writable by everyone, see [`Converter::allow_unsafe`]
+    UnsafeParent,
+    /// It is no longer the file that was read, or was written to since with
+    /// [`Converter::no_clobber`]
+    Stale,
+    /// Reading it again or making its replacement failed
+    Io(io::Error),
+}
+
+impl fmt::Display for ReplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplaceError::UnsafeParent => f.write_str("its dir is writable by everyone"),
+            ReplaceError::Stale => f.write_str("changed since it was read"),
+            ReplaceError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ReplaceError {}
+
+/// Form a target is rewritten to before linking to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetForm {
+    /// Relative targets resolved from the dir of their link
+    Absolute,
+    /// Absolute targets made relative to the dir of their link
+    Relative,
+}
+
+/// What a [`Converter`] does with the stubs its [`ScanOptions`] find.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Repeat filesystem calls failing transiently up to this many more times
+    pub retries: u32,
+    /// Also replace stubs in dirs everyone can write to
+    pub allow_unsafe: bool,
+    /// Leave stubs written to since they were read, not only those replaced
+    pub no_clobber: bool,
+    /// Rewrite targets to this form before linking to them, kept as stored if not set
+    pub target_form: Option<TargetForm>,
+}
+
+/// Turns stubs into symlinks, checking them like [`scan`](crate::scan::scan) does.
+#[derive(Clone)]
+pub struct Converter {
+    options: ScanOptions,
+    convert: ConvertOptions,
+    ops: Arc<dyn FsOps>,
+    confirm: Option<Arc<Confirm>>,
+}
+
+impl fmt::Debug for Converter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Converter")
+            .field("options", &self.options)
+            .field("convert", &self.convert)
+            .field("confirm", &self.confirm.is_some())
+            .finish()
+    }
+}
+
+impl Converter {
+    /// A converter finding stubs by `options`, with the defaults of [`ConvertOptions`]
+    pub fn new(options: ScanOptions) -> Self {
+        Converter {
+            options,
+            convert: ConvertOptions::default(),
+            ops: Arc::new(RealFs),
+            confirm: None,
+        }
+    }
+
+    /// Set up a converter with the defaults of the command line tool, not
+    /// descending into directories unless [`recursive`](ConverterBuilder::recursive)
+    pub fn builder() -> ConverterBuilder {
+        ConverterBuilder {
+            scan: ScanOptions::builder(),
+            convert: ConvertOptions::default(),
+            recursive: false,
+            max_depth: None,
+            excludes: Vec::new(),
+            includes: Vec::new(),
+            absolute: false,
+            relative: false,
+        }
+    }
+
+    /// The options conversions are made by
+    pub fn convert_options(&self) -> &ConvertOptions {
+        &self.convert
+    }
+
+    /// Make every change and re-read through `ops` instead of the real filesystem
+    pub fn with_ops(mut self, ops: Arc<dyn FsOps>) -> Self {
+        self.ops = ops;
+        self
+    }
+
+    /// Repeat filesystem calls failing transiently up to `retries` more times
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.convert.retries = retries;
+        self
+    }
+
+    /// Also replace stubs in dirs everyone can write to, where others could
+    /// swap them for something else meanwhile
+    pub fn allow_unsafe(mut self, allow_unsafe: bool) -> Self {
+        self.convert.allow_unsafe = allow_unsafe;
+        self
+    }
+
+    /// Leave stubs written to since they were read, not only those replaced
+    pub fn no_clobber(mut self, no_clobber: bool) -> Self {
+        self.convert.no_clobber = no_clobber;
+        self
+    }
+
+    /// The filesystem changes are made through
+    pub fn ops(&self) -> &dyn FsOps {
+        &*self.ops
+    }
+
+    /// Only convert stubs `confirm` returns `true` for, given the stub path
+    /// and its target, skipping the others as declined
+    pub fn confirm(
+        mut self,
+        confirm: impl Fn(&Path, &OsStr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.confirm = Some(Arc::new(confirm));
+        self
+    }
+
+    /// Replace the stub at `path` with a symlink to the target it stores
+    ///
+    /// The symlink is made as [`replace`](Self::replace) does. Files that
+    /// are no stubs or changed since they were read come back as
+    /// [`Outcome::Skipped`], entries that cannot be handled as
+    /// [`Outcome::Failed`], and failing filesystem calls as errors.
+    pub fn convert_file(&self, path: &Path) -> io::Result<Outcome> {
+        self.convert(inspect(path, &self.options))
+    }
+
+    /// Convert every stub below `root`, yielding the outcome for each entry
+    ///
+    /// The walk is lazy, each stub is converted when its outcome is taken.
+    pub fn convert_tree<'a>(
+        &'a self,
+        root: &Path,
+    ) -> impl Iterator<Item = (PathBuf, io::Result<Outcome>)> + 'a {
+        scan_with(&*self.ops, root, &self.options)
+            .filter(|candidate| {
+                !matches!(candidate.classification, Classification::Directory { .. })
+            })
+            .map(|candidate| (candidate.path.clone(), self.convert(candidate)))
+    }
+
+    fn convert(&self, candidate: Candidate) -> io::Result<Outcome> {
+        let path = &candidate.path;
+        match candidate.classification {
+            Classification::Convertible { target } => {
+                if let Some(confirm) = &self.confirm {
+                    if !confirm(path, &target) {
+                        return Ok(Outcome::Skipped(SkipReason::Declined));
+                    }
+                }
+                let read = match candidate.metadata {
+                    Some(metadata) => metadata,
+                    None => self.ops.metadata(path)?,
+                };
+                let target = match self.convert.target_form {
+                    Some(form) => reshape_target(path, &target, form).unwrap_or(target),
+                    None => target,
+                };
+                match self.replace(path, &target, &read, &Replacement::Symlink) {
+                    Ok(()) => Ok(Outcome::Converted),
+                    Err(ReplaceError::UnsafeParent) => {
+                        Ok(Outcome::Skipped(SkipReason::UnsafeParent))
+                    }
+                    Err(ReplaceError::Stale) => Ok(Outcome::Skipped(SkipReason::Stale)),
+                    Err(ReplaceError::Io(error)) => Err(error),
+                }
+            }
+            Classification::Error(error) => Err(error),
+            classification => {
+                Ok(SkipReason::of(&classification).map_or(Outcome::Failed, Outcome::Skipped))
+            }
+        }
+    }
+
+    /// Replace the stub at `path`, read with the metadata `read`, with a
+    /// symlink to `target` or what `replacement` asks for
+    ///
+    /// Right before, the stub is checked to still be the file read and, unless
+    /// [`allow_unsafe`](Self::allow_unsafe), to be in a dir not everyone can
+    /// write to. The link or copy is made under a temporary name in the same
+    /// directory and renamed over the stub, so the stub is never missing.
+    /// Transient failures are retried. On failure the stub is left as it was.
+    /// A copied directory cannot be renamed over a file, so the stub is
+    /// removed first then.
+    pub fn replace(
+        &self,
+        path: &Path,
+        target: &OsStr,
+        read: &Metadata,
+        replacement: &Replacement,
+    ) -> Result<(), ReplaceError> {
+        if !self.convert.allow_unsafe && in_unsafe_dir(path) {
+            return Err(ReplaceError::UnsafeParent);
+        }
+        match self.ops.metadata(path) {
+            Ok(current)
+                if same_file(read, &current)
+                    && (!self.convert.no_clobber || unchanged(read, &current)) => {}
+            Ok(_) => return Err(ReplaceError::Stale),
+            Err(error) => return Err(ReplaceError::Io(error)),
+        }
+
+        let ops = &*self.ops;
+        let created = create_temp_sibling(path, |temp| {
+            with_retry(self.convert.retries, || match replacement {
+                Replacement::Symlink => ops.symlink(target, temp),
+                Replacement::Hardlink(original) => ops.hard_link(original, temp),
+                Replacement::Copy(original) => ops.copy(original, temp),
+            })
+        });
+        let temp = match created {
+            Ok(temp) => temp,
+            Err(error) => {
+                let reason = match error.kind() {
+                    ErrorKind::CrossesDevices => {
+                        "target is on another filesystem, cannot hardlink it"
+                    }
+                    ErrorKind::PermissionDenied => "parent directory is not writable",
+                    ErrorKind::ReadOnlyFilesystem => {
+                        "parent directory is on a read-only filesystem"
+                    }
+                    _ => return Err(ReplaceError::Io(error)),
+                };
+                return Err(ReplaceError::Io(io::Error::new(error.kind(), reason)));
+            }
+        };
+        let copied_dir = matches!(replacement, Replacement::Copy(original) if original.is_dir());
+        if copied_dir {
+            if let Err(error) = with_retry(self.convert.retries, || ops.remove_file(path)) {
+                let _ = ops.remove_dir_all(&temp);
+                return Err(ReplaceError::Io(io::Error::new(
+                    error.kind(),
+                    format!("cannot remove it for the copied dir: {error}"),
+                )));
+            }
+        }
+        if let Err(error) = with_retry(self.convert.retries, || ops.rename(&temp, path)) {
+            if copied_dir {
+                return Err(ReplaceError::Io(io::Error::new(
+                    error.kind(),
+                    format!(
+                        "cannot rename the copied dir over it, left at '{}': {error}",
+                        temp.display()
+                    ),
+                )));
+            }
+            let reason = match error.kind() {
+                ErrorKind::CrossesDevices | ErrorKind::ResourceBusy => {
+                    "is a mount point, cannot replace it".to_owned()
+                }
+                _ => format!("cannot rename the new link over it: {error}"),
+            };
+            let reason = match ops.remove_file(&temp) {
+                Ok(()) => reason,
+                Err(error) => format!("{reason}, and cannot remove '{}': {error}", temp.display()),
+            };
+            return Err(ReplaceError::Io(io::Error::new(error.kind(), reason)));
+        }
+        Ok(())
+    }
+
+    /// Read stub content from `reader` and create a symlink to it at `dest`
+    ///
+    /// Relative targets are resolved from the directory of `dest`. The symlink
+    /// is only created when the returned classification is
+    /// [`Classification::Convertible`]; `dest` must not exist yet.
+    pub fn convert_reader(&self, mut reader: impl Read, dest: &Path) -> io::Result<Classification> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        let len = content.len() as u64;
+        if len == 0 {
+            return Ok(Classification::Empty);
+        }
+        if len > self.options.len {
+            return Ok(Classification::TooBig { len });
+        }
+        if len < self.options.min_len {
+            return Ok(Classification::TooSmall { len });
+        }
+
+        let classification = match target_from_content(content, &self.options) {
+            Ok(target) => classify_target(dest, target, &self.options),
+            Err(classification) => classification,
+        };
+        if let Classification::Convertible { target } = &classification {
+            self.ops.symlink(target, dest)?;
+        }
+        Ok(classification)
+    }
+}
+
+/// Builds a [`Converter`] step by step, checking its options in [`build`](Self::build).
+#[derive(Debug, Clone)]
+pub struct ConverterBuilder {
+    scan: ScanOptionsBuilder,
+    convert: ConvertOptions,
+    recursive: bool,
+    max_depth: Option<usize>,
+    excludes: Vec<String>,
+    includes: Vec<String>,
+    absolute: bool,
+    relative: bool,
+}
+
+impl ConverterBuilder {
+    /// Skip files longer than `len` bytes as too big, 512 by default
+    pub fn len(mut self, len: u64) -> Self {
+        self.scan = self.scan.len(len);
+        self
+    }
+
+    /// Skip files shorter than `min_len` bytes as too small, 1 by default
+    pub fn min_len(mut self, min_len: u64) -> Self {
+        self.scan = self.scan.min_len(min_len);
+        self
+    }
+
+    /// Whether to convert the stubs in every directory below the root
+    /// rather than only those right in it. Off by default.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Leave out entries more than `max_depth` levels below the root, even
+    /// when [`recursive`](Self::recursive)
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Leave out entries matching the glob `glob`, see
+    /// [`ScanOptionsBuilder::exclude`]. May be given several times.
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.excludes.push(glob.to_owned());
+        self
+    }
+
+    /// Leave out files matching none of the globs given this way, see
+    /// [`ScanOptionsBuilder::include`]
+    pub fn include(mut self, glob: &str) -> Self {
+        self.includes.push(glob.to_owned());
+        self
+    }
+
+    /// Whether to leave out entries ignored by `.gitignore` and `.ignore`
+    /// files. On by default.
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.scan = self.scan.gitignore(gitignore);
+        self
+    }
+
+    /// Whether to leave out hidden files and dirs below the root. On by default.
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.scan = self.scan.skip_hidden(skip_hidden);
+        self
+    }
+
+    /// Whether to skip stubs whose target has control characters inside. On
+    /// by default.
+    pub fn strict_path(mut self, strict_path: bool) -> Self {
+        self.scan = self.scan.strict_path(strict_path);
+        self
+    }
+
+    /// Whether to resolve relative targets from the dir of their link and
+    /// link to the absolute path. Cannot be combined with [`relative`](Self::relative).
+    pub fn absolute(mut self, absolute: bool) -> Self {
+        self.absolute = absolute;
+        self
+    }
+
+    /// Whether to link to absolute targets by a path relative to the dir of
+    /// the link. Cannot be combined with [`absolute`](Self::absolute).
+    pub fn relative(mut self, relative: bool) -> Self {
+        self.relative = relative;
+        self
+    }
+
+    /// Repeat filesystem calls failing transiently up to `retries` more times
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.convert.retries = retries;
+        self
+    }
+
+    /// Whether to also replace stubs in dirs everyone can write to. Off by default.
+    pub fn allow_unsafe(mut self, allow_unsafe: bool) -> Self {
+        self.convert.allow_unsafe = allow_unsafe;
+        self
+    }
+
+    /// Whether to leave stubs written to since they were read. Off by default.
+    pub fn no_clobber(mut self, no_clobber: bool) -> Self {
+        self.convert.no_clobber = no_clobber;
+        self
+    }
+
+    /// The converter, or why the options given do not go together
+    pub fn build(self) -> Result<Converter, InvalidOptions> {
+        let mut convert = self.convert;
+        convert.target_form = match (self.absolute, self.relative) {
+            (true, true) => {
+                return Err(InvalidOptions(
+                    "targets cannot be made both absolute and relative".to_owned(),
+                ))
+            }
+            (true, false) => Some(TargetForm::Absolute),
+            (false, true) => Some(TargetForm::Relative),
+            (false, false) => None,
+        };
+        let glob = |glob: &String| {
+            Glob::new(glob)
+                .map_err(|error| InvalidOptions(format!("invalid glob '{glob}': {error}")))
+        };
+        let mut scan = self.scan;
+        for exclude in &self.excludes {
+            scan = scan.exclude(glob(exclude)?);
+        }
+        for include in &self.includes {
+            scan = scan.include(glob(include)?);
+        }
+        match self.max_depth {
+            Some(depth) => scan = scan.max_depth(depth),
+            None if !self.recursive => scan = scan.max_depth(1),
+            None => {}
+        }
+        Ok(Converter {
+            convert,
+            ..Converter::new(scan.build()?)
+        })
+    }
+}
+
+/// `target` of the link at `link_path` rewritten to `form`, if that changes it
+///
+/// Paths are resolved lexically, `..` is not checked against symlinked dirs.
+pub fn reshape_target(link_path: &Path, target: &OsStr, form: TargetForm) -> Option<OsString> {
+    let target = Path::new(target);
+    let dir = link_path.parent().unwrap_or(Path::new(""));
+    let link_dir = paths::normalize(&std::path::absolute(dir).unwrap_or_else(|_| dir.to_owned()));
+    match form {
+        TargetForm::Absolute if target.is_relative() => {
+            Some(paths::normalize(&link_dir.join(target)).into_os_string())
+        }
+        TargetForm::Relative if target.is_absolute() => {
+            Some(paths::make_relative(&link_dir, &paths::normalize(target)).into_os_string())
+        }
+        _ => None,
+    }
+}
+
+/// Whether `path` is in a dir everyone can write to
+///
+/// Others could swap such a path for something else between checking and converting it.
+pub fn in_unsafe_dir(path: &Path) -> bool {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::metadata(dir).is_ok_and(|metadata| platform::mode(&metadata) & 0o002 != 0)
+}
+
+/// Whether an IO error is likely to go away if the operation is repeated
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ResourceBusy
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Run `op`, repeating it up to `retries` more times while it fails transiently
+pub fn with_retry<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(error) if attempt < retries && is_transient(&error) => {
+                attempt += 1;
+                thread::sleep(RETRY_BACKOFF * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run `create` with a hidden name next to `path` to create its replacement
+/// under, before renaming it over `path`, and return that name
+///
+/// The name stays in the same directory so the rename never crosses
+/// filesystems. When `create` fails because the name is taken, say by a file
+/// left over from a crashed run, it is run again with another name.
+pub fn create_temp_sibling(
+    path: &Path,
+    mut create: impl FnMut(&Path) -> io::Result<()>,
+) -> io::Result<PathBuf> {
+    let mut attempt = 1;
+    loop {
+        let temp = temp_sibling(path);
+        match create(&temp) {
+            Err(error)
+                if error.kind() == ErrorKind::AlreadyExists && attempt < TEMP_NAME_ATTEMPTS =>
+            {
+                attempt += 1
+            }
+            result => return result.map(|()| temp),
+        }
+    }
+}
+
+/// Hidden name next to `path`, different on every call
+fn temp_sibling(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+    let mut temp = b".".to_vec();
+    temp.extend_from_slice(&name[..name.len().min(TEMP_NAME_KEPT)]);
+    let number = TEMP_SIBLINGS.fetch_add(1, Ordering::Relaxed);
+    temp.extend_from_slice(format!(".restore-symlink-{}-{number}", process::id()).as_bytes());
+    path.with_file_name(platform::os_string(temp))
+}
+
+/// Whether `current` is still the regular file `read` was taken from
+fn same_file(read: &Metadata, current: &Metadata) -> bool {
+    current.is_file() && platform::same_file(read, current)
+}
+
+/// Whether `current` is still the regular file `read` was taken from, not written to since
+fn unchanged(read: &Metadata, current: &Metadata) -> bool {
+    same_file(read, current)
+        && current.len() == read.len()
+        && current.modified().ok() == read.modified().ok()
+        && platform::changed_at(current) == platform::changed_at(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn links_to_the_target_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        let dest = tmp.path().join("link");
+
+        let converter = Converter::new(ScanOptions::default());
+        let classification = converter
+            .convert_reader(Cursor::new(&b"target\n"[..]), &dest)
+            .unwrap();
+
+        assert!(matches!(classification, Classification::Convertible { .. }));
+        assert_eq!(fs::read_link(&dest).unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn creates_nothing_for_content_that_is_no_stub() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("link");
+        let converter = Converter::new(ScanOptions::default());
+
+        let classification = converter
+            .convert_reader(Cursor::new(&b"missing"[..]), &dest)
+            .unwrap();
+        assert!(!matches!(
+            classification,
+            Classification::Convertible { .. }
+        ));
+        let classification = converter
+            .convert_reader(Cursor::new(&b""[..]), &dest)
+            .unwrap();
+        assert!(matches!(classification, Classification::Empty));
+
+        assert!(fs::symlink_metadata(&dest).is_err());
+    }
+
+    #[test]
+    fn refuses_an_existing_dest() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        let dest = tmp.path().join("taken");
+        fs::write(&dest, "other").unwrap();
+
+        let converter = Converter::new(ScanOptions::default());
+        let error = converter
+            .convert_reader(Cursor::new(&b"target"[..]), &dest)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&dest).unwrap(), b"other");
+    }
+
+    /// A dir with the file `target` and the stub `stub` naming it
+    fn stub_dir() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        let stub = tmp.path().join("stub");
+        fs::write(&stub, "target").unwrap();
+        (tmp, stub)
+    }
+
+    #[test]
+    fn converts_a_single_stub() {
+        let (tmp, stub) = stub_dir();
+        let converter = Converter::new(ScanOptions::default());
+
+        assert_eq!(converter.convert_file(&stub).unwrap(), Outcome::Converted);
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+        assert_eq!(
+            converter.convert_file(&tmp.path().join("target")).unwrap(),
+            Outcome::Skipped(SkipReason::TargetMissing)
+        );
+        assert_eq!(
+            converter
+                .convert_file(&tmp.path().join("missing"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn converts_every_stub_in_a_tree() {
+        let (tmp, _) = stub_dir();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/stub"), "../target").unwrap();
+        let converter = Converter::new(ScanOptions::default());
+
+        let mut outcomes: Vec<_> = converter
+            .convert_tree(tmp.path())
+            .map(|(path, outcome)| (path, outcome.unwrap()))
+            .collect();
+        outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            outcomes,
+            [
+                (tmp.path().join("stub"), Outcome::Converted),
+                (tmp.path().join("sub/stub"), Outcome::Converted),
+                (
+                    tmp.path().join("target"),
+                    Outcome::Skipped(SkipReason::TargetMissing)
+                ),
+            ]
+        );
+        assert_eq!(
+            fs::read_link(tmp.path().join("sub/stub")).unwrap(),
+            Path::new("../target")
+        );
+    }
+
+    #[test]
+    fn builds_with_the_defaults_of_the_command_line() {
+        let (tmp, _) = stub_dir();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/stub"), "../target").unwrap();
+        fs::write(tmp.path().join("notes.md"), "target").unwrap();
+        fs::write(tmp.path().join(".hidden"), "target").unwrap();
+
+        let converted = |converter: Converter| {
+            let mut converted: Vec<_> = converter
+                .convert_tree(tmp.path())
+                .filter(|(_, outcome)| matches!(outcome, Ok(Outcome::Converted)))
+                .map(|(path, _)| path)
+                .collect();
+            converted.sort();
+            converted
+        };
+
+        let converter = Converter::builder().exclude("*.md").build().unwrap();
+        assert_eq!(converted(converter), [tmp.path().join("stub")]);
+        let converter = Converter::builder()
+            .len(1024)
+            .recursive(true)
+            .exclude("*.md")
+            .build()
+            .unwrap();
+        assert_eq!(converted(converter), [tmp.path().join("sub/stub")]);
+        assert_eq!(fs::read(tmp.path().join(".hidden")).unwrap(), b"target");
+    }
+
+    #[test]
+    fn refuses_invalid_builders() {
+        let invalid = [
+            Converter::builder().absolute(true).relative(true),
+            Converter::builder().exclude("[md"),
+            Converter::builder().include("{a,b"),
+            Converter::builder().len(0),
+            Converter::builder().len(4).min_len(8),
+        ];
+        for builder in invalid {
+            assert!(builder.clone().build().is_err(), "{builder:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn links_by_the_form_asked_for() {
+        let (tmp, stub) = stub_dir();
+        let target = tmp.path().join("target");
+        fs::write(&stub, target.as_os_str().as_encoded_bytes()).unwrap();
+
+        let converter = Converter::builder().relative(true).build().unwrap();
+        assert_eq!(converter.convert_file(&stub).unwrap(), Outcome::Converted);
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+
+        assert_eq!(
+            reshape_target(&stub, OsStr::new("target"), TargetForm::Absolute),
+            Some(paths::normalize(&std::path::absolute(&target).unwrap()).into_os_string())
+        );
+        assert_eq!(
+            reshape_target(&stub, OsStr::new("target"), TargetForm::Relative),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_to_the_target_in_the_xattr_asked_for() {
+        let (tmp, stub) = stub_dir();
+        let other = tmp.path().join("other");
+        fs::write(&other, "missing").unwrap();
+        let name = "user.restore-symlink.target";
+        if xattr::set(&other, name, b"target").is_err() {
+            return;
+        }
+        let options = ScanOptions::builder().from_xattr(name).build().unwrap();
+        let converter = Converter::new(options);
+
+        assert_eq!(converter.convert_file(&other).unwrap(), Outcome::Converted);
+        assert_eq!(fs::read_link(&other).unwrap(), Path::new("target"));
+        // Without the attribute, the content still names the target
+        assert_eq!(converter.convert_file(&stub).unwrap(), Outcome::Converted);
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn skips_stubs_declined() {
+        let (_tmp, stub) = stub_dir();
+        let converter = Converter::new(ScanOptions::default())
+            .confirm(|_, target| target != OsStr::new("target"));
+
+        assert_eq!(
+            converter.convert_file(&stub).unwrap(),
+            Outcome::Skipped(SkipReason::Declined)
+        );
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+    }
+
+    #[test]
+    fn leaves_stubs_replaced_since_they_were_read() {
+        let (_tmp, stub) = stub_dir();
+        let read = fs::metadata(&stub).unwrap();
+        let other = stub.with_file_name("other");
+        fs::write(&other, "other").unwrap();
+        fs::rename(&other, &stub).unwrap();
+        let converter = Converter::new(ScanOptions::default());
+
+        let replaced = converter.replace(&stub, OsStr::new("target"), &read, &Replacement::Symlink);
+
+        assert!(matches!(replaced, Err(ReplaceError::Stale)));
+        assert_eq!(fs::read(&stub).unwrap(), b"other");
+    }
+
+    #[test]
+    fn leaves_stubs_written_to_with_no_clobber() {
+        let (_tmp, stub) = stub_dir();
+        let read = fs::metadata(&stub).unwrap();
+        fs::write(&stub, "target, longer").unwrap();
+        let converter = Converter::new(ScanOptions::default()).no_clobber(true);
+
+        let replaced = converter.replace(&stub, OsStr::new("target"), &read, &Replacement::Symlink);
+
+        assert!(matches!(replaced, Err(ReplaceError::Stale)));
+        assert!(fs::symlink_metadata(&stub).unwrap().is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_stubs_in_dirs_everyone_can_write_to() {
+        let (tmp, stub) = stub_dir();
+        platform::set_mode(tmp.path(), 0o777).unwrap();
+
+        let converter = Converter::new(ScanOptions::default());
+        assert_eq!(
+            converter.convert_file(&stub).unwrap(),
+            Outcome::Skipped(SkipReason::UnsafeParent)
+        );
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+
+        let converter = converter.allow_unsafe(true);
+        assert_eq!(converter.convert_file(&stub).unwrap(), Outcome::Converted);
+    }
+
+    #[test]
+    fn retries_transient_errors_up_to_the_limit() {
+        let calls = Cell::new(0);
+        let result: io::Result<()> = with_retry(2, || {
+            calls.set(calls.get() + 1);
+            Err(ErrorKind::ResourceBusy.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn stops_retrying_once_it_succeeds() {
+        let calls = Cell::new(0);
+        let result = with_retry(5, || {
+            calls.set(calls.get() + 1);
+            if calls.get() < 2 {
+                Err(ErrorKind::Interrupted.into())
+            } else {
+                Ok(calls.get())
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_lasting_errors() {
+        for kind in [ErrorKind::PermissionDenied, ErrorKind::AlreadyExists] {
+            let calls = Cell::new(0);
+            let result: io::Result<()> = with_retry(3, || {
+                calls.set(calls.get() + 1);
+                Err(kind.into())
+            });
+            assert_eq!(result.unwrap_err().kind(), kind);
+            assert_eq!(calls.get(), 1);
+        }
+    }
+
+    #[test]
+    fn temp_sibling_stays_next_to_the_path() {
+        let temp = temp_sibling(Path::new("/mnt/share/dir/stub"));
+        assert_eq!(temp.parent(), Some(Path::new("/mnt/share/dir")));
+        let name = temp.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".stub.restore-symlink-"), "{name}");
+        assert!(!temp.starts_with(std::env::temp_dir()));
+        assert_ne!(temp_sibling(Path::new("/mnt/share/dir/stub")), temp);
+    }
+
+    #[test]
+    fn temp_sibling_name_fits_long_names() {
+        let long = "x".repeat(255);
+        let temp = temp_sibling(&Path::new("dir").join(&long));
+        assert_eq!(temp.parent(), Some(Path::new("dir")));
+        assert!(temp.file_name().unwrap().len() <= 255);
+    }
+
+    #[test]
+    fn creates_under_another_name_when_one_is_taken() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stub = tmp.path().join("stub");
+        let mut tried = Vec::new();
+
+        let temp = create_temp_sibling(&stub, |temp| {
+            tried.push(temp.to_owned());
+            if tried.len() < 3 {
+                return Err(ErrorKind::AlreadyExists.into());
+            }
+            fs::write(temp, "content")
+        })
+        .unwrap();
+
+        assert_eq!(tried.len(), 3);
+        assert_eq!(tried.last(), Some(&temp));
+        assert_ne!(tried[0], tried[1]);
+        assert_eq!(fs::read_to_string(&temp).unwrap(), "content");
+        let result = create_temp_sibling(&stub, |_| Err(ErrorKind::AlreadyExists.into()));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+}
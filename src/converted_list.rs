@@ -0,0 +1,31 @@
+//! List of the paths converted by a run, for scripts to pick up.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+static LIST: OnceLock<Mutex<(File, u8)>> = OnceLock::new();
+
+/// Write every following converted path to a new file at `path`, each followed by `separator`
+pub fn open(path: &Path, separator: u8) -> io::Result<()> {
+    let file = File::create(path)?;
+    let _ = LIST.set(Mutex::new((file, separator)));
+    Ok(())
+}
+
+/// Add `path`, just converted, to the list
+///
+/// Paths are written as raw bytes.
+pub fn record(path: &Path) -> io::Result<()> {
+    let Some(list) = LIST.get() else {
+        return Ok(());
+    };
+    let mut list = list.lock().unwrap_or_else(|error| error.into_inner());
+    let (file, separator) = &mut *list;
+    file.write_all(path.as_os_str().as_encoded_bytes())?;
+    file.write_all(&[*separator])?;
+    file.flush()
+}
@@ -0,0 +1,126 @@
+//! Replacing files that repeat an earlier one with symlinks to it.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::Metadata,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use restore_symlink::{
+    convert::Converter,
+    paths, platform,
+    scan::{scan, Classification, ScanOptions},
+    summary::{Outcome, Summary},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{convert_file, print_io_error, report_batch, stop_early, Args};
+
+/// A file walked earlier, that later copies link to
+type First = (PathBuf, Metadata);
+
+/// Files walked so far, by size and then by content
+#[derive(Default)]
+struct Seen {
+    /// The first file of each size, until a second one turns up and it is hashed
+    by_size: HashMap<u64, Option<First>>,
+    /// The first file with each size and content
+    by_digest: HashMap<(u64, Vec<u8>), First>,
+}
+
+impl Seen {
+    /// The first file walked with the content of the file at `path`, if it is not the first itself
+    fn first_copy(&mut self, path: &Path, metadata: &Metadata) -> io::Result<Option<&First>> {
+        let len = metadata.len();
+        match self.by_size.entry(len) {
+            Entry::Vacant(entry) => {
+                entry.insert(Some((path.to_owned(), metadata.clone())));
+                return Ok(None);
+            }
+            Entry::Occupied(mut entry) => {
+                if let Some((first, first_metadata)) = entry.get_mut().take() {
+                    let digest = digest(&first, &first_metadata)?;
+                    self.by_digest
+                        .insert((len, digest), (first, first_metadata));
+                }
+            }
+        }
+        match self.by_digest.entry((len, digest(path, metadata)?)) {
+            Entry::Occupied(entry) => Ok(Some(entry.into_mut())),
+            Entry::Vacant(entry) => {
+                entry.insert((path.to_owned(), metadata.clone()));
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Replace every regular file below `roots` that has the content of one
+/// walked earlier with a relative symlink to that first file
+///
+/// Files smaller than `--dedup-min-size` and hardlinks of the first file are
+/// left alone. Only the files replaced or failing are counted.
+pub fn dedup(
+    converter: &Converter,
+    roots: &[PathBuf],
+    options: &ScanOptions,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    let mut seen = Seen::default();
+    for root in roots {
+        for candidate in scan(root, options) {
+            if matches!(candidate.classification, Classification::Excluded) {
+                continue;
+            }
+            let Some(metadata) = candidate.metadata.filter(|metadata| metadata.is_file()) else {
+                continue;
+            };
+            if metadata.len() < args.dedup_min_size {
+                continue;
+            }
+            let path = &candidate.path;
+            let outcome = match seen.first_copy(path, &metadata) {
+                Ok(None) => continue,
+                Ok(Some((_, first))) if platform::same_file(first, &metadata) => continue,
+                Ok(Some((first, _))) => {
+                    let target =
+                        paths::make_relative(path.parent().unwrap_or(Path::new("")), first);
+                    convert_file(converter, path, target.as_os_str(), &metadata, args)
+                }
+                Err(error) => {
+                    print_io_error(path, &error.to_string());
+                    Outcome::Failed
+                }
+            };
+            if outcome == Outcome::Converted {
+                summary.converted_bytes += metadata.len();
+            }
+            summary.add(path, outcome);
+            report_batch(summary, outcome, args);
+            if stop_early(summary, args) {
+                return;
+            }
+        }
+    }
+}
+
+/// SHA-256 of the content of the regular file at `path`, still the file of `metadata`
+fn digest(path: &Path, metadata: &Metadata) -> io::Result<Vec<u8>> {
+    let mut file = platform::open_no_follow(path)?;
+    if !platform::same_file(&file.metadata()?, metadata) {
+        return Err(io::Error::other(
+            "replaced by another file while being read",
+        ));
+    }
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+    Ok(hasher.finalize().to_vec())
+}
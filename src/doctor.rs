@@ -0,0 +1,90 @@
+//! Read-only triage of a tree before converting it.
+
+use std::{fs, path::Path};
+
+use restore_symlink::scan::{find_collisions, scan, Candidate, Classification, ScanOptions};
+
+use crate::{
+    output::{self, report},
+    Args,
+};
+
+/// Report in aggregate what a run over `root` would meet, returning whether any of it is a problem
+///
+/// Control characters are looked for even with `--allow-control-chars`. With
+/// `--verbose` every problematic entry is listed as well.
+pub fn diagnose(root: &Path, options: &ScanOptions, args: &Args) -> bool {
+    let options = ScanOptions {
+        strict_path: true,
+        ..options.clone()
+    };
+    let candidates: Vec<Candidate> = scan(root, &options).collect();
+
+    let (mut stubs, mut broken, mut missing) = (0, 0, 0);
+    let (mut urls, mut multiple, mut control) = (0, 0, 0);
+    let verbose = args.verbose > 0;
+    for candidate in &candidates {
+        let path = output::shown(&candidate.path);
+        match &candidate.classification {
+            Classification::Convertible { .. } => stubs += 1,
+            Classification::Symlink { target } if fs::metadata(&candidate.path).is_err() => {
+                broken += 1;
+                if verbose {
+                    report!("Broken symlink: {} -> {}", path, output::text(target))
+                }
+            }
+            Classification::TargetMissing { target } => {
+                missing += 1;
+                if verbose {
+                    report!("Missing target: {} -> {}", path, output::text(target))
+                }
+            }
+            Classification::NotUtf8 => {
+                missing += 1;
+                if verbose {
+                    report!("Missing target: {} -> path that is not UTF-8", path)
+                }
+            }
+            Classification::Url { target } => {
+                urls += 1;
+                if verbose {
+                    report!("URL: {} -> {}", path, output::text(target))
+                }
+            }
+            Classification::MultiplePaths => {
+                multiple += 1;
+                if verbose {
+                    report!("Multiple paths: {}", path)
+                }
+            }
+            Classification::ControlCharacters => {
+                control += 1;
+                if verbose {
+                    report!("Control characters: {}", path)
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let collisions = find_collisions(&candidates);
+    if verbose {
+        for group in &collisions {
+            let paths: Vec<_> = group.iter().map(|path| output::shown(path)).collect();
+            report!("Case collision: {}", paths.join(", "))
+        }
+    }
+
+    output::message(&format!("Likely stubs: {stubs}"));
+    output::message(&format!("Broken symlinks: {broken}"));
+    output::message(&format!("Stubs with missing targets: {missing}"));
+    output::message(&format!(
+        "Suspicious stubs: {} (URL {}, multiple paths {}, control characters {})",
+        urls + multiple + control,
+        urls,
+        multiple,
+        control
+    ));
+    output::message(&format!("Case collisions: {}", collisions.len()));
+    broken + missing + urls + multiple + control + collisions.len() > 0
+}
@@ -0,0 +1,388 @@
+//! Creating symlinks straight from a tar archive of stubs.
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use restore_symlink::{
+    convert::Converter,
+    fs_ops::FsOps,
+    scan::{classify_target, target_from_content, Candidate, Classification, ScanOptions},
+    summary::{Outcome, SkipReason, Summary},
+};
+use tar::EntryType;
+
+use crate::{
+    convert_candidate, echo_confirmed,
+    output::{self, report},
+    print_error, print_io_error, prompt, record_converted, refuse_unsafe_dir, report_batch,
+    reshape_target, stop_early, with_retry, Args,
+};
+
+/// Create a symlink under `dest_dir` for every small regular file in `archive`
+///
+/// Directories are created as needed. Entry contents are checked like stub
+/// contents with `options`, except that missing targets looking like a path
+/// are accepted as with `--force`, as those may well be restored by later entries.
+pub fn restore_from_tar(
+    converter: &Converter,
+    archive: &Path,
+    dest_dir: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    let ops = converter.ops();
+    let options = ScanOptions {
+        allow_missing_targets: true,
+        ..options.clone()
+    };
+    let entries = File::open(archive).and_then(|file| {
+        let mut archive = tar::Archive::new(file);
+        archive
+            .entries()?
+            .map(|entry| {
+                let mut entry = entry?;
+                let path = entry.path()?.into_owned();
+                let entry_type = entry.header().entry_type();
+                let size = entry.size();
+                let mut content = Vec::new();
+                if entry_type == EntryType::Regular && size <= args.len {
+                    entry.read_to_end(&mut content)?;
+                }
+                Ok((path, entry_type, size, content))
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+    });
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(error) => {
+            print_io_error(archive, &error.to_string());
+            summary.add(archive, Outcome::Failed);
+            return;
+        }
+    };
+
+    for (path, entry_type, size, content) in entries {
+        let link_path = dest_dir.join(&path);
+        if !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            print_error(
+                &link_path,
+                "archive entry points outside of the destination",
+            );
+            summary.add(&link_path, Outcome::Failed);
+            continue;
+        }
+        if let Some(symlink) = symlinked_parent(ops, dest_dir, &path) {
+            print_error(
+                &link_path,
+                &format!(
+                    "archive entry goes through the symlink '{}'",
+                    output::shown(&symlink)
+                ),
+            );
+            summary.add(&link_path, Outcome::Failed);
+            continue;
+        }
+
+        let outcome = match entry_type {
+            EntryType::Directory => {
+                if let Err(error) = ops.create_dir_all(&link_path) {
+                    print_io_error(&link_path, &error.to_string());
+                    summary.add(&link_path, Outcome::Failed);
+                }
+                continue;
+            }
+            EntryType::Regular if size > args.len => {
+                if args.verbose > 0 {
+                    report!(
+                        "Archive entry {} is too big to be considered as symlink({} > {})",
+                        output::text(path),
+                        size,
+                        args.len
+                    )
+                }
+                Outcome::Skipped(SkipReason::TooBig)
+            }
+            EntryType::Regular if size < args.min_len => {
+                if args.verbose > 0 {
+                    report!(
+                        "Archive entry {} is too small to be considered as symlink({} < {})",
+                        output::text(path),
+                        size,
+                        args.min_len
+                    )
+                }
+                Outcome::Skipped(SkipReason::TooSmall)
+            }
+            EntryType::Regular => {
+                let classification = match target_from_content(content, &options) {
+                    Ok(target) => classify_target(&link_path, target, &options),
+                    Err(classification) => classification,
+                };
+                match classification {
+                    Classification::Convertible { target } => {
+                        link_from_entry(ops, &link_path, &target, args)
+                    }
+                    classification => convert_candidate(
+                        converter,
+                        Candidate {
+                            path: link_path.clone(),
+                            depth: 0,
+                            metadata: None,
+                            classification,
+                        },
+                        args,
+                    ),
+                }
+            }
+            _ => {
+                if args.verbose > 0 {
+                    report!("Skipped archive entry {}", output::text(path))
+                }
+                continue;
+            }
+        };
+        summary.add(&link_path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+/// The first dir between `dest_dir` and the entry at `path` that is a symlink
+///
+/// Creating the entry would write wherever that symlink points, maybe
+/// outside of `dest_dir`, as links made by earlier entries are followed.
+fn symlinked_parent(ops: &dyn FsOps, dest_dir: &Path, path: &Path) -> Option<PathBuf> {
+    let mut dir = dest_dir.to_owned();
+    for component in path.parent()?.components() {
+        dir.push(component);
+        if ops.read_link(&dir).is_ok() {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+fn link_from_entry(ops: &dyn FsOps, link_path: &Path, link_val: &OsStr, args: &Args) -> Outcome {
+    let reshaped = reshape_target(link_path, link_val, args);
+    let link_val = reshaped.as_deref().unwrap_or(link_val);
+    if refuse_unsafe_dir(link_path, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+
+    if args.interactive
+        && !prompt::confirm(
+            &format!(
+                "Create symlink '{}' -> '{}'?",
+                output::shown(link_path),
+                output::text(link_val)
+            ),
+            args.interactive_default,
+        )
+    {
+        return Outcome::Skipped(SkipReason::Declined);
+    }
+
+    if let Some(parent) = link_path.parent() {
+        if let Err(error) = ops.create_dir_all(parent) {
+            print_io_error(link_path, &error.to_string());
+            return Outcome::Failed;
+        }
+    }
+    echo_confirmed(link_path, link_val, args);
+    if let Err(error) = with_retry(args.retry, || ops.symlink(link_val, link_path)) {
+        print_io_error(link_path, &error.to_string());
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!(
+            "Converted to symlink: {} -> {}",
+            output::shown(link_path),
+            output::text(link_val)
+        )
+    }
+    record_converted(link_path, args);
+    Outcome::Converted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use clap::Parser;
+
+    use super::*;
+    use restore_symlink::fs_ops::{MemFs, Node, RealFs};
+
+    /// A tar archive at `path` holding a regular file for each `(name, content)`
+    fn write_archive(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    fn restore(archive: &Path, dest_dir: &Path, options: &[&str]) -> Summary {
+        restore_with(Arc::new(RealFs), archive, dest_dir, options)
+    }
+
+    fn restore_with(
+        ops: Arc<dyn FsOps>,
+        archive: &Path,
+        dest_dir: &Path,
+        options: &[&str],
+    ) -> Summary {
+        let args = Args::parse_from(
+            ["restore_symlink", "--from-tar"]
+                .map(OsStr::new)
+                .into_iter()
+                .chain([archive.as_os_str()])
+                .chain(options.iter().map(OsStr::new))
+                .chain([dest_dir.as_os_str()]),
+        );
+        let options = crate::scan_options(&args).unwrap();
+        let mut summary = Summary::default();
+        restore_from_tar(
+            &crate::converter(&args, &options, ops),
+            archive,
+            dest_dir,
+            &options,
+            &args,
+            &mut summary,
+        );
+        summary
+    }
+
+    #[test]
+    fn creates_symlinks_from_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest_dir = tmp.path().join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+        fs::write(dest_dir.join("target"), "content").unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        write_archive(&archive, &[("sub/link", b"../target\n")]);
+
+        let summary = restore(&archive, &dest_dir, &[]);
+
+        assert_eq!(summary.converted, 1);
+        assert_eq!(
+            fs::read_link(dest_dir.join("sub/link")).unwrap(),
+            Path::new("../target")
+        );
+    }
+
+    #[test]
+    fn refuses_entries_through_a_symlink_made_by_an_earlier_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest_dir = tmp.path().join("dest");
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&dest_dir).unwrap();
+        fs::create_dir(&outside).unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        let outside_target = outside.as_os_str().as_encoded_bytes();
+        write_archive(
+            &archive,
+            &[("link", outside_target), ("link/escaped", outside_target)],
+        );
+
+        let summary = restore(&archive, &dest_dir, &[]);
+
+        assert_eq!(fs::read_link(dest_dir.join("link")).unwrap(), outside);
+        assert!(fs::symlink_metadata(outside.join("escaped")).is_err());
+        assert_eq!((summary.converted, summary.failed), (1, 1));
+    }
+
+    #[test]
+    fn checks_contents_like_stubs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        write_archive(
+            &archive,
+            &[
+                ("url", b"http://example.com/x"),
+                ("lines", b"a/b\nc/d"),
+                ("tab", b"a\tb"),
+                ("long", "a/".repeat(40).as_bytes()),
+            ],
+        );
+
+        let summary = restore(&archive, tmp.path(), &["--max-target-len", "64"]);
+
+        assert_eq!(summary.converted, 0);
+        let reasons: Vec<_> = summary.skipped_by_reason().collect();
+        assert_eq!(
+            reasons,
+            [
+                (SkipReason::ControlCharacters, 1),
+                (SkipReason::MultiplePaths, 1),
+                (SkipReason::Url, 1),
+                (SkipReason::TargetTooLong, 1),
+            ]
+        );
+        for name in ["url", "lines", "tab", "long"] {
+            assert!(fs::symlink_metadata(tmp.path().join(name)).is_err());
+        }
+    }
+
+    #[test]
+    fn rewrites_targets_like_stubs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        write_archive(&archive, &[("link", b"/old/lib/x\n")]);
+
+        let summary = restore(&archive, tmp.path(), &["--map", "/old=/new"]);
+
+        assert_eq!(summary.converted, 1);
+        assert_eq!(
+            fs::read_link(tmp.path().join("link")).unwrap(),
+            Path::new("/new/lib/x")
+        );
+    }
+
+    #[test]
+    fn skips_relative_targets_climbing_too_far() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        write_archive(&archive, &[("a/link", b"../../../x/y")]);
+
+        let summary = restore(&archive, tmp.path(), &["--max-relative-depth", "2"]);
+
+        assert_eq!(summary.converted, 0);
+        assert_eq!(summary.skipped(), 1);
+        assert!(fs::symlink_metadata(tmp.path().join("a/link")).is_err());
+    }
+
+    #[test]
+    fn creates_dirs_and_symlinks_through_ops() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("stubs.tar");
+        write_archive(&archive, &[("sub/link", b"../target\n")]);
+        let ops = Arc::new(MemFs::new());
+        let dest_dir = Path::new("/mem/dest");
+        ops.create_dir_all(dest_dir).unwrap();
+
+        let summary = restore_with(ops.clone(), &archive, dest_dir, &["--silent"]);
+
+        assert_eq!(summary.converted, 1);
+        assert_eq!(ops.node(&dest_dir.join("sub")), Some(Node::Dir));
+        assert_eq!(
+            ops.node(&dest_dir.join("sub/link")),
+            Some(Node::Symlink("../target".into()))
+        );
+    }
+}
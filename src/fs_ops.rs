@@ -0,0 +1,596 @@
+//! Filesystem operations walking and changing the tree, behind a trait so they can be swapped out.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs::{self, Metadata},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::fs::MetadataExt};
+
+use crate::platform;
+
+/// Paths of the entries of a directory, read as they are iterated over
+pub type DirEntries<'a> = Box<dyn Iterator<Item = io::Result<PathBuf>> + Send + Sync + 'a>;
+
+/// Every filesystem call made while walking a tree and converting its stubs,
+/// apart from classifying them
+pub trait FsOps: Send + Sync {
+    /// Content of the file at `path`, failing if it is a symlink
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Metadata of `path` itself, not following a symlink
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Paths of the entries of the directory at `path`
+    fn read_dir(&self, path: &Path) -> io::Result<DirEntries<'_>>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Remove the empty directory at `path`
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    /// Remove the directory at `path` with everything below it
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn symlink(&self, target: &OsStr, link: &Path) -> io::Result<()>;
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+    /// Copy the file or, with everything below it, the directory at `from`
+    /// to the new path `to`, keeping modes and recreating symlinks as they are
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Move `from` over `to` in one step, replacing whatever `to` was
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Create the file at `path` with `content` and the permission bits `mode`
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()>;
+    /// Create the directory at `path` along with its missing parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Give the symlink at `link` the owner and timestamps of `metadata` and
+    /// the extended attributes `xattrs`
+    fn preserve(
+        &self,
+        link: &Path,
+        metadata: &Metadata,
+        xattrs: &[(OsString, Vec<u8>)],
+    ) -> io::Result<()>;
+}
+
+/// The real filesystem
+pub struct RealFs;
+
+impl FsOps for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        read_no_follow(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<DirEntries<'_>> {
+        let entries = fs::read_dir(path)?;
+        Ok(Box::new(
+            entries.map(|entry| entry.map(|entry| entry.path())),
+        ))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn symlink(&self, target: &OsStr, link: &Path) -> io::Result<()> {
+        platform::symlink(target, link)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let copied = copy_tree(from, to, &fs::metadata(from)?);
+        if copied.is_err() {
+            let _ = fs::remove_dir_all(to).or_else(|_| fs::remove_file(to));
+        }
+        copied
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        fs::write(path, content)?;
+        platform::set_mode(path, mode)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn preserve(
+        &self,
+        link: &Path,
+        metadata: &Metadata,
+        xattrs: &[(OsString, Vec<u8>)],
+    ) -> io::Result<()> {
+        preserve_link(link, metadata, xattrs)
+    }
+}
+
+/// The real filesystem for reading, every change succeeding without being made
+pub struct DryRun;
+
+impl FsOps for DryRun {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        read_no_follow(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<DirEntries<'_>> {
+        let entries = fs::read_dir(path)?;
+        Ok(Box::new(
+            entries.map(|entry| entry.map(|entry| entry.path())),
+        ))
+    }
+
+    fn remove_file(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn symlink(&self, _target: &OsStr, _link: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn hard_link(&self, _original: &Path, _link: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn copy(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn write(&self, _path: &Path, _content: &[u8], _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn preserve(
+        &self,
+        _link: &Path,
+        _metadata: &Metadata,
+        _xattrs: &[(OsString, Vec<u8>)],
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file, symlink or directory of a [`MemFs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    File { content: Vec<u8>, mode: u32 },
+    Symlink(OsString),
+    Dir,
+}
+
+/// A filesystem held in memory, for tests that should not touch the disk
+///
+/// Paths are taken as they are, so tests should stick to absolute ones. Hard
+/// links get a copy of the file rather than sharing it. `metadata` and
+/// `preserve` fail as unsupported, as a [`Metadata`] cannot be made up.
+#[derive(Default)]
+pub struct MemFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What is at `path`, if anything
+    pub fn node(&self, path: &Path) -> Option<Node> {
+        self.nodes().get(path).cloned()
+    }
+
+    fn nodes(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+        self.nodes.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Add `node` at the new path `path`, whose parent must be a directory
+    fn create(&self, path: &Path, node: Node) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        if nodes.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        match path.parent() {
+            Some(parent) if parent.parent().is_some() && !parent.as_os_str().is_empty() => {
+                match nodes.get(parent) {
+                    Some(Node::Dir) => {}
+                    Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+                    None => return Err(io::ErrorKind::NotFound.into()),
+                }
+            }
+            _ => {}
+        }
+        nodes.insert(path.to_owned(), node);
+        Ok(())
+    }
+}
+
+/// Paths in `nodes` of `path` and everything below it
+fn subtree(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+    nodes
+        .range(path.to_owned()..)
+        .map(|(below, _)| below)
+        .take_while(|below| below.starts_with(path))
+        .cloned()
+        .collect()
+}
+
+impl FsOps for MemFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.nodes().get(path) {
+            Some(Node::File { content, .. }) => Ok(content.clone()),
+            Some(_) => Err(io::Error::other("is no regular file")),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn metadata(&self, _path: &Path) -> io::Result<Metadata> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "metadata of files in memory",
+        ))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<DirEntries<'_>> {
+        let nodes = self.nodes();
+        match nodes.get(path) {
+            Some(Node::Dir) => {}
+            Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        }
+        let entries: Vec<_> = subtree(&nodes, path)
+            .into_iter()
+            .filter(|below| below.parent() == Some(path))
+            .map(Ok)
+            .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        match nodes.get(path) {
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            Some(_) => {
+                nodes.remove(path);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        match nodes.get(path) {
+            Some(Node::Dir) if subtree(&nodes, path).len() > 1 => {
+                Err(io::ErrorKind::DirectoryNotEmpty.into())
+            }
+            Some(Node::Dir) => {
+                nodes.remove(path);
+                Ok(())
+            }
+            Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        match nodes.get(path) {
+            Some(Node::Dir) => {
+                for below in subtree(&nodes, path) {
+                    nodes.remove(&below);
+                }
+                Ok(())
+            }
+            Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn symlink(&self, target: &OsStr, link: &Path) -> io::Result<()> {
+        self.create(link, Node::Symlink(target.to_owned()))
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        match self.node(original) {
+            Some(file @ Node::File { .. }) => self.create(link, file),
+            Some(_) => Err(io::ErrorKind::PermissionDenied.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let copied: Vec<_> = {
+            let nodes = self.nodes();
+            if !nodes.contains_key(from) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            subtree(&nodes, from)
+                .into_iter()
+                .map(|below| {
+                    let node = nodes[&below].clone();
+                    (below, node)
+                })
+                .collect()
+        };
+        for (below, node) in copied {
+            let relative = below.strip_prefix(from).unwrap_or(&below);
+            self.create(&to.join(relative), node)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        if !nodes.contains_key(from) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if nodes.get(to) == Some(&Node::Dir) && subtree(&nodes, to).len() > 1 {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+        nodes.remove(to);
+        for below in subtree(&nodes, from) {
+            let node = nodes.remove(&below).unwrap_or(Node::Dir);
+            let relative = below.strip_prefix(from).unwrap_or(&below).to_owned();
+            nodes.insert(to.join(relative), node);
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.nodes().get(path) {
+            Some(Node::Symlink(target)) => Ok(PathBuf::from(target)),
+            Some(_) => Err(io::ErrorKind::InvalidInput.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        let file = Node::File {
+            content: content.to_owned(),
+            mode,
+        };
+        if let Some(node) = self.nodes().get_mut(path) {
+            if *node == Node::Dir {
+                return Err(io::ErrorKind::IsADirectory.into());
+            }
+            *node = file;
+            return Ok(());
+        }
+        self.create(path, file)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes();
+        for dir in path.ancestors().filter(|dir| dir.parent().is_some()) {
+            match nodes.get(dir) {
+                Some(Node::Dir) => {}
+                Some(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+                None => {
+                    nodes.insert(dir.to_owned(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn preserve(
+        &self,
+        _link: &Path,
+        _metadata: &Metadata,
+        _xattrs: &[(OsString, Vec<u8>)],
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "owners and timestamps of files in memory",
+        ))
+    }
+}
+
+/// Give the symlink at `link` the owner and timestamps of `metadata` and the
+/// extended attributes `xattrs`
+#[cfg(unix)]
+fn preserve_link(
+    link: &Path,
+    metadata: &Metadata,
+    xattrs: &[(OsString, Vec<u8>)],
+) -> io::Result<()> {
+    std::os::unix::fs::lchown(link, Some(metadata.uid()), Some(metadata.gid()))?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let link_c = CString::new(link.as_os_str().as_encoded_bytes())?;
+    // SAFETY: `link_c` is a NUL-terminated path and `times` holds the two
+    // timestamps utimensat reads.
+    let set = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            link_c.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if set != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The xattr crate does not follow symlinks, so these land on the link
+    for (name, value) in xattrs {
+        xattr::set(link, name, value).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("extended attribute {}: {}", name.to_string_lossy(), error),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Owners, timestamps and extended attributes of symlinks are only kept on Unix
+#[cfg(not(unix))]
+fn preserve_link(
+    _link: &Path,
+    _metadata: &Metadata,
+    _xattrs: &[(OsString, Vec<u8>)],
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "keeping owner and timestamps of symlinks is only supported on Unix",
+    ))
+}
+
+/// Content of the regular file at `path`, opened so a symlink put in its
+/// place is not read through
+fn read_no_follow(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = platform::open_no_follow(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::other("is no regular file"));
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Copy `from` with `metadata` to `to`, a directory with everything below it
+///
+/// Directories get their mode once filled, so read-only ones can be copied.
+fn copy_tree(from: &Path, to: &Path, metadata: &Metadata) -> io::Result<()> {
+    if metadata.is_symlink() {
+        return platform::symlink(fs::read_link(from)?.as_os_str(), to);
+    }
+    if metadata.is_file() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    if !metadata.is_dir() {
+        return Err(io::Error::other(format!(
+            "cannot copy special file '{}'",
+            from.to_string_lossy()
+        )));
+    }
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_tree(
+            &entry.path(),
+            &to.join(entry.file_name()),
+            &entry.metadata()?,
+        )?;
+    }
+    fs::set_permissions(to, metadata.permissions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_fs_keeps_files_symlinks_and_dirs() {
+        let fs = MemFs::new();
+        fs.create_dir_all(Path::new("/a/b")).unwrap();
+        fs.write(Path::new("/a/b/file"), b"content", 0o600).unwrap();
+        fs.symlink(OsStr::new("b/file"), Path::new("/a/link"))
+            .unwrap();
+
+        assert_eq!(fs.read(Path::new("/a/b/file")).unwrap(), b"content");
+        assert_eq!(
+            fs.read_link(Path::new("/a/link")).unwrap(),
+            Path::new("b/file")
+        );
+        assert!(fs.read(Path::new("/a/link")).is_err());
+        let entries: Vec<_> = fs
+            .read_dir(Path::new("/a"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries, [Path::new("/a/b"), Path::new("/a/link")]);
+        assert_eq!(
+            fs.write(Path::new("/missing/file"), b"", 0o644)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn mem_fs_renames_and_removes_like_the_disk() {
+        let fs = MemFs::new();
+        fs.create_dir_all(Path::new("/dir/sub")).unwrap();
+        fs.write(Path::new("/dir/sub/file"), b"x", 0o644).unwrap();
+        fs.symlink(OsStr::new("target"), Path::new("/dir/.tmp"))
+            .unwrap();
+        fs.write(Path::new("/dir/stub"), b"target", 0o644).unwrap();
+
+        fs.rename(Path::new("/dir/.tmp"), Path::new("/dir/stub"))
+            .unwrap();
+        assert_eq!(
+            fs.node(Path::new("/dir/stub")),
+            Some(Node::Symlink("target".into()))
+        );
+        assert_eq!(fs.node(Path::new("/dir/.tmp")), None);
+
+        assert_eq!(
+            fs.remove_dir(Path::new("/dir/sub")).unwrap_err().kind(),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+        fs.rename(Path::new("/dir/sub"), Path::new("/moved"))
+            .unwrap();
+        assert_eq!(
+            fs.node(Path::new("/moved/file")),
+            Some(Node::File {
+                content: b"x".to_vec(),
+                mode: 0o644
+            })
+        );
+        fs.remove_dir_all(Path::new("/moved")).unwrap();
+        assert_eq!(fs.node(Path::new("/moved/file")), None);
+    }
+}
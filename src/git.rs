@@ -0,0 +1,55 @@
+//! Asking git which files of a work tree it records as symlinks.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use restore_symlink::platform;
+
+/// Mode of a symlink in the git index.
+const SYMLINK_MODE: &[u8] = b"120000";
+
+/// Paths of the files at or below `root` that the git index records as symlinks
+///
+/// The paths are joined to `root` the way a walk of it yields them.
+pub fn symlinks(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, base, pathspec) = if root.is_dir() {
+        (root, root, None)
+    } else {
+        let parent = match root.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        (
+            parent,
+            root.parent().unwrap_or(Path::new("")),
+            root.file_name(),
+        )
+    };
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(dir)
+        .args(["ls-files", "--stage", "-z"]);
+    if let Some(name) = pathspec {
+        command.arg("--").arg(name);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(message.trim().to_owned()));
+    }
+
+    // Each entry is `<mode> <object> <stage>\t<path>`
+    Ok(output
+        .stdout
+        .split(|&byte| byte == 0)
+        .filter_map(|entry| {
+            let (info, path) = entry.split_at(entry.iter().position(|&byte| byte == b'\t')?);
+            info.starts_with(SYMLINK_MODE)
+                .then(|| base.join(platform::os_str(&path[1..])))
+        })
+        .collect())
+}
@@ -0,0 +1,104 @@
+//! Sharing one inode between identical symlinks created in a run.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    output::{self, report},
+    print_io_error, Args,
+};
+
+/// Replace every symlink in `links` that repeats an earlier one with a hardlink to it
+///
+/// Links are only shared when both the stored target and the path it resolves
+/// to are equal, so every name keeps pointing where it did. When the
+/// filesystem refuses the hardlink the symlink is left as it is.
+pub fn hardlink_duplicates(links: &[(PathBuf, OsString)], args: &Args) {
+    let mut groups: BTreeMap<(PathBuf, &OsStr), Vec<&Path>> = BTreeMap::new();
+    for (path, target) in links {
+        if let Ok(resolved) = fs::canonicalize(path) {
+            groups.entry((resolved, target)).or_default().push(path);
+        }
+    }
+
+    for paths in groups.values() {
+        let Some((first, rest)) = paths.split_first() else {
+            continue;
+        };
+        for path in rest {
+            if let Err(error) = replace_with_hardlink(first, path) {
+                print_io_error(
+                    path,
+                    &format!("cannot hardlink to '{}': {error}", output::shown(first)),
+                );
+                continue;
+            }
+            if args.verbose > 0 {
+                report!(
+                    "Hardlinked {} to {}",
+                    output::shown(path),
+                    output::shown(first)
+                )
+            }
+        }
+    }
+}
+
+/// Atomically swap the symlink `path` for a hardlink to the symlink `original`
+fn replace_with_hardlink(original: &Path, path: &Path) -> std::io::Result<()> {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".hardlink");
+    let temp = path.with_file_name(name);
+    fs::hard_link(original, &temp)?;
+    fs::rename(&temp, path).map_err(|error| match fs::remove_file(&temp) {
+        Ok(()) => error,
+        Err(remove_error) => std::io::Error::new(
+            error.kind(),
+            format!(
+                "{error}, and cannot remove '{}': {remove_error}",
+                temp.to_string_lossy()
+            ),
+        ),
+    })
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod tests {
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn duplicates_share_the_inode_of_the_first_link() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        fs::write(tmp.path().join("other"), "content").unwrap();
+        let links: Vec<_> = [("a", "target"), ("b", "target"), ("c", "other")]
+            .into_iter()
+            .map(|(name, target)| {
+                let path = tmp.path().join(name);
+                symlink(target, &path).unwrap();
+                (path, OsString::from(target))
+            })
+            .collect();
+        let args = Args::parse_from(["restore_symlink", "--hardlink-duplicates", "."]);
+
+        hardlink_duplicates(&links, &args);
+
+        let inode = |name: &str| fs::symlink_metadata(tmp.path().join(name)).unwrap().ino();
+        assert_eq!(inode("a"), inode("b"));
+        assert_ne!(inode("a"), inode("c"));
+        assert_eq!(
+            fs::read_link(tmp.path().join("b")).unwrap(),
+            Path::new("target")
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Journal of the files replaced by symlinks, for undoing conversions.
+//!
+//! Every conversion appends a `seconds\tmode\tcontent\tpath\ttarget` line,
+//! with the original content hex-encoded, the mode in octal and the absolute
+//! path and target as raw bytes.
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    str,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use restore_symlink::{
+    fs_ops::FsOps,
+    platform,
+    summary::{Outcome, SkipReason, Summary},
+};
+
+use crate::{
+    output::{self, report},
+    plan::listable,
+    print_error, print_io_error, refuse_unsafe_dir, report_batch, stop_early, with_retry, Args,
+};
+
+static JOURNAL: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// A conversion read back from the journal
+struct Entry<'a> {
+    mode: u32,
+    content: Vec<u8>,
+    path: Cow<'a, OsStr>,
+    target: Cow<'a, OsStr>,
+}
+
+/// Append an entry for every following conversion to the file at `path`
+pub fn open(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = JOURNAL.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Whether conversions are journaled, see [`open`]
+pub fn is_open() -> bool {
+    JOURNAL.get().is_some()
+}
+
+/// Record the file at `file_path` with `content` and `mode`, just turned into a symlink to `link`
+pub fn record(file_path: &Path, content: &[u8], mode: u32, link: &OsStr) -> io::Result<()> {
+    let Some(file) = JOURNAL.get() else {
+        return Ok(());
+    };
+    let file_path = std::path::absolute(file_path)?;
+    if !listable(file_path.as_os_str()) || !listable(link) {
+        return Err(io::Error::other("path or target cannot be written to it"));
+    }
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut entry = format!("{seconds}\t{mode:o}\t").into_bytes();
+    for byte in content {
+        entry.extend_from_slice(format!("{byte:02x}").as_bytes());
+    }
+    entry.push(b'\t');
+    entry.extend_from_slice(file_path.as_os_str().as_encoded_bytes());
+    entry.push(b'\t');
+    entry.extend_from_slice(link.as_encoded_bytes());
+    entry.push(b'\n');
+
+    let mut file = file.lock().unwrap_or_else(|error| error.into_inner());
+    file.write_all(&entry)?;
+    file.flush()
+}
+
+/// Turn the symlinks recorded in `journal` back into their original files, newest first
+///
+/// A path is only restored while it is still the symlink the conversion
+/// created.
+pub fn undo(journal: &Path, ops: &dyn FsOps, args: &Args, summary: &mut Summary) {
+    let lines = match fs::read(journal) {
+        Ok(content) => content,
+        Err(error) => {
+            print_io_error(journal, &error.to_string());
+            summary.add(journal, Outcome::Failed);
+            return;
+        }
+    };
+
+    let lines: Vec<_> = lines.split(|&byte| byte == b'\n').enumerate().collect();
+    for (number, line) in lines.into_iter().rev() {
+        if line.is_empty() {
+            continue;
+        }
+        let Some(entry) = parse(line) else {
+            print_error(
+                journal,
+                &format!("line {} is not a journal entry", number + 1),
+            );
+            summary.add(journal, Outcome::Failed);
+            continue;
+        };
+        let path = Path::new(&entry.path);
+        let outcome = match ops.read_link(path) {
+            Ok(current) if current.as_os_str() == entry.target => restore(ops, &entry, args),
+            Ok(_) | Err(_) => {
+                print_error(
+                    path,
+                    "is no longer the symlink the conversion created, not restoring it",
+                );
+                Outcome::Skipped(SkipReason::Stale)
+            }
+        };
+        summary.add(path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+fn parse(line: &[u8]) -> Option<Entry<'_>> {
+    let mut fields = line.split(|&byte| byte == b'\t');
+    let (Some(_), Some(mode), Some(content), Some(path), Some(target), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+    let mode = u32::from_str_radix(str::from_utf8(mode).ok()?, 8).ok()?;
+    let content = str::from_utf8(content).ok()?;
+    if content.len() % 2 != 0 {
+        return None;
+    }
+    let content = (0..content.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(content.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Entry {
+        mode,
+        content,
+        path: platform::os_str(path),
+        target: platform::os_str(target),
+    })
+}
+
+/// Replace the symlink of `entry` with the file it was made from
+fn restore(ops: &dyn FsOps, entry: &Entry, args: &Args) -> Outcome {
+    let link = Path::new(&entry.path);
+    if refuse_unsafe_dir(link, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+    if let Err(error) = with_retry(args.retry, || ops.remove_file(link)) {
+        print_io_error(link, &error.to_string());
+        return Outcome::Failed;
+    }
+    if let Err(error) = with_retry(args.retry, || ops.write(link, &entry.content, entry.mode)) {
+        match ops.symlink(&entry.target, link) {
+            Ok(()) => print_io_error(link, &format!("{error}, symlink restored")),
+            Err(restore_error) => print_io_error(
+                link,
+                &format!("{error}, cannot restore symlink: {restore_error}"),
+            ),
+        }
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!("Restored file: {}", output::shown(link))
+    }
+    Outcome::Restored
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use restore_symlink::fs_ops::{MemFs, Node};
+
+    use super::*;
+
+    #[test]
+    fn undo_restores_only_links_left_as_converted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let journal = tmp.path().join("journal");
+        fs::write(
+            &journal,
+            "1\t600\t6f6c64\t/mem/kept\ttarget\n1\t644\t6f6c64\t/mem/changed\ttarget\n",
+        )
+        .unwrap();
+        let ops = MemFs::new();
+        ops.create_dir_all(Path::new("/mem")).unwrap();
+        ops.symlink(OsStr::new("target"), Path::new("/mem/kept"))
+            .unwrap();
+        ops.symlink(OsStr::new("elsewhere"), Path::new("/mem/changed"))
+            .unwrap();
+        let args = Args::parse_from([
+            OsStr::new("restore_symlink"),
+            OsStr::new("undo"),
+            journal.as_os_str(),
+        ]);
+
+        let mut summary = Summary::default();
+        undo(&journal, &ops, &args, &mut summary);
+
+        assert_eq!(
+            ops.node(Path::new("/mem/kept")),
+            Some(Node::File {
+                content: b"old".to_vec(),
+                mode: 0o600
+            })
+        );
+        assert_eq!(
+            ops.node(Path::new("/mem/changed")),
+            Some(Node::Symlink("elsewhere".into()))
+        );
+        assert_eq!((summary.restored, summary.skipped()), (1, 1));
+    }
+}
@@ -0,0 +1,9 @@
+//! Building blocks for restoring symlinks from their text file form.
+
+pub mod convert;
+pub mod fs_ops;
+pub mod paths;
+pub mod platform;
+pub mod pointers;
+pub mod scan;
+pub mod summary;
@@ -0,0 +1,314 @@
+//! Listings of the symlinks of a tree, to recreate them after a transfer.
+//!
+//! The `dump-manifest` listing has a `path\ttarget` line per symlink, paths as
+//! raw bytes, as `find -type l -printf '%p\t%l\n'` writes it. Listings
+//! starting with `#` are read as mtree specifications instead.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use restore_symlink::{
+    convert::{create_temp_sibling, Converter},
+    platform,
+    scan::{scan, Classification, OnSymlink, ScanOptions},
+    summary::{Outcome, SkipReason, Summary},
+};
+
+use crate::{
+    convert_file, echo_confirmed,
+    output::{self, report},
+    plan::listable,
+    print_error, print_io_error, prompt, record_converted, refuse_unsafe_dir, report_batch,
+    stop_early, with_retry, Args,
+};
+
+/// Write a `path\ttarget` line to stdout for every symlink met walking `root`
+///
+/// Symlinks whose path or target holds a tab or a newline cannot be listed
+/// and are reported instead. Returns how many were listed.
+pub fn dump(root: &Path, options: &ScanOptions) -> io::Result<usize> {
+    let options = ScanOptions {
+        only_files: Some(HashSet::new()),
+        on_symlink: OnSymlink::Skip,
+        parallel: false,
+        ..options.clone()
+    };
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut listed = 0;
+    for candidate in scan(root, &options) {
+        let Classification::Symlink { target } = &candidate.classification else {
+            continue;
+        };
+        if !listable(candidate.path.as_os_str()) || !listable(target.as_os_str()) {
+            print_error(
+                &candidate.path,
+                "path or target cannot be written to a manifest",
+            );
+            continue;
+        }
+        let mut line = candidate.path.as_os_str().as_encoded_bytes().to_vec();
+        line.push(b'\t');
+        line.extend_from_slice(target.as_os_str().as_encoded_bytes());
+        line.push(b'\n');
+        stdout.write_all(&line)?;
+        listed += 1;
+    }
+    stdout.flush()?;
+    Ok(listed)
+}
+
+/// Create every symlink listed in `manifest`, relative paths taken from `dir`
+///
+/// Targets are used exactly as listed. A regular file no longer than `--len`
+/// found in place of a link is taken as its placeholder and replaced, as is
+/// a symlink to another target. Anything else is left alone and reported.
+pub fn apply(
+    manifest: &Path,
+    dir: &Path,
+    converter: &Converter,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    let content = match fs::read(manifest) {
+        Ok(content) => content,
+        Err(error) => {
+            print_io_error(manifest, &error.to_string());
+            summary.add(manifest, Outcome::Failed);
+            return;
+        }
+    };
+    let links = if content.starts_with(b"#") {
+        parse_mtree(&content)
+    } else {
+        parse_find(&content)
+    };
+
+    for link in links {
+        let (path, target) = match link {
+            Ok(link) => link,
+            Err(number) => {
+                print_error(manifest, &format!("line {number} is not a symlink entry"));
+                summary.add(manifest, Outcome::Failed);
+                continue;
+            }
+        };
+        let path = dir.join(path.strip_prefix(".").unwrap_or(&path));
+        let outcome = restore_link(converter, &path, &target, args);
+        summary.add(&path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+/// Make `link_path` the symlink to `link_val`, whatever holds its place
+fn restore_link(converter: &Converter, link_path: &Path, link_val: &OsStr, args: &Args) -> Outcome {
+    let ops = converter.ops();
+    let replaced = match ops.metadata(link_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() <= args.len => {
+            return convert_file(converter, link_path, link_val, &metadata, args);
+        }
+        Ok(metadata) if metadata.is_symlink() => match ops.read_link(link_path) {
+            Ok(current) if current.as_os_str() == link_val => {
+                if args.verbose > 0 {
+                    report!("{} is already a symlink", output::shown(link_path))
+                }
+                return Outcome::Skipped(SkipReason::Symlink);
+            }
+            _ => true,
+        },
+        Ok(_) => {
+            print_error(link_path, "is no placeholder nor symlink, not replacing it");
+            return Outcome::Failed;
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+        Err(error) => {
+            print_io_error(link_path, &error.to_string());
+            return Outcome::Failed;
+        }
+    };
+    if refuse_unsafe_dir(link_path, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+
+    if args.interactive
+        && !prompt::confirm(
+            &format!(
+                "Create symlink '{}' -> '{}'?",
+                output::shown(link_path),
+                output::text(link_val)
+            ),
+            args.interactive_default,
+        )
+    {
+        return Outcome::Skipped(SkipReason::Declined);
+    }
+
+    if let Some(parent) = link_path.parent() {
+        if let Err(error) = ops.create_dir_all(parent) {
+            print_io_error(link_path, &error.to_string());
+            return Outcome::Failed;
+        }
+    }
+    echo_confirmed(link_path, link_val, args);
+    let created = if replaced {
+        create_temp_sibling(link_path, |temp| {
+            with_retry(args.retry, || ops.symlink(link_val, temp))
+        })
+        .and_then(|temp| {
+            with_retry(args.retry, || ops.rename(&temp, link_path)).inspect_err(|_| {
+                let _ = ops.remove_file(&temp);
+            })
+        })
+    } else {
+        with_retry(args.retry, || ops.symlink(link_val, link_path))
+    };
+    if let Err(error) = created {
+        print_io_error(link_path, &error.to_string());
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!(
+            "Restored symlink: {} -> {}",
+            output::shown(link_path),
+            output::text(link_val)
+        )
+    }
+    record_converted(link_path, args);
+    Outcome::Converted
+}
+
+/// The `path\ttarget` lines of `content`, or the number of a line that is not one
+fn parse_find(content: &[u8]) -> Vec<Result<(PathBuf, OsString), usize>> {
+    content
+        .split(|&byte| byte == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| {
+            let mut fields = line.split(|&byte| byte == b'\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(path), Some(target), None) if !path.is_empty() && !target.is_empty() => Ok((
+                    PathBuf::from(platform::os_string(path.to_vec())),
+                    platform::os_string(target.to_vec()),
+                )),
+                _ => Err(number + 1),
+            }
+        })
+        .collect()
+}
+
+/// The `type=link` entries of the mtree specification `content`
+///
+/// Names without a `/` are relative to the last directory entered, as
+/// `mtree -c` writes them, others to the top of the tree. Only the
+/// `type` and `link` keywords are looked at.
+fn parse_mtree(content: &[u8]) -> Vec<Result<(PathBuf, OsString), usize>> {
+    let mut links = Vec::new();
+    let mut defaults: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut cwd = PathBuf::new();
+    let mut entry = Vec::new();
+    let mut first_line = 0;
+    for (number, line) in content.split(|&byte| byte == b'\n').enumerate() {
+        if entry.is_empty() {
+            first_line = number + 1;
+        }
+        match line.strip_suffix(b"\\") {
+            Some(line) => {
+                entry.extend_from_slice(line);
+                entry.push(b' ');
+                continue;
+            }
+            None => entry.extend_from_slice(line),
+        }
+        let line = std::mem::take(&mut entry);
+        let mut words = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty());
+        let Some(name) = words.next() else {
+            continue;
+        };
+        let keywords = words.map(|word| match word.iter().position(|&byte| byte == b'=') {
+            Some(at) => (&word[..at], &word[at + 1..]),
+            None => (word, &b""[..]),
+        });
+        match name {
+            _ if name.starts_with(b"#") => {}
+            b"/set" => {
+                for (key, value) in keywords {
+                    defaults.insert(key.to_vec(), value.to_vec());
+                }
+            }
+            b"/unset" => {
+                for (key, _) in keywords {
+                    defaults.remove(key);
+                }
+            }
+            b".." => {
+                cwd.pop();
+            }
+            _ => {
+                let mut keywords: HashMap<&[u8], &[u8]> = keywords.collect();
+                let name = PathBuf::from(platform::os_string(unvis(name)));
+                let kind = keywords
+                    .remove(&b"type"[..])
+                    .or(defaults.get(&b"type"[..]).map(Vec::as_slice));
+                let full = name.as_os_str().as_encoded_bytes().contains(&b'/');
+                let path = if full { name } else { cwd.join(name) };
+                match kind {
+                    Some(b"dir") if !full => cwd = path,
+                    Some(b"link") => match keywords.get(&b"link"[..]) {
+                        Some(target) if !target.is_empty() => {
+                            links.push(Ok((path, platform::os_string(unvis(target)))))
+                        }
+                        _ => links.push(Err(first_line)),
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+    links
+}
+
+/// Decode the `\ooo` octal and `\c` escapes mtree writes names and targets with
+fn unvis(text: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            decoded.push(byte);
+            continue;
+        }
+        let mut octal = 0u32;
+        let mut digits = 0;
+        while digits < 3 {
+            match bytes.peek() {
+                Some(&digit @ b'0'..=b'7') => {
+                    octal = octal * 8 + u32::from(digit - b'0');
+                    digits += 1;
+                    bytes.next();
+                }
+                _ => break,
+            }
+        }
+        if digits > 0 {
+            decoded.push(octal as u8);
+            continue;
+        }
+        match bytes.next() {
+            Some(b's') => decoded.push(b' '),
+            Some(b't') => decoded.push(b'\t'),
+            Some(b'n') => decoded.push(b'\n'),
+            Some(escaped) => decoded.push(escaped),
+            None => decoded.push(b'\\'),
+        }
+    }
+    decoded
+}
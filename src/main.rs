@@ -1,20 +1,85 @@
 use std::{
-    fs,
-    io::Read,
-    os::unix,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, Metadata},
+    io::{self, IsTerminal, Read},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    process, slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use encoding_rs::Encoding;
+use globset::{Glob, GlobBuilder};
+use output::report;
+use prompt::Reply;
+use rayon::prelude::*;
+use regex::bytes::Regex;
+use restore_symlink::{
+    convert::{self, in_unsafe_dir, with_retry, Converter, ReplaceError, Replacement, TargetForm},
+    fs_ops::{DryRun, FsOps, RealFs},
+    paths, platform,
+    scan::{
+        find_collisions, find_duplicates, inspect, link_target_exists, scan, Candidate,
+        Classification, InvalidOptions, OnSymlink, ScanOptions,
+    },
+    summary::{Outcome, SkipReason, Summary},
+};
+
+mod backup;
+mod config;
+mod converted_list;
+mod dedup;
+mod doctor;
+mod from_tar;
+mod git;
+mod hardlink;
+mod journal;
+mod listing;
+mod manifest;
+mod output;
+mod plan;
+mod prompt;
+mod prune;
+mod repair;
+mod reverse;
+#[cfg(test)]
+mod test_fs;
+mod tree;
+#[cfg(target_os = "linux")]
+mod watch;
+
+/// Version with the build details captured by build.rs, shown by `--version`
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("BUILD_GIT_COMMIT"),
+    "\nbuilt: ",
+    env!("BUILD_DATE"),
+    "\ntarget: ",
+    env!("BUILD_TARGET"),
+);
 
 /// Simple program to convert text file into symlink from its content.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, long_version = LONG_VERSION, about, long_about = None, args_override_self = true)]
 struct Args {
-    /// Path to a file or dir
-    path: PathBuf,
+    /// Paths to files or dirs, handled in order. Files directly inside a dir are always processed
+    #[arg(default_value = ".", num_args = 1..)]
+    paths: Vec<PathBuf>,
 
-    /// Walk through content of the directory recursively
+    /// Ignore ~/.config/restore-symlink/config.toml and .restore-symlink.toml in the first PATH
+    #[arg(long)]
+    no_config: bool,
+
+    /// Also walk through subdirectories of the dir recursively
     #[arg(short, long)]
     recursive: bool,
 
@@ -26,141 +91,3196 @@ struct Args {
     #[arg(short, long)]
     interactive: bool,
 
-    /// Maximum file length to be considered as possible link
-    #[arg(short, long, default_value = "512")]
+    /// Show what would be converted and print the totals, without changing anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Maximum file length in bytes to be considered as possible link, at least 1
+    #[arg(short, long, default_value = "512", value_parser = parse_len)]
     len: u64,
 
-    /// Explain what is being done
-    #[arg(short, long, conflicts_with = "silent")]
-    verbose: bool,
+    /// Minimum file length in bytes to be considered as possible link
+    #[arg(long, value_name = "N", default_value = "1")]
+    min_len: u64,
+
+    /// Keep a newline ending the file content as part of the target instead of dropping it
+    #[arg(long)]
+    keep_trailing_newline: bool,
+
+    /// Explain what is being done. Give twice to also print the effective options first, three times to also list each directory entered
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "silent")]
+    verbose: u8,
+
+    /// Read symlink target from the extended attribute NAME, falling back to file content
+    #[arg(long, value_name = "NAME")]
+    from_xattr: Option<String>,
+
+    /// Re-read each created symlink and restore the original file on mismatch
+    #[arg(long)]
+    verify_after: bool,
+
+    /// Annotate each conversion with whether its target existed
+    #[arg(long)]
+    show_target_state: bool,
+
+    /// Strip a trailing comment starting with CHAR from the target
+    #[arg(long, value_name = "CHAR")]
+    strip_comment: Option<char>,
+
+    /// Trim each of CHARS along with whitespace from both ends of the target, \0 standing for NUL
+    #[arg(long, value_name = "CHARS", value_parser = parse_trim_chars)]
+    trim_chars: Option<String>,
+
+    /// Retry removing files and creating symlinks up to N times on transient errors
+    #[arg(long, value_name = "N", default_value = "0")]
+    retry: u32,
+
+    /// What to do with stubs whose names differ only by letter case
+    #[arg(long, value_enum, default_value_t = OnCollision::Skip)]
+    on_collision: OnCollision,
+
+    /// Delete empty files instead of skipping them
+    #[arg(long)]
+    delete_empty: bool,
+
+    /// Only convert stubs whose target resolves under DIR. May be repeated
+    #[arg(long, value_name = "DIR")]
+    target_must_be_under: Vec<PathBuf>,
+
+    /// Append a timestamped record of every outcome, error and message to the file at PATH
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Send the records --log-file writes to syslog too
+    #[arg(long)]
+    syslog: bool,
+
+    /// Most detailed records to log
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t = output::Level::Info)]
+    log_level: output::Level,
+
+    /// Create symlinks in the dir PATH from the stubs stored in the tar ARCHIVE
+    #[arg(long, value_name = "ARCHIVE")]
+    from_tar: Option<PathBuf>,
+
+    /// Append the SHA-256 of each converted file's content to the manifest PATH
+    #[arg(long, value_name = "PATH")]
+    checksum_manifest: Option<PathBuf>,
+
+    /// Copy each file to DIR before converting it, at its path relative to PATH, keeping mode and mtime
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// Append the original content and mode of each converted file to the journal PATH, for `undo`
+    #[arg(long, value_name = "PATH", conflicts_with = "hardlink")]
+    journal: Option<PathBuf>,
+
+    /// Write the paths of the converted stubs to PATH, one per line
+    #[arg(long, value_name = "PATH")]
+    converted_list: Option<PathBuf>,
+
+    /// End each path of --converted-list with a NUL instead of a newline
+    #[arg(long, requires = "converted_list")]
+    converted_list_null: bool,
+
+    /// Do not skip files and dirs ignored by .gitignore and .ignore files
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Skip files and dirs ignored by .gitignore files, which is the default now
+    #[arg(long, hide = true, conflicts_with = "no_ignore")]
+    gitignore: bool,
+
+    /// Also walk and convert hidden files and dirs, whose name starts with a dot
+    #[arg(long)]
+    hidden: bool,
+
+    /// Convert exactly the files listed one per line in FILE, - for stdin, instead of walking PATH
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["from_tar", "apply_plan", "reverse", "only_broken_symlinks", "set_target"])]
+    files_from: Option<PathBuf>,
+
+    /// Separate the paths of --files-from with NUL instead of newlines
+    #[arg(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Only convert files the index of the enclosing git work tree records as symlinks
+    #[arg(long)]
+    git: bool,
+
+    /// Link git-annex pointer files (/annex/objects/KEY) to their object in the annex
+    #[arg(long)]
+    annex: bool,
+
+    /// Report git-LFS pointer files with where their object is, instead of converting them
+    #[arg(long)]
+    lfs: bool,
+
+    /// Skip files and dirs matching GLOB, not descending into them. A GLOB without / matches names, others paths relative to PATH. May be repeated
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<Glob>,
+
+    /// Only consider files matching GLOB, matched like --exclude. May be repeated
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    include: Vec<Glob>,
+
+    /// Match --exclude and --include globs ignoring letter case
+    #[arg(long)]
+    glob_ignore_case: bool,
+
+    /// Use the path of file:// URLs as target instead of skipping them
+    #[arg(long)]
+    strip_file_url: bool,
+
+    /// Follow targets that are stubs themselves and link to the final target
+    #[arg(long)]
+    flatten: bool,
+
+    /// How to print results
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// How to print bytes of paths and targets that are not valid UTF-8
+    #[arg(long, value_enum, default_value_t = OutputEncoding::Utf8)]
+    output_encoding: OutputEncoding,
+
+    /// How to print the totals, json printing them even without --verbose
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    summary_format: SummaryFormat,
+
+    /// Replace the file PATH with a symlink to TARGET, ignoring its content
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["recursive", "from_tar"])]
+    set_target: Option<OsString>,
+
+    /// Maximum target length in bytes, longer content is not considered a path
+    #[arg(long, value_name = "N", default_value_t = platform::PATH_MAX)]
+    max_target_len: usize,
+
+    /// Skip stubs whose target, as stored or made relative by --relativize, starts with more than N `..` components
+    #[arg(long, value_name = "N")]
+    max_relative_depth: Option<usize>,
+
+    /// Print nothing at all if nothing was converted or deleted and nothing failed
+    #[arg(long)]
+    quiet_if_noop: bool,
+
+    /// Only report stubs pointing to the same target, without converting anything
+    #[arg(long)]
+    report_duplicates: bool,
+
+    /// Only list the stubs that would be converted, exiting with status 7 if there are any
+    #[arg(long, conflicts_with_all = ["report_duplicates", "dump_plan"])]
+    check: bool,
+
+    /// Keep watching the dirs walked after the first pass, converting stubs as they are written
+    #[arg(long, conflicts_with_all = ["files_from", "from_tar", "apply_plan", "reverse", "dedup", "only_broken_symlinks", "set_target", "check", "dump_plan", "report_duplicates"])]
+    watch: bool,
+
+    /// With --watch, wait for DURATION without changes before converting what changed
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "500ms")]
+    watch_debounce: Duration,
+
+    /// Stop converting once DURATION (e.g. 30s, 5m) has passed and exit with status 3
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Turn symlinks created in this run that are identical into hardlinks of one link
+    #[arg(long)]
+    hardlink_duplicates: bool,
+
+    /// Read huge directories bit by bit instead of in one go
+    #[arg(long)]
+    chunked_readdir: bool,
+
+    /// Read and convert the files of a dir with N threads, the CPU count by default. Output keeps the walk order, --interactive always runs with one
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// With several jobs, convert the files of each dir one after the other in walk order, only working on different dirs at once
+    #[arg(long)]
+    parallel_dirs_only: bool,
+
+    /// Order to walk dir entries in: as the filesystem lists them, or sorted by name so output is the same whatever --jobs is
+    #[arg(long, value_enum, value_name = "ORDER", default_value_t = WalkOrder::Walk)]
+    parallel_walk_order: WalkOrder,
+
+    /// Print nothing but errors reading or changing the filesystem
+    #[arg(long)]
+    report_io_errors_only: bool,
+
+    /// Only convert stubs whose target the current user can read
+    #[arg(long)]
+    require_readable: bool,
+
+    /// Replace the leading path OLD of targets with NEW, whole components only, before --rewrite. May be repeated, the first matching one is used
+    #[arg(long, value_name = "OLD=NEW", value_parser = parse_map)]
+    map: Vec<(PathBuf, PathBuf)>,
+
+    /// Replace TOKEN in every target with the value of the RESTORE_BASE environment variable, before --rewrite
+    #[arg(long, value_name = "TOKEN", value_parser = parse_base_token)]
+    base_token: Option<OsString>,
+
+    /// Replace FROM with TO in every target before using it. May be repeated, applied in order
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_rewrite)]
+    rewrite: Vec<(OsString, OsString)>,
+
+    /// Replace matches of PATTERN with REPLACEMENT ($1 for groups) after --rewrite. May be repeated
+    #[arg(long, visible_alias = "map-regex", value_name = "PATTERN=REPLACEMENT", value_parser = parse_rewrite_regex)]
+    rewrite_regex: Vec<(Regex, OsString)>,
+
+    /// Take backslashes in targets as path separators, for stubs written on Windows
+    #[arg(long)]
+    normalize_separators: bool,
+
+    /// Exit with status 4 if more than N entries were skipped
+    #[arg(long, value_name = "N")]
+    max_skips: Option<usize>,
+
+    /// Stop at the first entry that fails to convert instead of going on with the others
+    #[arg(long)]
+    strict: bool,
+
+    /// Convert stubs whose target contains control characters such as tabs or NUL bytes
+    #[arg(long)]
+    allow_control_chars: bool,
+
+    /// Skip stubs whose target contains control characters, which is the default now
+    #[arg(long, hide = true, conflicts_with = "allow_control_chars")]
+    strict_path: bool,
+
+    /// Make absolute targets under DIR relative to the dir of their link, DIR being the PATH argument if not given
+    #[arg(long, value_name = "DIR", conflicts_with = "absolutize")]
+    relativize: Option<Option<PathBuf>>,
+
+    /// Make relative targets absolute, resolving them from the dir of their link
+    #[arg(long)]
+    absolutize: bool,
+
+    /// Print paths relative to DIR, the PATH argument if DIR is not given
+    #[arg(long, value_name = "DIR")]
+    report_relative_to: Option<Option<PathBuf>>,
+
+    /// Decode file contents from ENCODING (e.g. latin1) instead of taking them as raw bytes
+    #[arg(long, value_name = "ENCODING", value_parser = parse_encoding)]
+    encoding: Option<&'static Encoding>,
+
+    /// Leave out entries sorting before PATH to continue an interrupted run. Approximate, as directories are read unsorted
+    #[arg(long, value_name = "PATH")]
+    resume_from: Option<PathBuf>,
+
+    /// Use the first line of stubs spanning several lines instead of skipping them
+    #[arg(long)]
+    first_line: bool,
+
+    /// Answer to assume when a question of --interactive is answered with just Enter
+    #[arg(long, value_enum, requires = "interactive")]
+    interactive_default: Option<Answer>,
+
+    /// Leave files less than N directories below PATH alone, 1 being the entries of PATH itself
+    #[arg(long, value_name = "N")]
+    min_depth: Option<usize>,
+
+    /// Link to a target whose name differs only by case when the stored one does not exist
+    #[arg(long)]
+    ignore_case_targets: bool,
+
+    /// Also create symlinks to targets that do not exist yet, if they are free of whitespace and contain a / or a .
+    #[arg(long, visible_alias = "allow-missing-target")]
+    force: bool,
+
+    /// Check right before deleting a stub that it has not changed since it was read
+    #[arg(long)]
+    no_clobber: bool,
+
+    /// Warn about symlinks made that resolve through more than N symlinks, 8 if N is not given
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "8")]
+    warn_on_large_targets: Option<usize>,
+
+    /// Only consider files modified after TIME, a duration ago like 1h or a timestamp like 2024-05-01T12:00:00Z
+    #[arg(long, value_name = "TIME", value_parser = parse_modified_since)]
+    modified_since: Option<SystemTime>,
+
+    /// Print the totals so far after every N conversions
+    #[arg(long, value_name = "N")]
+    batch_size: Option<NonZeroUsize>,
+
+    /// What to do with symlinks met while scanning
+    #[arg(long, value_enum, default_value_t = OnSymlinkArg::Skip)]
+    on_symlink: OnSymlinkArg,
+
+    /// Descend into symlinked directories, each directory at most once. Same as --on-symlink follow
+    #[arg(long, conflicts_with = "on_symlink")]
+    follow: bool,
+
+    /// Do not descend into dirs on another filesystem than their PATH
+    #[arg(short = 'x', long)]
+    one_file_system: bool,
+
+    /// Only convert files owned by the user running the program
+    #[arg(long)]
+    same_owner: bool,
+
+    /// Also convert files in dirs everyone can write to, which are refused by default
+    #[arg(long = "unsafe")]
+    allow_unsafe: bool,
+
+    /// Walk at most N levels below each dir, implying --recursive. 1 handles the files directly inside
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Add files and bytes per second to the summary printed with --verbose
+    #[arg(long)]
+    report_throughput: bool,
+
+    /// Print the totals, skip reasons, bytes reclaimed and elapsed time at the end, even with --silent
+    #[arg(long, overrides_with = "no_summary")]
+    summary: bool,
+
+    /// Print no totals at the end, not even with --verbose or --dry-run
+    #[arg(long, overrides_with = "summary")]
+    no_summary: bool,
+
+    /// Do not show a progress line on stderr when it is a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Refuse to run while another run holds a lock on the file PATH
+    #[arg(long, value_name = "PATH")]
+    lock_file: Option<PathBuf>,
+
+    /// Only write the conversions that would be made to the file PATH, without converting
+    #[arg(long, value_name = "PATH")]
+    dump_plan: Option<PathBuf>,
+
+    /// Make exactly the conversions listed in the plan file PATH, skipping stubs changed since
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["from_tar", "set_target", "dump_plan"])]
+    apply_plan: Option<PathBuf>,
+
+    /// Replace stubs with hardlinks to their target instead of symlinks. The target must be a file on the same filesystem
+    #[arg(long, alias = "hard", conflicts_with_all = ["from_tar", "hardlink_duplicates"])]
+    hardlink: bool,
+
+    /// Replace stubs with a copy of their target instead of symlinks, for filesystems without links
+    #[arg(long, conflicts_with_all = ["from_tar", "hardlink", "hardlink_duplicates", "journal", "preserve"])]
+    copy: bool,
+
+    /// With --copy, copy targets that are directories along with everything below them
+    #[arg(long, requires = "copy")]
+    copy_dirs: bool,
+
+    /// Give each symlink the owner, timestamps and user.* extended attributes of its stub
+    #[arg(long, conflicts_with_all = ["from_tar", "hardlink"])]
+    preserve: bool,
+
+    /// With --preserve, copy every extended attribute instead of only user.* ones
+    #[arg(long, requires = "preserve")]
+    all_xattrs: bool,
+
+    /// Remove directories left empty by deleting placeholders, once done
+    #[arg(long)]
+    prune_empty_dirs: bool,
+
+    /// With --prune-empty-dirs, remove every empty directory below PATH, not just those the run emptied
+    #[arg(long, requires = "prune_empty_dirs")]
+    prune_all_empty: bool,
+
+    /// Turn symlinks into files holding their target instead, the other way round
+    #[arg(
+        long,
+        conflicts_with_all = ["from_tar", "set_target", "apply_plan", "dump_plan", "hardlink", "copy"]
+    )]
+    reverse: bool,
+
+    /// Only re-create broken symlinks, from the stub NAME.target next to each
+    #[arg(
+        long,
+        conflicts_with_all = ["from_tar", "set_target", "apply_plan", "hardlink", "copy", "reverse"]
+    )]
+    only_broken_symlinks: bool,
+
+    /// Replace files with the content of a file walked earlier with relative symlinks to that file
+    #[arg(
+        long,
+        conflicts_with_all = ["from_tar", "set_target", "apply_plan", "dump_plan", "hardlink", "copy", "reverse", "only_broken_symlinks"]
+    )]
+    dedup: bool,
+
+    /// Leave files shorter than N bytes alone with --dedup
+    #[arg(long, value_name = "N", default_value = "1", requires = "dedup")]
+    dedup_min_size: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Report stubs and problems below PATH without changing anything, exiting with status 6 on problems
+    Doctor {
+        /// Path to a file or dir
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Turn the symlinks recorded in the --journal file JOURNAL back into their original files, newest first
+    Undo { journal: PathBuf },
+    /// Write a `path<TAB>target` line for every symlink below PATH to stdout, for apply-manifest
+    DumpManifest {
+        /// Path to a file or dir
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Create the symlinks listed in MANIFEST below DIR, replacing their placeholder files
+    ///
+    /// MANIFEST is written by dump-manifest or `find -type l -printf '%p\t%l\n'`,
+    /// or is an mtree specification.
+    ApplyManifest {
+        manifest: PathBuf,
+        /// Dir the relative paths of MANIFEST start from
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Write the completion script for SHELL to stdout
+    Completions { shell: clap_complete::Shell },
+    /// Write the man page to stdout, as roff
+    Man,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// A line for each result as it happens
+    Text,
+    /// All processed paths as an indented tree once done
+    Tree,
+    /// A JSON object for each result and error as it happens, then one with the totals
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputEncoding {
+    /// As U+FFFD replacement characters
+    Utf8,
+    /// As \xNN escapes, along with control characters
+    Escape,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SummaryFormat {
+    /// Totals and skip reasons as sentences, with --verbose
+    Text,
+    /// A single JSON object with the totals, bytes reclaimed and elapsed time
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Answer {
+    Yes,
+    No,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OnSymlinkArg {
+    /// Leave symlinks alone
+    Skip,
+    /// Descend into symlinked directories
+    Follow,
+    /// Convert the file or walk the directory a symlink points to
+    ConvertTarget,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum WalkOrder {
+    /// As the filesystem lists them
+    Walk,
+    /// Sorted by name, unless with --chunked-readdir
+    Stable,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OnCollision {
+    /// Convert none of the colliding stubs, but for one converted before the others were read
+    Skip,
+    /// Convert the first colliding stub met and skip the rest
+    First,
+}
+
+fn parse_base_token(value: &str) -> Result<OsString, String> {
+    if value.is_empty() {
+        return Err("token must not be empty".to_owned());
+    }
+    Ok(value.into())
+}
+
+fn parse_modified_since(value: &str) -> Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("'{value}' ago is too long ago"));
+    }
+    humantime::parse_rfc3339_weak(value)
+        .map_err(|_| format!("'{value}' is neither a duration like 1h nor a timestamp"))
+}
+
+fn parse_map(value: &str) -> Result<(PathBuf, PathBuf), String> {
+    match value.split_once('=') {
+        Some(("", _)) => Err("prefix to map must not be empty".to_owned()),
+        Some((old, new)) => Ok((old.into(), new.into())),
+        None => Err("expected OLD=NEW".to_owned()),
+    }
+}
+
+fn parse_rewrite(value: &str) -> Result<(OsString, OsString), String> {
+    match value.split_once('=') {
+        Some(("", _)) => Err("text to rewrite must not be empty".to_owned()),
+        Some((from, to)) => Ok((from.into(), to.into())),
+        None => Err("expected FROM=TO".to_owned()),
+    }
+}
+
+fn parse_rewrite_regex(value: &str) -> Result<(Regex, OsString), String> {
+    let (pattern, replacement) = value
+        .split_once('=')
+        .ok_or_else(|| "expected PATTERN=REPLACEMENT".to_owned())?;
+    match Regex::new(pattern) {
+        Ok(pattern) => Ok((pattern, replacement.into())),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+fn parse_glob(value: &str) -> Result<Glob, String> {
+    Glob::new(value).map_err(|error| error.to_string())
+}
+
+/// `glob` as given, or ignoring letter case with `--glob-ignore-case`
+fn glob_of(glob: &Glob, args: &Args) -> Glob {
+    if !args.glob_ignore_case {
+        return glob.clone();
+    }
+    GlobBuilder::new(glob.glob())
+        .case_insensitive(true)
+        .build()
+        .expect("glob already parsed")
+}
+
+fn parse_trim_chars(value: &str) -> Result<String, String> {
+    Ok(value.replace("\\0", "\0"))
+}
+
+fn parse_encoding(value: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(value.as_bytes()).ok_or_else(|| format!("unknown encoding '{value}'"))
+}
+
+fn parse_len(value: &str) -> Result<u64, String> {
+    match value.parse() {
+        Ok(0) => Err("no file would be considered, use at least 1".to_owned()),
+        Ok(len) => Ok(len),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Entries converted together by the threads of `--jobs` before their output is printed.
+const PARALLEL_CHUNK: usize = 256;
+
+/// Entries read ahead of converting them, within which every stub of a case
+/// collision is found.
+const READ_AHEAD: usize = PARALLEL_CHUNK;
+
+/// Mode of a regular file created under the common 022 umask.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Symlinks Linux follows while resolving a path before failing with `ELOOP`.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Environment variable holding what `--base-token` is replaced with.
+const BASE_ENV: &str = "RESTORE_BASE";
+
+/// Exit status of a run in which some entries failed to convert.
+const EXIT_FAILED: i32 = 1;
+
+/// Exit status of a run refused for its options, or for files it cannot open before starting.
+const EXIT_USAGE: i32 = 2;
+
+/// Exit status of a run stopped by `--max-runtime` before every entry was handled.
+const EXIT_INCOMPLETE: i32 = 3;
+
+/// Exit status of a run that skipped more entries than `--max-skips` allows.
+const EXIT_TOO_MANY_SKIPS: i32 = 4;
+
+/// Exit status of a run refused because another one holds `--lock-file`.
+const EXIT_LOCKED: i32 = 5;
+
+/// Exit status of `doctor` when it found problems in the tree.
+const EXIT_PROBLEMS_FOUND: i32 = 6;
+
+/// Exit status of `--check` when there are stubs left to convert.
+const EXIT_STUBS_FOUND: i32 = 7;
+
+/// Print to stderr why the run cannot start and exit with [`EXIT_USAGE`]
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(EXIT_USAGE)
 }
 
 fn print_error(path: &Path, reason: &str) {
-    println!("Cannot convert '{}': {}", path.to_string_lossy(), reason)
+    if output::is_json() {
+        return error_event(path, reason);
+    }
+    output::error(&format!(
+        "Cannot convert '{}': {}",
+        output::shown(path),
+        reason
+    ))
 }
 
-fn link_target_exists(path: Option<&Path>, link: &str) -> bool {
-    match path {
-        Some(path) => path.join(link).exists(),
-        None => Path::new(link).to_owned().exists(),
+/// Like [`print_error`], for failures of the filesystem rather than of the stub
+fn print_io_error(path: &Path, reason: &str) {
+    if output::is_json() {
+        return error_event(path, reason);
     }
+    output::io_error(&format!(
+        "Cannot convert '{}': {}",
+        output::shown(path),
+        reason
+    ))
 }
 
-fn ask_for_confirmation(file: &Path, link: &str) -> bool {
-    println!(
-        "Convert '{}' file into symlink '{}'?",
-        file.to_string_lossy(),
-        link
-    );
-    loop {
-        let mut input = [0];
-        let _ = std::io::stdin().read(&mut input);
-        match input[0] as char {
-            'y' | 'Y' => return true,
-            'n' | 'N' => return false,
-            _ => println!("y/n only please."),
+/// `--format json` line for a stub that cannot be converted
+fn error_event(path: &Path, reason: &str) {
+    output::event(&format!(
+        "{{\"event\":\"error\",\"path\":{},\"message\":{}}}",
+        output::json_string(&output::shown(path)),
+        output::json_string(reason)
+    ))
+}
+
+/// Log the outcome of `path`, also printing it as a JSON event with `--format json`
+fn log_outcome(path: &Path, outcome: Outcome) {
+    let shown = output::shown(path);
+    let (level, text) = match outcome {
+        Outcome::Converted => match fs::read_link(path) {
+            Ok(target) => (
+                output::Level::Info,
+                format!("converted {shown} -> {}", output::text(target)),
+            ),
+            Err(_) => (output::Level::Info, format!("converted {shown}")),
+        },
+        Outcome::Deleted => (output::Level::Info, format!("deleted {shown}")),
+        Outcome::Restored => (output::Level::Info, format!("restored {shown}")),
+        Outcome::Skipped(reason) => (output::Level::Info, format!("skipped {shown} ({reason})")),
+        Outcome::Failed => (output::Level::Error, format!("failed {shown}")),
+    };
+    output::record(level, &text);
+    if output::is_json() {
+        outcome_event(path, outcome)
+    }
+}
+
+fn outcome_event(path: &Path, outcome: Outcome) {
+    let shown = output::json_string(&output::shown(path));
+    output::event(&match outcome {
+        Outcome::Converted => match fs::read_link(path) {
+            Ok(target) => format!(
+                "{{\"event\":\"converted\",\"path\":{shown},\"target\":{}}}",
+                output::json_string(&output::text(target))
+            ),
+            Err(_) => format!("{{\"event\":\"converted\",\"path\":{shown}}}"),
+        },
+        Outcome::Deleted => format!("{{\"event\":\"deleted\",\"path\":{shown}}}"),
+        Outcome::Restored => format!("{{\"event\":\"restored\",\"path\":{shown}}}"),
+        Outcome::Skipped(reason) => {
+            format!("{{\"event\":\"skipped\",\"path\":{shown},\"reason\":\"{reason}\"}}")
+        }
+        Outcome::Failed => format!("{{\"event\":\"failed\",\"path\":{shown}}}"),
+    })
+}
+
+/// `link_val` in the form `--relativize` or `--absolutize` asks for, if it changes
+fn reshape_target(link_path: &Path, link_val: &OsStr, args: &Args) -> Option<OsString> {
+    if let Some(dir) = &args.relativize {
+        let root = match dir {
+            Some(dir) => dir,
+            None => &roots(args)[0],
+        };
+        let root = paths::normalize(&std::path::absolute(root).unwrap_or_else(|_| root.to_owned()));
+        if !paths::normalize(Path::new(link_val)).starts_with(&root) {
+            return None;
         }
+        return convert::reshape_target(link_path, link_val, TargetForm::Relative);
+    }
+    if args.absolutize {
+        return convert::reshape_target(link_path, link_val, TargetForm::Absolute);
+    }
+    None
+}
+
+/// Whether `path` is in a dir everyone can write to without `--unsafe` given, reported if so
+///
+/// Others could swap such a path for something else between checking and converting it.
+fn refuse_unsafe_dir(path: &Path, args: &Args) -> bool {
+    if args.allow_unsafe {
+        return false;
+    }
+    let world_writable = in_unsafe_dir(path);
+    if world_writable {
+        print_error(
+            path,
+            "its dir is writable by everyone, pass --unsafe to change it anyway",
+        );
+    }
+    world_writable
+}
+
+/// What the target `link_val` of the link at `link_path` is, for the prompt
+fn target_kind(link_path: &Path, link_val: &OsStr) -> &'static str {
+    match fs::metadata(link_path.parent().unwrap_or(Path::new("")).join(link_val)) {
+        Ok(metadata) if metadata.is_dir() => "a directory",
+        Ok(metadata) if metadata.is_file() => "a file",
+        Ok(_) => "a special file",
+        Err(_) => "missing",
+    }
+}
+
+/// Tell which symlink is about to be made, where `--interactive` asked for it
+fn echo_confirmed(link_path: &Path, link_val: &OsStr, args: &Args) {
+    if args.interactive && args.verbose > 0 {
+        let target = link_path.parent().unwrap_or(Path::new("")).join(link_val);
+        report!(
+            "Creating {}: {} -> {} (resolved {})",
+            link_kind(args),
+            output::shown(link_path),
+            output::text(link_val),
+            std::path::absolute(&target).unwrap_or(target).display()
+        )
+    }
+}
+
+/// Check that the symlink at `file_path` points exactly to `link`
+fn verify_symlink(ops: &dyn FsOps, file_path: &Path, link: &OsStr) -> Result<(), String> {
+    match ops.read_link(file_path) {
+        Ok(target) if target == Path::new(link) => Ok(()),
+        Ok(target) => Err(format!(
+            "created symlink points to '{}'",
+            output::text(target)
+        )),
+        Err(error) => Err(format!("cannot read created symlink: {error}")),
+    }
+}
+
+/// Check that the copy at `file_path` is a file as long as `target`, or a directory if that is one
+fn verify_copy(ops: &dyn FsOps, file_path: &Path, target: &Path) -> Result<(), String> {
+    let created = ops
+        .metadata(file_path)
+        .map_err(|error| format!("cannot read created copy: {error}"))?;
+    let target = fs::metadata(target).map_err(|error| format!("cannot read target: {error}"))?;
+    match (created.is_dir(), target.is_dir()) {
+        (true, true) => Ok(()),
+        (false, false) if created.is_file() && created.len() == target.len() => Ok(()),
+        _ => Err("created copy does not match the target".to_owned()),
+    }
+}
+
+fn verify_hardlink(ops: &dyn FsOps, file_path: &Path, target: &Path) -> Result<(), String> {
+    let created = ops
+        .metadata(file_path)
+        .map_err(|error| format!("cannot read created hardlink: {error}"))?;
+    let target = fs::metadata(target).map_err(|error| format!("cannot read target: {error}"))?;
+    if platform::same_file(&created, &target) {
+        Ok(())
+    } else {
+        Err("created hardlink is not the target".to_owned())
+    }
+}
+
+/// Replace whatever is at `file_path` with the original file content
+fn restore_original(
+    ops: &dyn FsOps,
+    file_path: &Path,
+    content: &[u8],
+    mode: u32,
+) -> std::io::Result<()> {
+    ops.remove_file(file_path)?;
+    ops.write(file_path, content, mode)
+}
+
+/// Put the original `content` back at the converted `file_path`, as the
+/// conversion cannot be kept for `reason`
+fn take_back(
+    ops: &dyn FsOps,
+    file_path: &Path,
+    content: &[u8],
+    mode: u32,
+    reason: &str,
+) -> Outcome {
+    match restore_original(ops, file_path, content, mode) {
+        Ok(()) => print_error(file_path, &format!("{reason}, original restored")),
+        Err(error) => print_io_error(
+            file_path,
+            &format!("{reason}, cannot restore original: {error}"),
+        ),
     }
+    Outcome::Failed
 }
 
-fn convert_file(file_path: &Path, args: &Args) {
-    if let Ok(metadata) = fs::metadata(file_path) {
-        if metadata.len() > args.len {
-            if args.verbose {
-                println!(
+/// Handle one scanned entry: convert it, report why it is skipped, or report an error
+fn convert_candidate(converter: &Converter, candidate: Candidate, args: &Args) -> Outcome {
+    let ops = converter.ops();
+    let path = &candidate.path;
+    match candidate.classification {
+        Classification::Convertible { target } => match &candidate.metadata {
+            Some(metadata) => convert_file(converter, path, &target, metadata, args),
+            None => Outcome::Failed,
+        },
+        Classification::Empty => {
+            if args.delete_empty {
+                return delete_empty(ops, path, args);
+            }
+            if args.verbose > 0 {
+                report!("File {} is empty", output::shown(path))
+            }
+            Outcome::Skipped(SkipReason::Empty)
+        }
+        Classification::TooBig { len } => {
+            if args.verbose > 0 {
+                report!(
                     "File {} is too big to be considered as symlink({} > {})",
-                    file_path.to_string_lossy(),
-                    fs::metadata(file_path).unwrap().len(),
+                    output::shown(path),
+                    len,
                     args.len
                 )
             }
+            Outcome::Skipped(SkipReason::TooBig)
         }
-        return;
-    }
-
-    if let Ok(link_val) = fs::read_to_string(file_path) {
-        if !link_target_exists(file_path.parent(), &link_val) {
-            if args.verbose {
-                println!(
+        Classification::TooSmall { len } => {
+            if args.verbose > 0 {
+                report!(
+                    "File {} is too small to be considered as symlink({} < {})",
+                    output::shown(path),
+                    len,
+                    args.min_len
+                )
+            }
+            Outcome::Skipped(SkipReason::TooSmall)
+        }
+        Classification::OutsideAllowedRoots { target } => {
+            let roots: Vec<_> = args
+                .target_must_be_under
+                .iter()
+                .map(|root| format!("'{}'", output::text(root)))
+                .collect();
+            print_error(
+                path,
+                &format!(
+                    "target '{}' is not under any of {}",
+                    output::text(target),
+                    roots.join(", ")
+                ),
+            );
+            Outcome::Skipped(SkipReason::OutsideAllowedRoots)
+        }
+        Classification::Url { target } => {
+            if args.verbose > 0 {
+                report!(
+                    "Content of {} looks like a URL, not a path: {}",
+                    output::shown(path),
+                    output::text(target).trim()
+                )
+            }
+            Outcome::Skipped(SkipReason::Url)
+        }
+        Classification::BrokenChain { target, reason } => {
+            print_error(path, &format!("{reason} at '{}'", output::text(target)));
+            Outcome::Failed
+        }
+        Classification::TargetTooLong { len } => {
+            if args.verbose > 0 {
+                report!(
+                    "Target of {} is too long to be a path({} > {})",
+                    output::shown(path),
+                    len,
+                    args.max_target_len
+                )
+            }
+            Outcome::Skipped(SkipReason::TargetTooLong)
+        }
+        Classification::RelativeTooDeep { target, depth } => {
+            if args.verbose > 0 {
+                report!(
+                    "Target of {} climbs up too many directories({} > {}): {}",
+                    output::shown(path),
+                    depth,
+                    args.max_relative_depth.unwrap_or_default(),
+                    output::text(target)
+                )
+            }
+            Outcome::Skipped(SkipReason::RelativeTooDeep)
+        }
+        Classification::TargetMissing { target } => {
+            if args.verbose > 0 {
+                report!(
                     "Symlink target {} -> {} does not exists",
-                    file_path.to_string_lossy(),
-                    link_val
+                    output::shown(path),
+                    output::text(target)
                 )
             }
-            return;
+            Outcome::Skipped(SkipReason::TargetMissing)
         }
-
-        if !args.interactive || ask_for_confirmation(file_path, &link_val) {
-            let _ = fs::remove_file(file_path);
-
-            if let Err(error) = unix::fs::symlink(&link_val, file_path) {
-                print_error(file_path, &error.to_string());
-                return;
+        Classification::TargetUnreadable { target } => {
+            if args.verbose > 0 {
+                report!(
+                    "Symlink target {} -> {} exists but is not readable",
+                    output::shown(path),
+                    output::text(target)
+                )
             }
-
-            if !args.silent {
-                println!(
-                    "Converted to symlink: {} -> {}",
-                    file_path.to_string_lossy(),
-                    link_val
+            Outcome::Skipped(SkipReason::TargetUnreadable)
+        }
+        Classification::MultiplePaths => {
+            if args.verbose > 0 {
+                report!(
+                    "Content of {} appears to be multiple paths",
+                    output::shown(path)
                 )
             }
+            Outcome::Skipped(SkipReason::MultiplePaths)
         }
-    }
-}
-
-fn convert_dir(dir_path: &Path, args: &Args) {
-    match fs::read_dir(dir_path) {
-        Ok(dir) => {
-            for entry in dir {
-                match entry {
-                    Ok(entry) => {
-                        let metadata = entry.metadata().unwrap();
-                        if metadata.is_dir() {
-                            convert_dir(&entry.path(), args)
-                        } else if metadata.is_file() {
-                            convert_file(&entry.path(), args)
-                        } else if metadata.is_symlink() {
-                            if args.verbose {
-                                let path = entry.path();
-                                println!(
-                                    "Skipped symlink {} -> {}",
-                                    path.to_string_lossy(),
-                                    fs::read_link(&path).unwrap_or_default().to_string_lossy()
-                                );
-                            }
-                        } else {
-                            print_error(&entry.path(), "Not a directory or a file or a symlink")
-                        }
-                    }
-                    Err(error) => print_error(dir_path, &error.to_string()),
+        Classification::ControlCharacters => {
+            if args.verbose > 0 {
+                report!(
+                    "Target of {} contains control characters",
+                    output::shown(path)
+                )
+            }
+            Outcome::Skipped(SkipReason::ControlCharacters)
+        }
+        Classification::NotUtf8 => {
+            if args.verbose > 0 {
+                report!(
+                    "Target of {} is not valid UTF-8 and does not exist",
+                    output::shown(path)
+                )
+            }
+            Outcome::Skipped(SkipReason::NotUtf8)
+        }
+        Classification::Directory { .. } => unreachable!("directories are reported by convert_dir"),
+        Classification::Excluded => {
+            if args.verbose > 0 {
+                report!("Skipped excluded {}", output::shown(path))
+            }
+            Outcome::Skipped(SkipReason::Excluded)
+        }
+        Classification::Unreadable(error) => {
+            if args.report_io_errors_only {
+                print_io_error(path, &error.to_string())
+            }
+            Outcome::Skipped(SkipReason::Unreadable)
+        }
+        Classification::Symlink { target } => {
+            if args.verbose > 0 {
+                report!(
+                    "Skipped symlink {} -> {}",
+                    output::shown(path),
+                    output::text(target)
+                );
+            }
+            Outcome::Skipped(SkipReason::Symlink)
+        }
+        Classification::OtherOwner { uid } => {
+            if args.verbose > 0 {
+                report!(
+                    "Skipped {} owned by another user (uid {})",
+                    output::shown(path),
+                    uid
+                );
+            }
+            Outcome::Skipped(SkipReason::OtherOwner)
+        }
+        Classification::LfsPointer { oid, object } => {
+            if !args.silent {
+                match object {
+                    Some(object) => report!(
+                        "LFS pointer {} -> object {} at {}",
+                        output::shown(path),
+                        oid,
+                        output::shown(&object)
+                    ),
+                    None => report!(
+                        "LFS pointer {} -> object {} not fetched",
+                        output::shown(path),
+                        oid
+                    ),
                 }
             }
+            Outcome::Skipped(SkipReason::LfsPointer)
+        }
+        Classification::Other => {
+            print_error(path, "Not a directory or a file or a symlink");
+            Outcome::Failed
+        }
+        Classification::Error(error) => {
+            print_io_error(path, &error.to_string());
+            Outcome::Failed
         }
-        Err(error) => print_error(dir_path, &error.to_string()),
     }
 }
 
-fn main() {
-    let args = Args::parse();
+fn delete_empty(ops: &dyn FsOps, file_path: &Path, args: &Args) -> Outcome {
+    if refuse_unsafe_dir(file_path, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+    if args.interactive
+        && !prompt::confirm(
+            &format!("Delete empty placeholder '{}'?", output::shown(file_path)),
+            args.interactive_default,
+        )
+    {
+        return Outcome::Skipped(SkipReason::Declined);
+    }
 
-    match fs::metadata(&args.path) {
-        Ok(metadata) => {
-            if metadata.is_dir() {
-                if args.recursive {
-                    convert_dir(&args.path, &args)
-                } else {
-                    print_error(
-                        &args.path,
-                        "Is a directory. Please specify 'recursive' flag",
-                    )
-                }
-            } else if metadata.is_file() {
-                convert_file(&args.path, &args)
-            } else {
-                print_error(&args.path, "Not a directory or file")
-            }
+    if let Err(error) = with_retry(args.retry, || ops.remove_file(file_path)) {
+        print_io_error(file_path, &error.to_string());
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!("Deleted empty placeholder: {}", output::shown(file_path))
+    }
+    Outcome::Deleted
+}
+
+/// Number of symlinks followed from `link` on, the link itself included, until
+/// reaching something else or giving up after as many as the kernel follows
+fn symlink_hops(ops: &dyn FsOps, link: &Path) -> usize {
+    let mut path = link.to_owned();
+    let mut hops = 0;
+    while hops <= MAX_SYMLINK_HOPS {
+        match ops.read_link(&path) {
+            Ok(target) => path = path.parent().unwrap_or(Path::new("")).join(target),
+            Err(_) => break,
+        }
+        hops += 1;
+    }
+    hops
+}
+
+/// What `--hardlink` or `--copy` makes the stubs
+fn link_kind(args: &Args) -> &'static str {
+    if args.hardlink {
+        "hardlink"
+    } else if args.copy {
+        "copy"
+    } else {
+        "symlink"
+    }
+}
+
+/// What the stub at `file_path` with `metadata` is replaced with for `link_val`
+///
+/// Checked before the stub is deleted, as it could not be put back otherwise.
+fn replacement(
+    file_path: &Path,
+    link_val: &OsStr,
+    metadata: &Metadata,
+    args: &Args,
+) -> Result<Replacement, String> {
+    if args.hardlink {
+        hardlink_target(file_path, link_val, metadata).map(Replacement::Hardlink)
+    } else if args.copy {
+        copied_target(file_path, link_val, args).map(Replacement::Copy)
+    } else {
+        Ok(Replacement::Symlink)
+    }
+}
+
+/// The file or, with `--copy-dirs`, directory `--copy` copies in place of the stub at `file_path`
+fn copied_target(file_path: &Path, link_val: &OsStr, args: &Args) -> Result<PathBuf, String> {
+    let target = file_path.parent().unwrap_or(Path::new("")).join(link_val);
+    let target_metadata = fs::metadata(&target)
+        .map_err(|error| format!("cannot copy '{}': {}", output::text(link_val), error))?;
+    if target_metadata.is_dir() && !args.copy_dirs {
+        return Err(format!(
+            "cannot copy '{}', it is a directory, pass --copy-dirs to copy it",
+            output::text(link_val)
+        ));
+    }
+    if !target_metadata.is_dir() && !target_metadata.is_file() {
+        return Err(format!(
+            "cannot copy '{}', it is not a regular file",
+            output::text(link_val)
+        ));
+    }
+    Ok(target)
+}
+
+/// The file the stub at `file_path` with `metadata` can be hardlinked to for `link_val`
+fn hardlink_target(
+    file_path: &Path,
+    link_val: &OsStr,
+    metadata: &Metadata,
+) -> Result<PathBuf, String> {
+    let target = file_path.parent().unwrap_or(Path::new("")).join(link_val);
+    let target_metadata = fs::metadata(&target)
+        .map_err(|error| format!("cannot hardlink to '{}': {}", output::text(link_val), error))?;
+    if target_metadata.is_dir() {
+        return Err(format!(
+            "cannot hardlink to '{}', it is a directory",
+            output::text(link_val)
+        ));
+    }
+    if !target_metadata.is_file() {
+        return Err(format!(
+            "cannot hardlink to '{}', it is not a regular file",
+            output::text(link_val)
+        ));
+    }
+    if platform::device(&target_metadata) != platform::device(metadata) {
+        return Err(format!(
+            "cannot hardlink to '{}', it is on another filesystem",
+            output::text(link_val)
+        ));
+    }
+    Ok(target)
+}
+
+fn convert_file(
+    converter: &Converter,
+    file_path: &Path,
+    link_val: &OsStr,
+    metadata: &Metadata,
+    args: &Args,
+) -> Outcome {
+    let ops = converter.ops();
+    if refuse_unsafe_dir(file_path, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+    let reshaped = reshape_target(file_path, link_val, args);
+    if let (Some(reshaped), Some(max)) = (&reshaped, args.max_relative_depth) {
+        let depth = paths::leading_parent_dirs(Path::new(reshaped));
+        if depth > max {
+            if args.verbose > 0 {
+                report!(
+                    "Target of {} made relative climbs up too many directories({} > {}): {}",
+                    output::shown(file_path),
+                    depth,
+                    max,
+                    output::text(reshaped)
+                )
+            }
+            return Outcome::Skipped(SkipReason::RelativeTooDeep);
+        }
+    }
+    let link_val = reshaped.as_deref().unwrap_or(link_val);
+    let edited;
+    let link_val = if args.interactive {
+        let question = format!(
+            "Convert '{}' file ({} bytes) into {} '{}' ({})?",
+            output::shown(file_path),
+            metadata.len(),
+            link_kind(args),
+            output::text(link_val),
+            target_kind(file_path, link_val)
+        );
+        match prompt::ask_conversion(&question, file_path, args.interactive_default) {
+            Reply::Yes => link_val,
+            Reply::Edit(target) => {
+                edited = target;
+                &edited
+            }
+            Reply::No | Reply::SkipDir => return Outcome::Skipped(SkipReason::Declined),
+        }
+    } else {
+        link_val
+    };
+    let target_exists = link_target_exists(file_path.parent(), link_val);
+
+    let replacement = match replacement(file_path, link_val, metadata, args) {
+        Ok(replacement) => replacement,
+        Err(reason) => {
+            print_error(file_path, &reason);
+            return Outcome::Failed;
+        }
+    };
+
+    let mode = platform::mode(metadata);
+    if args.verbose > 0 && matches!(replacement, Replacement::Symlink) {
+        if let Some(warning) = mode_warning(file_path, mode, platform::new_file_mode()) {
+            report!("{warning}")
+        }
+    }
+
+    let original = if !args.dry_run
+        && (args.verify_after
+            || args.checksum_manifest.is_some()
+            || journal::is_open()
+            || args.backup_dir.is_some())
+    {
+        match ops.read(file_path) {
+            Ok(content) => Some(content),
+            Err(error) => {
+                print_io_error(file_path, &error.to_string());
+                return Outcome::Failed;
+            }
+        }
+    } else {
+        None
+    };
+
+    let xattrs = if args.preserve {
+        match preserved_xattrs(file_path, args.all_xattrs) {
+            Ok(xattrs) => xattrs,
+            Err(error) => {
+                print_io_error(
+                    file_path,
+                    &format!("cannot read extended attributes: {error}"),
+                );
+                return Outcome::Failed;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    echo_confirmed(file_path, link_val, args);
+    match converter.replace(file_path, link_val, metadata, &replacement) {
+        Ok(()) => {}
+        Err(ReplaceError::UnsafeParent) => {
+            print_error(
+                file_path,
+                "its dir is writable by everyone, pass --unsafe to change it anyway",
+            );
+            return Outcome::Skipped(SkipReason::UnsafeParent);
+        }
+        Err(ReplaceError::Stale) => {
+            print_error(file_path, "changed since it was read, not replacing it");
+            return Outcome::Skipped(SkipReason::Stale);
+        }
+        Err(ReplaceError::Io(error)) => {
+            print_io_error(file_path, &error.to_string());
+            return Outcome::Failed;
+        }
+    }
+
+    if args.preserve {
+        if let Err(error) = ops.preserve(file_path, metadata, &xattrs) {
+            output::io_error(&format!(
+                "Cannot preserve metadata of '{}': {}",
+                output::shown(file_path),
+                error
+            ))
+        }
+    }
+
+    if let Some(content) = original.as_ref().filter(|_| args.verify_after) {
+        let verified = match &replacement {
+            Replacement::Symlink => verify_symlink(ops, file_path, link_val),
+            Replacement::Hardlink(target) => verify_hardlink(ops, file_path, target),
+            Replacement::Copy(target) => verify_copy(ops, file_path, target),
+        };
+        if let Err(reason) = verified {
+            return take_back(ops, file_path, content, mode, &reason);
+        }
+    }
+
+    if let Some(content) = &original {
+        if let Err(error) = manifest::record(file_path, content, link_val) {
+            let reason = format!("cannot write checksum manifest: {error}");
+            return take_back(ops, file_path, content, mode, &reason);
+        }
+    }
+
+    if let Some(content) = &original {
+        if let Err(error) = journal::record(file_path, content, mode, link_val) {
+            let reason = format!("cannot write journal: {error}");
+            return take_back(ops, file_path, content, mode, &reason);
+        }
+    }
+
+    if let (Some(content), Some(backup_dir)) = (&original, &args.backup_dir) {
+        if let Err(error) = backup::back_up(backup_dir, roots(args), file_path, content, metadata) {
+            let reason = format!("cannot back it up: {error}");
+            return take_back(ops, file_path, content, mode, &reason);
+        }
+    }
+
+    if !args.silent {
+        let target_state = match (args.show_target_state, target_exists) {
+            (false, _) => "",
+            (true, true) => " [target ok]",
+            (true, false) => " [target missing]",
+        };
+        report!(
+            "Converted to {}: {} -> {}{}",
+            link_kind(args),
+            output::shown(file_path),
+            output::text(link_val),
+            target_state
+        )
+    }
+    if let Some(max) = args.warn_on_large_targets {
+        let hops = symlink_hops(ops, file_path);
+        if hops > max {
+            report!(
+                "Symlink {} resolves through {} symlinks, more than {}",
+                output::shown(file_path),
+                hops,
+                max
+            )
+        }
+    }
+    record_converted(file_path, args);
+    Outcome::Converted
+}
+
+/// Warning that the symlink replacing `file_path` drops its `mode`, unless
+/// that is the `new_file_mode` any file created here would get anyway
+fn mode_warning(file_path: &Path, mode: u32, new_file_mode: u32) -> Option<String> {
+    (mode != new_file_mode).then(|| {
+        format!(
+            "File {} has permissions {:o}, the symlink will not keep them",
+            output::shown(file_path),
+            mode
+        )
+    })
+}
+
+/// The library converter making the changes `args` asks for through `ops`
+fn converter(args: &Args, options: &ScanOptions, ops: Arc<dyn FsOps>) -> Converter {
+    Converter::new(options.clone())
+        .with_ops(ops)
+        .retries(args.retry)
+        .allow_unsafe(args.allow_unsafe)
+        .no_clobber(args.no_clobber)
+}
+
+/// Add `path` to `--converted-list`, the conversion standing if that fails
+fn record_converted(path: &Path, args: &Args) {
+    if args.dry_run {
+        return;
+    }
+    if let Err(error) = converted_list::record(path) {
+        print_io_error(path, &format!("cannot write converted list: {error}"))
+    }
+}
+
+/// The first option given that needs what this platform does not have
+fn unsupported_option(args: &Args) -> Option<&'static str> {
+    [
+        (args.same_owner && cfg!(not(unix)), "--same-owner"),
+        (args.preserve && cfg!(not(unix)), "--preserve"),
+        (args.syslog && cfg!(not(unix)), "--syslog"),
+        (args.watch && cfg!(not(target_os = "linux")), "--watch"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+fn scan_options(args: &Args) -> Result<ScanOptions, InvalidOptions> {
+    let mut builder = ScanOptions::builder()
+        .len(args.len)
+        .min_len(args.min_len)
+        .strip_trailing_newline(!args.keep_trailing_newline);
+    if let Some(name) = &args.from_xattr {
+        builder = builder.from_xattr(name);
+    }
+    if let Some(marker) = args.strip_comment {
+        builder = builder.strip_comment(marker);
+    }
+    if let Some(chars) = &args.trim_chars {
+        builder = builder.trim_chars(chars);
+    }
+    match args.max_depth {
+        Some(depth) => builder = builder.max_depth(depth),
+        None if !args.recursive => builder = builder.max_depth(1),
+        None => {}
+    }
+    if let Some(depth) = args.max_relative_depth {
+        builder = builder.max_relative_depth(depth);
+    }
+    if let Some(time) = args.modified_since {
+        builder = builder.modified_since(time);
+    }
+    if let Some(min_depth) = args.min_depth {
+        builder = builder.min_depth(min_depth);
+    }
+    #[cfg(unix)]
+    if args.same_owner {
+        // SAFETY: getuid takes nothing and cannot fail.
+        builder = builder.owned_by(unsafe { libc::getuid() });
+    }
+    for glob in &args.exclude {
+        builder = builder.exclude(glob_of(glob, args));
+    }
+    for glob in &args.include {
+        builder = builder.include(glob_of(glob, args));
+    }
+    if args.git {
+        let mut symlinks = Vec::new();
+        for root in roots(args) {
+            symlinks.extend(git::symlinks(root).map_err(|error| {
+                InvalidOptions(format!(
+                    "cannot read the git index for '{}': {}",
+                    root.to_string_lossy(),
+                    error
+                ))
+            })?);
+        }
+        builder = builder.only_files(symlinks);
+    }
+    for dir in &args.target_must_be_under {
+        builder = builder.target_must_be_under(dir);
+    }
+    if let Some(encoding) = args.encoding {
+        builder = builder.encoding(encoding);
+    }
+    if let Some(path) = &args.resume_from {
+        builder = builder.resume_from(path);
+    }
+    for (old, new) in &args.map {
+        builder = builder.map_prefix(old, new);
+    }
+    if let Some(token) = &args.base_token {
+        let Some(base) = env::var_os(BASE_ENV) else {
+            return Err(InvalidOptions(format!(
+                "--base-token needs {BASE_ENV} to be set"
+            )));
+        };
+        builder = builder.rewrite(token.clone(), base);
+    }
+    for (from, to) in &args.rewrite {
+        builder = builder.rewrite(from.clone(), to.clone());
+    }
+    for (pattern, replacement) in &args.rewrite_regex {
+        builder = builder.rewrite_regex(pattern.clone(), replacement.clone());
+    }
+    builder
+        .gitignore(!args.no_ignore)
+        .skip_hidden(!args.hidden)
+        .annex(args.annex)
+        .lfs(args.lfs)
+        .one_file_system(args.one_file_system)
+        .strip_file_url(args.strip_file_url)
+        .flatten(args.flatten)
+        .max_target_len(args.max_target_len)
+        .streaming(args.chunked_readdir)
+        .parallel(jobs(args) > 1)
+        .sorted(args.parallel_walk_order == WalkOrder::Stable)
+        .require_readable(args.require_readable)
+        .report_dirs(args.verbose >= 3)
+        .first_line(args.first_line)
+        .normalize_separators(args.normalize_separators)
+        .ignore_case_targets(args.ignore_case_targets)
+        .allow_missing_targets(args.force)
+        .on_symlink(match args.on_symlink {
+            _ if args.follow => OnSymlink::Follow,
+            OnSymlinkArg::Skip => OnSymlink::Skip,
+            OnSymlinkArg::Follow => OnSymlink::Follow,
+            OnSymlinkArg::ConvertTarget => OnSymlink::ConvertTarget,
+        })
+        .strict_path(!args.allow_control_chars)
+        .build()
+}
+
+/// The settings a run goes by once config files are applied, shown with `-vv`
+fn effective_options(args: &Args, options: &ScanOptions) -> Vec<String> {
+    let globs = |globs: &[Glob]| match globs {
+        [] => "none".to_owned(),
+        globs => globs.iter().map(Glob::glob).collect::<Vec<_>>().join(", "),
+    };
+    fn value(value: &impl ValueEnum) -> String {
+        value.to_possible_value().unwrap().get_name().to_owned()
+    }
+    let walk = match (args.recursive, options.max_depth) {
+        (false, _) => "only the given dirs".to_owned(),
+        (true, None) => "recursive".to_owned(),
+        (true, Some(max_depth)) => format!("recursive, {max_depth} levels deep at most"),
+    };
+    let targets = if args.relativize.is_some() {
+        "made relative"
+    } else if args.absolutize {
+        "made absolute"
+    } else {
+        "as written"
+    };
+    vec![
+        format!("Stub size: {} to {} bytes", options.min_len, options.len),
+        format!(
+            "Walk: {walk}, {}",
+            match args.parallel_walk_order {
+                WalkOrder::Walk => "in listing order",
+                WalkOrder::Stable => "sorted by name",
+            }
+        ),
+        format!("Include: {}", globs(&args.include)),
+        format!(
+            "Exclude: {}{}",
+            globs(&args.exclude),
+            if args.glob_ignore_case {
+                ", ignoring case"
+            } else {
+                ""
+            }
+        ),
+        format!(
+            "Hidden files: {}, ignore files: {}",
+            if args.hidden { "walked" } else { "skipped" },
+            if args.no_ignore {
+                "not read"
+            } else {
+                "honored"
+            }
+        ),
+        format!(
+            "Symlinks: {}",
+            if args.follow {
+                "follow".to_owned()
+            } else {
+                value(&args.on_symlink)
+            }
+        ),
+        format!("Case collisions: {}", value(&args.on_collision)),
+        format!("Replace stubs with: {}", link_kind(args)),
+        format!(
+            "Targets: {targets}, missing ones {}",
+            if args.force { "accepted" } else { "skipped" }
+        ),
+        format!(
+            "Dry run: {}, jobs: {}, retries: {}",
+            if args.dry_run { "yes" } else { "no" },
+            jobs(args),
+            args.retry
+        ),
+        format!(
+            "Failures: {}",
+            if args.strict {
+                "stop the run"
+            } else {
+                "reported"
+            }
+        ),
+    ]
+}
+
+fn convert_dir(
+    converter: &Converter,
+    dir_path: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    deadline: Option<Instant>,
+    summary: &mut Summary,
+) {
+    let ops = converter.ops();
+    let mut candidates = scan(dir_path, options);
+    // Entries read ahead, and the lowercased paths of the stubs read so far
+    // to notice case collisions without holding on to every candidate
+    let mut ahead = VecDeque::new();
+    let mut met = HashMap::new();
+    let chunk_len = if jobs(args) > 1 { PARALLEL_CHUNK } else { 1 };
+    let mut converted = Vec::new();
+    let mut emptied = BTreeSet::new();
+    // With --strict, entries not started yet on any thread are left alone
+    // once one failed, as the run stops after the chunk
+    let failed = AtomicBool::new(false);
+    let process_unless_failed = |candidate| {
+        if args.strict && failed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let processed = process_captured(converter, candidate, args);
+        if processed.outcome == Some(Outcome::Failed) {
+            failed.store(true, Ordering::Relaxed);
+        }
+        Some(processed)
+    };
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            // Classifying the rest just to count it could take as long
+            // as converting it, so only whether anything is left is known
+            summary.stopped_early |= ahead.drain(..).chain(candidates).any(|candidate| {
+                !matches!(candidate.classification, Classification::Directory { .. })
+            });
+            break;
+        }
+        while ahead.is_empty() {
+            let mut read: Vec<Candidate> = candidates.by_ref().take(READ_AHEAD).collect();
+            if read.is_empty() {
+                break;
+            }
+            let colliding = skip_collisions(&read, &mut met, args, summary);
+            read.retain(|candidate| !colliding.contains(&candidate.path));
+            ahead.extend(read);
+        }
+        let chunk: Vec<Candidate> = ahead.drain(..chunk_len.min(ahead.len())).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let processed: Vec<Processed> = if chunk_len > 1 && args.parallel_dirs_only {
+            let mut processed: Vec<(usize, Processed)> = by_parent(chunk)
+                .into_par_iter()
+                .flat_map_iter(|entries| {
+                    entries.into_iter().map_while(|(index, candidate)| {
+                        Some((index, process_unless_failed(candidate)?))
+                    })
+                })
+                .collect();
+            processed.sort_by_key(|(index, _)| *index);
+            processed
+                .into_iter()
+                .map(|(_, processed)| processed)
+                .collect()
+        } else if chunk_len > 1 {
+            chunk
+                .into_par_iter()
+                .filter_map(process_unless_failed)
+                .collect()
+        } else {
+            chunk
+                .into_iter()
+                .map(|candidate| process_candidate(converter, candidate, args))
+                .collect()
+        };
+
+        for processed in processed {
+            output::replay(processed.lines);
+            let Some(outcome) = processed.outcome else {
+                continue;
+            };
+            let path = processed.path;
+            if outcome == Outcome::Converted {
+                summary.converted_bytes += processed.len;
+            }
+            if let Some(target) = processed.target.filter(|_| outcome == Outcome::Converted) {
+                converted.push((path.clone(), target));
+            }
+            if let Some(parent) = path.parent().filter(|_| outcome == Outcome::Deleted) {
+                emptied.insert(parent.to_owned());
+            }
+            summary.add(&path, outcome);
+            report_batch(summary, outcome, args)
+        }
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+
+    if args.hardlink_duplicates && !args.dry_run {
+        hardlink::hardlink_duplicates(&converted, args)
+    }
+    if args.prune_empty_dirs {
+        prune::prune_empty_dirs(ops, dir_path, emptied, args.prune_all_empty, args)
+    }
+}
+
+/// A candidate handled by [`process_candidate`], possibly on another thread
+struct Processed {
+    path: PathBuf,
+    /// What happened to the entry, `None` for a directory that was only reported
+    outcome: Option<Outcome>,
+    /// Size of the stub
+    len: u64,
+    /// Target of the stub, kept for `--hardlink-duplicates`
+    target: Option<OsString>,
+    /// Lines printed while converting, when done on another thread
+    lines: Vec<output::Captured>,
+}
+
+/// [`process_candidate`] on another thread, keeping the lines it prints to replay them in walk order
+fn process_captured(converter: &Converter, candidate: Candidate, args: &Args) -> Processed {
+    let (processed, lines) = output::capture(|| process_candidate(converter, candidate, args));
+    Processed { lines, ..processed }
+}
+
+/// `candidates` grouped by the dir holding them, each with its place in
+/// `candidates`, for `--parallel-dirs-only`
+fn by_parent(candidates: Vec<Candidate>) -> Vec<Vec<(usize, Candidate)>> {
+    let mut groups: Vec<Vec<(usize, Candidate)>> = Vec::new();
+    let mut group_of = HashMap::new();
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        let parent = candidate.path.parent().map(Path::to_owned);
+        let group = *group_of.entry(parent).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push((index, candidate));
+    }
+    groups
+}
+
+fn process_candidate(converter: &Converter, candidate: Candidate, args: &Args) -> Processed {
+    let path = candidate.path.clone();
+    if let Classification::Directory { entries } = candidate.classification {
+        match entries {
+            Some(entries) => report!("Entering {} ({} entries)", output::shown(&path), entries),
+            None => report!("Entering {}", output::shown(&path)),
+        }
+        return Processed {
+            path,
+            outcome: None,
+            len: 0,
+            target: None,
+            lines: Vec::new(),
+        };
+    }
+    let target = match &candidate.classification {
+        Classification::Convertible { target } if args.hardlink_duplicates => Some(target.clone()),
+        _ => None,
+    };
+    let len = candidate.metadata.as_ref().map_or(0, Metadata::len);
+    Processed {
+        path,
+        outcome: Some(convert_candidate(converter, candidate, args)),
+        len,
+        target,
+        lines: Vec::new(),
+    }
+}
+
+/// Extended attributes of the file at `path` for `--preserve`, all or only `user.*` ones
+#[cfg(unix)]
+fn preserved_xattrs(path: &Path, all: bool) -> io::Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path)? {
+        if !all && !name.as_encoded_bytes().starts_with(b"user.") {
+            continue;
+        }
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// No extended attributes to keep where `--preserve` is not supported
+#[cfg(not(unix))]
+fn preserved_xattrs(_path: &Path, _all: bool) -> io::Result<Vec<(OsString, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// The files and dirs to work on, given to the subcommand or else to the program
+fn roots(args: &Args) -> &[PathBuf] {
+    match &args.command {
+        Some(Command::Doctor { path } | Command::DumpManifest { path }) => slice::from_ref(path),
+        Some(Command::ApplyManifest { dir, .. }) => slice::from_ref(dir),
+        Some(Command::Undo { .. } | Command::Completions { .. } | Command::Man) | None => {
+            &args.paths
+        }
+    }
+}
+
+/// The only path given to the program, for modes that cannot take several
+fn single_path(args: &Args) -> Option<&Path> {
+    match args.paths.as_slice() {
+        [path] => Some(path),
+        _ => None,
+    }
+}
+
+/// Threads to convert with, see `--jobs`
+fn jobs(args: &Args) -> usize {
+    if args.interactive {
+        return 1;
+    }
+    args.jobs
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+}
+
+/// Convert every file listed in `list`, or in stdin if it is `-`
+///
+/// Listed paths are checked like a single file given as PATH; symlinks are
+/// not followed.
+fn convert_listed(
+    converter: &Converter,
+    list: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    let content = if list == Path::new("-") {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content).map(|_| content)
+    } else {
+        fs::read(list)
+    };
+    let content = match content {
+        Ok(content) => content,
+        Err(error) => {
+            print_io_error(list, &error.to_string());
+            summary.add(list, Outcome::Failed);
+            return;
+        }
+    };
+
+    let separator = if args.null { b'\0' } else { b'\n' };
+    for path in content.split(|&byte| byte == separator) {
+        if path.is_empty() {
+            continue;
+        }
+        let path = platform::os_str(path);
+        let path = Path::new(&path);
+        let outcome = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let outcome = convert_candidate(converter, inspect(path, options), args);
+                if outcome == Outcome::Converted {
+                    summary.converted_bytes += metadata.len();
+                }
+                outcome
+            }
+            Ok(metadata) if metadata.is_symlink() => Outcome::Skipped(SkipReason::Symlink),
+            Ok(_) => {
+                print_error(path, "Not a file");
+                Outcome::Failed
+            }
+            Err(error) => {
+                print_io_error(path, &error.to_string());
+                Outcome::Failed
+            }
+        };
+        summary.add(path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+/// Convert the stub at `path` or the stubs in the dir at `path`
+fn convert_path(
+    converter: &Converter,
+    path: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    deadline: Option<Instant>,
+    summary: &mut Summary,
+) {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            if metadata.is_dir() {
+                convert_dir(converter, path, options, args, deadline, summary)
+            } else if metadata.is_file() {
+                let outcome = convert_candidate(converter, inspect(path, options), args);
+                if outcome == Outcome::Converted {
+                    summary.converted_bytes += metadata.len();
+                }
+                summary.add(path, outcome)
+            } else {
+                print_error(path, "Not a directory or file");
+                summary.add(path, Outcome::Failed)
+            }
+        }
+        Err(error) => {
+            print_io_error(path, &error.to_string());
+            summary.add(path, Outcome::Failed)
+        }
+    }
+}
+
+/// Report candidates whose names differ only by case and return those to leave alone
+///
+/// `met` holds the lowercased paths of the stubs passed before, and gets
+/// those of `candidates` added. A stub colliding with one passed before is
+/// always left alone, as that one was handled already.
+fn skip_collisions(
+    candidates: &[Candidate],
+    met: &mut HashMap<String, PathBuf>,
+    args: &Args,
+    summary: &mut Summary,
+) -> HashSet<PathBuf> {
+    let mut colliding = HashSet::new();
+    let stubs = candidates
+        .iter()
+        .filter(|candidate| matches!(candidate.classification, Classification::Convertible { .. }));
+    for candidate in stubs.clone() {
+        if let Some(earlier) = met.get(&candidate.path.to_string_lossy().to_lowercase()) {
+            skip_colliding(&candidate.path, &[earlier], summary);
+            colliding.insert(candidate.path.clone());
+        }
+    }
+    for group in find_collisions(candidates) {
+        if colliding.contains(&group[0]) {
+            continue;
+        }
+        let kept = match args.on_collision {
+            OnCollision::Skip => 0,
+            OnCollision::First => 1,
+        };
+        for path in &group[kept..] {
+            let others: Vec<_> = group.iter().filter(|other| *other != path).collect();
+            skip_colliding(path, &others, summary);
+            colliding.insert(path.clone());
+        }
+    }
+    for candidate in stubs {
+        met.entry(candidate.path.to_string_lossy().to_lowercase())
+            .or_insert_with(|| candidate.path.clone());
+    }
+    colliding
+}
+
+/// Report that `path` is left alone for differing only by case from `others`
+fn skip_colliding(path: &Path, others: &[&PathBuf], summary: &mut Summary) {
+    let others: Vec<_> = others
+        .iter()
+        .map(|other| format!("'{}'", output::shown(other)))
+        .collect();
+    print_error(
+        path,
+        &format!("name differs only by case from {}", others.join(", ")),
+    );
+    summary.add(path, Outcome::Skipped(SkipReason::Collision));
+}
+
+/// Print groups of stubs below `paths` that point to the same target
+fn report_duplicates(paths: &[PathBuf], options: &ScanOptions) {
+    let candidates: Vec<Candidate> = paths.iter().flat_map(|path| scan(path, options)).collect();
+    for (target, stubs) in find_duplicates(&candidates) {
+        output::message(&format!(
+            "{} stubs point to {}:",
+            stubs.len(),
+            output::text(target)
+        ));
+        for stub in stubs {
+            output::message(&format!("  {}", output::shown(&stub)));
+        }
+    }
+}
+
+/// List the stubs below `path` that a run would convert, returning how many there are
+fn check(path: &Path, options: &ScanOptions, args: &Args) -> usize {
+    let mut stubs = 0;
+    for candidate in scan(path, options) {
+        match candidate.classification {
+            Classification::Convertible { target } => {
+                stubs += 1;
+                if !args.silent {
+                    output::message(&format!(
+                        "Stub left: {} -> {}",
+                        output::shown(&candidate.path),
+                        output::text(target)
+                    ))
+                }
+            }
+            Classification::Error(error) => print_io_error(&candidate.path, &error.to_string()),
+            _ => {}
+        }
+    }
+    output::message(&format!("Found {stubs} stubs"));
+    stubs
+}
+
+/// Take an exclusive advisory lock on the file at `path`, held until the file is dropped
+fn lock(path: &Path) -> Result<fs::File, String> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|error| error.to_string())?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err("another run holds the lock".to_owned()),
+        Err(fs::TryLockError::Error(error)) => Err(error.to_string()),
+    }
+}
+
+/// Write the conversions a run would make to the plan file `plan`
+fn dump_plan(plan: &Path, options: &ScanOptions, args: &Args) {
+    let candidates: Vec<Candidate> = args
+        .paths
+        .iter()
+        .flat_map(|path| scan(path, options))
+        .collect();
+    let colliding = skip_collisions(
+        &candidates,
+        &mut HashMap::new(),
+        args,
+        &mut Summary::default(),
+    );
+    let planned = candidates
+        .iter()
+        .filter(|candidate| !colliding.contains(&candidate.path));
+    match plan::dump(plan, planned) {
+        Ok(planned) => output::message(&format!(
+            "Planned {} conversions in {}",
+            planned,
+            output::text(plan)
+        )),
+        Err(error) => fail(&format!(
+            "Cannot write plan '{}': {}",
+            plan.to_string_lossy(),
+            error
+        )),
+    }
+}
+
+/// Update the progress line, and print the totals so far if `outcome`
+/// completed another `--batch-size` conversions
+fn report_batch(summary: &Summary, outcome: Outcome, args: &Args) {
+    output::progress(|| {
+        format!(
+            "Checked {}: {} converted, {} skipped, {} failed",
+            summary.converted
+                + summary.deleted
+                + summary.restored
+                + summary.skipped()
+                + summary.failed,
+            summary.converted,
+            summary.skipped(),
+            summary.failed
+        )
+    });
+    let Some(batch_size) = args.batch_size else {
+        return;
+    };
+    if outcome == Outcome::Converted && summary.converted % batch_size == 0 {
+        output::message(&format!(
+            "Batch {}: {} converted, {} skipped, {} failed so far",
+            summary.converted / batch_size,
+            summary.converted,
+            summary.skipped(),
+            summary.failed
+        ))
+    }
+}
+
+/// Whether to stop now, as something failed with `--strict` or a prompt was answered with quit
+fn stop_early(summary: &Summary, args: &Args) -> bool {
+    (args.strict && summary.failed > 0) || prompt::quit_requested()
+}
+
+/// Print the totals, with what `--summary` adds if `elapsed` is given
+fn print_summary(summary: &Summary, elapsed: Option<Duration>, throughput: bool) {
+    let mut totals = format!(
+        "Converted {}, deleted {}, skipped {}, failed {}",
+        summary.converted,
+        summary.deleted,
+        summary.skipped(),
+        summary.failed
+    );
+    if summary.restored > 0 {
+        totals.push_str(&format!(", restored {}", summary.restored));
+    }
+    output::message(&totals);
+    let reasons: Vec<_> = summary
+        .skipped_by_reason()
+        .map(|(reason, count)| format!("{reason} {count}"))
+        .collect();
+    if !reasons.is_empty() {
+        output::message(&format!("Skipped: {}", reasons.join(", ")))
+    }
+    let Some(elapsed) = elapsed else {
+        return;
+    };
+    output::message(&format!(
+        "Reclaimed {} bytes in {:.3}s",
+        summary.converted_bytes,
+        elapsed.as_secs_f64()
+    ));
+    if throughput {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let entries = summary.converted
+            + summary.deleted
+            + summary.restored
+            + summary.skipped()
+            + summary.failed;
+        output::message(&format!(
+            "Throughput: {:.1} files/s, {:.1} bytes/s over {:.3}s",
+            entries as f64 / seconds,
+            summary.converted_bytes as f64 / seconds,
+            elapsed.as_secs_f64()
+        ))
+    }
+}
+
+fn main() {
+    let args = config::parse();
+    match &args.command {
+        Some(Command::Completions { shell }) => {
+            let mut command = Args::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(*shell, &mut command, name, &mut io::stdout());
+            return;
+        }
+        Some(Command::Man) => {
+            if let Err(error) = clap_mangen::Man::new(Args::command()).render(&mut io::stdout()) {
+                fail(&format!("Cannot write man page: {error}"))
+            }
+            return;
+        }
+        _ => {}
+    }
+    let started = Instant::now();
+    let deadline = args.max_runtime.map(|budget| started + budget);
+    if args.output_encoding == OutputEncoding::Escape {
+        output::escape_bytes()
+    }
+    if let Some(option) = unsupported_option(&args) {
+        fail(&format!("{option} is not supported on this platform"))
+    }
+    let options = match scan_options(&args) {
+        Ok(options) => options,
+        Err(error) => fail(&error.to_string()),
+    };
+    if let Err(error) = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs(&args))
+        .build_global()
+    {
+        fail(&format!("Cannot start {} threads: {}", jobs(&args), error))
+    }
+    let _lock = match &args.lock_file {
+        Some(path) => match lock(path) {
+            Ok(file) => Some(file),
+            Err(reason) => {
+                eprintln!("Cannot lock '{}': {}", path.to_string_lossy(), reason);
+                process::exit(EXIT_LOCKED)
+            }
+        },
+        None => None,
+    };
+    output::log_level(args.log_level);
+    #[cfg(unix)]
+    if args.syslog {
+        output::open_syslog()
+    }
+    if let Some(log_file) = &args.log_file {
+        if let Err(error) = output::open_log(log_file) {
+            fail(&format!(
+                "Cannot open log file '{}': {}",
+                log_file.to_string_lossy(),
+                error
+            ))
+        }
+    }
+    if let Some(path) = &args.journal {
+        if let Err(error) = journal::open(path) {
+            fail(&format!(
+                "Cannot open journal '{}': {}",
+                path.to_string_lossy(),
+                error
+            ))
+        }
+    }
+    if let Some(path) = &args.checksum_manifest {
+        if let Err(error) = manifest::open(path) {
+            fail(&format!(
+                "Cannot open checksum manifest '{}': {}",
+                path.to_string_lossy(),
+                error
+            ))
+        }
+    }
+    if let Some(path) = &args.converted_list {
+        let separator = if args.converted_list_null {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        if let Err(error) = converted_list::open(path, separator) {
+            fail(&format!(
+                "Cannot create converted list '{}': {}",
+                path.to_string_lossy(),
+                error
+            ))
+        }
+    }
+
+    if args.verbose >= 2 {
+        output::message("Effective options:");
+        for line in effective_options(&args, &options) {
+            output::message(&format!("  {line}"))
+        }
+    }
+
+    if let Some(dir) = &args.report_relative_to {
+        let root = &roots(&args)[0];
+        let dir = match dir {
+            Some(dir) => dir,
+            None if root.is_dir() => root,
+            None => root.parent().unwrap_or(Path::new(".")),
+        };
+        output::report_relative_to(dir)
+    }
+    if let Some(backup_dir) = &args.backup_dir {
+        if roots(&args)
+            .iter()
+            .any(|root| backup::is_inside(backup_dir, root))
+        {
+            fail("--backup-dir must be outside of the PATHs converted")
+        }
+    }
+    let path = single_path(&args);
+    if path.is_none() && (args.from_tar.is_some() || args.set_target.is_some()) {
+        fail("--from-tar and --set-target take a single PATH")
+    }
+
+    if let Some(Command::Doctor { path }) = &args.command {
+        if doctor::diagnose(path, &options, &args) {
+            process::exit(EXIT_PROBLEMS_FOUND)
+        }
+        return;
+    }
+
+    if let Some(Command::DumpManifest { path }) = &args.command {
+        if let Err(error) = listing::dump(path, &options) {
+            fail(&format!("Cannot write manifest: {error}"))
+        }
+        return;
+    }
+
+    if args.report_duplicates {
+        report_duplicates(&args.paths, &options);
+        return;
+    }
+    if args.check {
+        let stubs: usize = args
+            .paths
+            .iter()
+            .map(|path| check(path, &options, &args))
+            .sum();
+        if stubs > 0 {
+            process::exit(EXIT_STUBS_FOUND)
+        }
+        return;
+    }
+    if let Some(plan) = &args.dump_plan {
+        dump_plan(plan, &options, &args);
+        return;
+    }
+
+    if args.quiet_if_noop {
+        output::hold_back()
+    }
+    if args.report_io_errors_only {
+        output::io_errors_only()
+    }
+    if args.dry_run {
+        output::mark_dry_run()
+    }
+    let ops: Arc<dyn FsOps> = if args.dry_run {
+        Arc::new(DryRun)
+    } else {
+        Arc::new(RealFs)
+    };
+    let converter = converter(&args, &options, ops);
+    let ops = converter.ops();
+    if !args.no_progress && !args.interactive && io::stderr().is_terminal() {
+        output::show_progress()
+    }
+    let summary = match args.format {
+        Format::Text => Summary::default(),
+        Format::Tree => {
+            output::hide_entry_lines();
+            Summary::recording()
+        }
+        Format::Json => {
+            output::json_lines();
+            Summary::default()
+        }
+    };
+    let mut summary = summary.with_observer(log_outcome);
+    if let Some(Command::Undo { journal }) = &args.command {
+        journal::undo(journal, ops, &args, &mut summary)
+    } else if let Some(Command::ApplyManifest { manifest, dir }) = &args.command {
+        listing::apply(manifest, dir, &converter, &args, &mut summary)
+    } else if let Some(list) = &args.files_from {
+        convert_listed(&converter, list, &options, &args, &mut summary)
+    } else if let Some(archive) = &args.from_tar {
+        let dest_dir = path.unwrap_or(Path::new("."));
+        from_tar::restore_from_tar(&converter, archive, dest_dir, &options, &args, &mut summary)
+    } else if let Some(plan) = &args.apply_plan {
+        plan::apply(plan, &converter, &args, &mut summary)
+    } else if args.reverse {
+        for path in &args.paths {
+            if stop_early(&summary, &args) {
+                break;
+            }
+            reverse::materialize(ops, path, &options, &args, &mut summary)
+        }
+    } else if args.dedup {
+        dedup::dedup(&converter, &args.paths, &options, &args, &mut summary)
+    } else if args.only_broken_symlinks {
+        for path in &args.paths {
+            if stop_early(&summary, &args) {
+                break;
+            }
+            repair::repair_broken(ops, path, &options, &args, &mut summary)
+        }
+    } else if let (Some(target), Some(path)) = (&args.set_target, path) {
+        let outcome = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                convert_file(&converter, path, target, &metadata, &args)
+            }
+            Ok(_) => {
+                print_error(path, "Not a file");
+                Outcome::Failed
+            }
+            Err(error) => {
+                print_io_error(path, &error.to_string());
+                Outcome::Failed
+            }
+        };
+        summary.add(path, outcome)
+    } else {
+        for path in &args.paths {
+            if stop_early(&summary, &args) {
+                break;
+            }
+            convert_path(&converter, path, &options, &args, deadline, &mut summary)
+        }
+        #[cfg(target_os = "linux")]
+        if args.watch && !stop_early(&summary, &args) {
+            watch::watch(
+                &converter,
+                &args.paths,
+                &options,
+                &args,
+                deadline,
+                &mut summary,
+            )
+        }
+    }
+
+    output::end_progress();
+    if args.format == Format::Tree {
+        for root in &args.paths {
+            let outcomes: Vec<_> = summary
+                .outcomes()
+                .iter()
+                .filter(|(path, _)| path.starts_with(root))
+                .cloned()
+                .collect();
+            for line in tree::render(root, &outcomes) {
+                output::message(&line)
+            }
+        }
+    }
+    match args.summary_format {
+        _ if args.format == Format::Json => {
+            let totals = summary.to_json(started.elapsed());
+            output::event(&format!("{{\"event\":\"summary\",{}", &totals[1..]))
+        }
+        _ if args.no_summary => {}
+        SummaryFormat::Text if args.summary || args.verbose > 0 || args.dry_run => print_summary(
+            &summary,
+            (args.summary || args.report_throughput).then(|| started.elapsed()),
+            args.report_throughput,
+        ),
+        SummaryFormat::Text => {}
+        SummaryFormat::Json => output::message(&summary.to_json(started.elapsed())),
+    }
+    if args.strict && summary.failed > 0 {
+        output::error("Stopped at the first failure, as asked by --strict")
+    }
+    if summary.stopped_early {
+        output::message("Stopped after running out of time, unprocessed: unknown")
+    }
+    let too_many_skips = match args.max_skips {
+        Some(max) if summary.skipped() > max => {
+            output::message(&format!(
+                "Skipped {} entries, more than the {} allowed",
+                summary.skipped(),
+                max
+            ));
+            true
+        }
+        _ => false,
+    };
+    if args.quiet_if_noop {
+        output::release(
+            summary.converted + summary.deleted + summary.restored + summary.failed == 0
+                && !summary.stopped_early
+                && !too_many_skips,
+        )
+    }
+    if summary.stopped_early {
+        process::exit(EXIT_INCOMPLETE)
+    }
+    if too_many_skips {
+        process::exit(EXIT_TOO_MANY_SKIPS)
+    }
+    if summary.failed > 0 {
+        process::exit(EXIT_FAILED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+    use crate::test_fs::{Op, TestFs};
+
+    /// Paths of the files `args` would scan below `root`, relative to it
+    fn scanned(root: &Path, args: &[&str]) -> Vec<PathBuf> {
+        let args = Args::parse_from(
+            ["restore_symlink"]
+                .iter()
+                .chain(args)
+                .map(OsStr::new)
+                .chain([root.as_os_str()]),
+        );
+        let options = scan_options(&args).unwrap();
+        let mut paths: Vec<PathBuf> = scan(root, &options)
+            .filter(|candidate| {
+                !matches!(
+                    candidate.classification,
+                    Classification::Directory { .. } | Classification::Excluded
+                )
+            })
+            .map(|candidate| candidate.path.strip_prefix(root).unwrap().to_owned())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn refuses_a_zero_length_limit() {
+        let error = Args::try_parse_from(["restore_symlink", "--len", "0", "."]).unwrap_err();
+        assert!(
+            error.to_string().contains("no file would be considered"),
+            "{error}"
+        );
+
+        let args = Args::parse_from(["restore_symlink", "--len", "1", "."]);
+        assert_eq!(scan_options(&args).unwrap().len, 1);
+    }
+
+    #[test]
+    fn refuses_an_invalid_rewrite_regex() {
+        let error =
+            Args::try_parse_from(["restore_symlink", "--rewrite-regex", "(unclosed=x", "."])
+                .unwrap_err();
+        assert!(error.to_string().contains("unclosed group"), "{error}");
+
+        let args = Args::parse_from(["restore_symlink", "--rewrite-regex", "(a)=$1", "."]);
+        assert_eq!(args.rewrite_regex.len(), 1);
+    }
+
+    #[test]
+    fn matches_globs_ignoring_case_on_request() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("Docs")).unwrap();
+        for name in ["file.txt", "other.md", "Docs/notes.txt"] {
+            fs::write(tmp.path().join(name), "target").unwrap();
+        }
+
+        let all = [
+            PathBuf::from("Docs/notes.txt"),
+            PathBuf::from("file.txt"),
+            PathBuf::from("other.md"),
+        ];
+        assert_eq!(scanned(tmp.path(), &["-r", "--exclude", "*.TXT"]), all);
+        assert_eq!(
+            scanned(
+                tmp.path(),
+                &["-r", "--exclude", "*.TXT", "--glob-ignore-case"]
+            ),
+            [PathBuf::from("other.md")]
+        );
+        assert_eq!(
+            scanned(
+                tmp.path(),
+                &["-r", "--include", "*.TXT", "--glob-ignore-case"]
+            ),
+            [PathBuf::from("Docs/notes.txt"), PathBuf::from("file.txt")]
+        );
+        assert_eq!(
+            scanned(
+                tmp.path(),
+                &["-r", "--exclude", "docs", "--glob-ignore-case"]
+            ),
+            [PathBuf::from("file.txt"), PathBuf::from("other.md")]
+        );
+    }
+
+    /// A tree below `root` of stubs to convert, stubs to a missing target and
+    /// files that are no stubs, spread over a few dirs
+    fn write_mixed_tree(root: &Path) {
+        let target = root.join("target");
+        fs::create_dir(root).unwrap();
+        fs::write(&target, "content").unwrap();
+        for i in 0..3 * PARALLEL_CHUNK {
+            let dir = root.join(format!("dir{}", i % 4));
+            fs::create_dir_all(&dir).unwrap();
+            let content = match i % 3 {
+                0 => target.as_os_str().as_encoded_bytes().to_owned(),
+                1 => root
+                    .join("missing")
+                    .as_os_str()
+                    .as_encoded_bytes()
+                    .to_owned(),
+                _ => b"first line\nsecond line".to_vec(),
+            };
+            fs::write(dir.join(format!("file{i}")), content).unwrap();
+        }
+    }
+
+    fn convert_tree(root: &Path, jobs: &str) -> Summary {
+        let args = Args::parse_from([
+            OsStr::new("restore_symlink"),
+            OsStr::new("-r"),
+            OsStr::new("--jobs"),
+            OsStr::new(jobs),
+            root.as_os_str(),
+        ]);
+        let options = scan_options(&args).unwrap();
+        let mut summary = Summary::default();
+        convert_dir(
+            &converter(&args, &options, Arc::new(RealFs)),
+            root,
+            &options,
+            &args,
+            None,
+            &mut summary,
+        );
+        summary
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn links_to_targets_that_are_not_utf8_byte_for_byte() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9-\xff\x80");
+        fs::create_dir(tmp.path().join(name)).unwrap();
+        fs::write(tmp.path().join(name).join("target"), "content").unwrap();
+        let stub = tmp.path().join("stub");
+        let mut content = name.as_bytes().to_vec();
+        content.extend_from_slice(b"/target\n");
+        fs::write(&stub, &content).unwrap();
+
+        let summary = convert_tree(tmp.path(), "1");
+
+        assert_eq!(summary.converted, 1);
+        assert_eq!(
+            fs::read_link(&stub).unwrap().as_os_str().as_bytes(),
+            &content[..content.len() - 1]
+        );
+    }
+
+    #[test]
+    fn parallel_totals_match_single_threaded_ones() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Same length roots, for stubs of the same size
+        let (serial_root, parallel_root) = (tmp.path().join("jobs1"), tmp.path().join("jobs4"));
+        write_mixed_tree(&serial_root);
+        write_mixed_tree(&parallel_root);
+
+        let serial = convert_tree(&serial_root, "1");
+        let parallel = convert_tree(&parallel_root, "4");
+
+        assert_eq!(serial.converted, PARALLEL_CHUNK);
+        assert_eq!(
+            (
+                parallel.converted,
+                parallel.deleted,
+                parallel.failed,
+                parallel.stopped_early
+            ),
+            (
+                serial.converted,
+                serial.deleted,
+                serial.failed,
+                serial.stopped_early
+            )
+        );
+        assert_eq!(parallel.converted_bytes, serial.converted_bytes);
+        assert_eq!(
+            parallel.skipped_by_reason().collect::<Vec<_>>(),
+            serial.skipped_by_reason().collect::<Vec<_>>()
+        );
+    }
+
+    /// Convert the stub at `stub` into a link to `target` with the options `args`
+    fn convert_stub(ops: Arc<dyn FsOps>, stub: &Path, target: &str, args: &[&str]) -> Outcome {
+        let args = Args::parse_from(
+            ["restore_symlink"]
+                .iter()
+                .chain(args)
+                .map(OsStr::new)
+                .chain([stub.as_os_str()]),
+        );
+        let options = scan_options(&args).unwrap();
+        let metadata = fs::symlink_metadata(stub).unwrap();
+        convert_file(
+            &converter(&args, &options, ops),
+            stub,
+            OsStr::new(target),
+            &metadata,
+            &args,
+        )
+    }
+
+    /// Check that `--preserve` puts the xattr `name` of a stub on its link,
+    /// unless the filesystem or our privileges do not allow setting it
+    #[cfg(unix)]
+    fn check_preserved_xattr(name: &str, args: &[&str]) {
+        let tmp = tempfile::tempdir().unwrap();
+        let stub = tmp.path().join("stub");
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        fs::write(&stub, "target").unwrap();
+        if xattr::set(&stub, name, b"value").is_err() {
+            return;
+        }
+
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &stub, "target", args),
+            Outcome::Converted
+        );
+
+        assert!(fs::symlink_metadata(&stub).unwrap().is_symlink());
+        assert_eq!(
+            xattr::get(&stub, name).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+    }
+
+    /// Linux only allows user.* attributes on files and dirs, not symlinks
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn preserves_xattrs_on_links() {
+        check_preserved_xattr("trusted.restore-symlink", &["--preserve", "--all-xattrs"]);
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    #[test]
+    fn preserves_xattrs_on_links() {
+        check_preserved_xattr("user.restore-symlink", &["--preserve"]);
+    }
+
+    /// A stub in a fresh dir next to the file it names, `target`
+    fn stub_dir() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let stub = tmp.path().join("stub");
+        fs::write(tmp.path().join("target"), "content").unwrap();
+        fs::write(&stub, "target").unwrap();
+        (tmp, stub)
+    }
+
+    /// Names in `dir`, sorted
+    fn names(dir: &Path) -> Vec<OsString> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn keeps_stubs_changed_since_the_scan_with_no_clobber() {
+        let (_tmp, stub) = stub_dir();
+        let args = Args::parse_from([
+            OsStr::new("restore_symlink"),
+            OsStr::new("--no-clobber"),
+            stub.as_os_str(),
+        ]);
+        let options = scan_options(&args).unwrap();
+        let converter = converter(&args, &options, Arc::new(RealFs));
+        let scanned = fs::symlink_metadata(&stub).unwrap();
+        fs::write(&stub, "real content").unwrap();
+
+        assert_eq!(
+            convert_file(&converter, &stub, OsStr::new("target"), &scanned, &args),
+            Outcome::Skipped(SkipReason::Stale)
+        );
+        assert_eq!(fs::read(&stub).unwrap(), b"real content");
+
+        fs::write(&stub, "target").unwrap();
+        let scanned = fs::symlink_metadata(&stub).unwrap();
+        assert_eq!(
+            convert_file(&converter, &stub, OsStr::new("target"), &scanned, &args),
+            Outcome::Converted
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn hardlinks_stubs_to_their_target() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (tmp, stub) = stub_dir();
+
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &stub, "target", &["--hardlink"]),
+            Outcome::Converted
+        );
+        let target = fs::symlink_metadata(tmp.path().join("target")).unwrap();
+        let link = fs::symlink_metadata(&stub).unwrap();
+        assert!(link.is_file());
+        assert_eq!((link.ino(), link.nlink()), (target.ino(), 2));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn refuses_hardlinks_across_filesystems() {
+        let (_tmp, stub) = stub_dir();
+        // procfs is never the filesystem of a temporary dir
+        let elsewhere = fs::metadata("/proc/self/stat").unwrap();
+
+        assert_eq!(
+            hardlink_target(&stub, OsStr::new("target"), &elsewhere),
+            Err("cannot hardlink to 'target', it is on another filesystem".to_owned())
+        );
+    }
+
+    #[test]
+    fn skips_targets_made_relative_that_climb_too_far() {
+        let (tmp, stub) = stub_dir();
+        let deep = (0..4).fold(tmp.path().to_owned(), |dir, _| dir.join("d"));
+        fs::create_dir_all(&deep).unwrap();
+        let target = tmp.path().join("target");
+        let target = target.to_str().unwrap();
+        let deep_stub = deep.join("stub");
+        fs::write(&deep_stub, target).unwrap();
+        let root = tmp.path().to_str().unwrap();
+        let args = ["--relativize", root, "--max-relative-depth", "3"];
+
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &deep_stub, target, &args),
+            Outcome::Skipped(SkipReason::RelativeTooDeep)
+        );
+        assert!(fs::symlink_metadata(&deep_stub).unwrap().is_file());
+        fs::write(&stub, target).unwrap();
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &stub, target, &args),
+            Outcome::Converted
+        );
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn keeps_the_stub_when_the_link_cannot_be_created() {
+        let (tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Symlink,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+
+        assert_eq!(
+            convert_stub(ops.clone(), &stub, "target", &[]),
+            Outcome::Failed
+        );
+
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+        assert_eq!(names(tmp.path()), ["stub", "target"]);
+    }
+
+    #[test]
+    fn records_checksums_only_of_stubs_replaced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manifest_path = tmp.path().join("manifest");
+        manifest::open(&manifest_path).unwrap();
+        let manifest_arg = manifest_path.to_str().unwrap();
+        let args = ["--checksum-manifest", manifest_arg];
+
+        let (_kept_dir, kept) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Rename,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+        assert_eq!(convert_stub(ops, &kept, "target", &args), Outcome::Failed);
+        let (_converted_dir, converted) = stub_dir();
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &converted, "target", &args),
+            Outcome::Converted
+        );
+
+        // Other tests convert while the manifest is open, so only look for these two
+        let written = fs::read(&manifest_path).unwrap();
+        // SHA-256 of "target", as given by `sha256sum`
+        let entry = format!(
+            "{}\t34a04005bcaf206eec990bd9637d9fdb6725e0a0c0d4aebf003f17f4c956eb5c\ttarget\n",
+            converted.display()
+        );
+        assert!(contains(&written, entry.as_bytes()));
+        assert!(!contains(&written, kept.as_os_str().as_encoded_bytes()));
+    }
+
+    #[test]
+    fn journals_only_stubs_replaced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let journal_path = tmp.path().join("journal");
+        journal::open(&journal_path).unwrap();
+
+        let (_kept_dir, kept) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Rename,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+        assert_eq!(convert_stub(ops, &kept, "target", &[]), Outcome::Failed);
+        let (_converted_dir, converted) = stub_dir();
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &converted, "target", &[]),
+            Outcome::Converted
+        );
+
+        let written = fs::read(&journal_path).unwrap();
+        assert!(contains(&written, converted.as_os_str().as_encoded_bytes()));
+        assert!(!contains(&written, kept.as_os_str().as_encoded_bytes()));
+    }
+
+    #[test]
+    fn backs_up_only_stubs_replaced() {
+        let backups = tempfile::tempdir().unwrap();
+        let args = ["--backup-dir", backups.path().to_str().unwrap()];
+
+        let (_tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Rename,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+        assert_eq!(convert_stub(ops, &stub, "target", &args), Outcome::Failed);
+        assert!(names(backups.path()).is_empty());
+
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &stub, "target", &args),
+            Outcome::Converted
+        );
+        assert_eq!(fs::read(backups.path().join("stub")).unwrap(), b"target");
+    }
+
+    #[test]
+    fn warns_about_modes_other_than_the_umask_default() {
+        let path = Path::new("stub");
+
+        assert!(mode_warning(path, 0o600, 0o644).is_some_and(|warning| warning.contains("600")));
+        assert_eq!(mode_warning(path, 0o644, 0o644), None);
+        assert!(mode_warning(path, 0o644, 0o664).is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_files_get_the_umask_default() {
+        let (_tmp, stub) = stub_dir();
+
+        let metadata = fs::metadata(stub).unwrap();
+        assert_eq!(platform::mode(&metadata), platform::new_file_mode());
+    }
+
+    #[test]
+    fn verifies_links_after_creating_them() {
+        let (_tmp, stub) = stub_dir();
+
+        assert_eq!(
+            convert_stub(Arc::new(RealFs), &stub, "target", &["--verify-after"]),
+            Outcome::Converted
+        );
+
+        assert_eq!(verify_symlink(&RealFs, &stub, OsStr::new("target")), Ok(()));
+        assert!(verify_symlink(&RealFs, &stub, OsStr::new("other"))
+            .is_err_and(|reason| reason.contains("points to 'target'")));
+    }
+
+    #[test]
+    fn tells_when_the_parent_dir_is_not_writable() {
+        let (_tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Symlink,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+        let args = Args::parse_from(["restore_symlink", "."]);
+        let options = scan_options(&args).unwrap();
+        let metadata = fs::symlink_metadata(&stub).unwrap();
+
+        let replaced = converter(&args, &options, ops).replace(
+            &stub,
+            OsStr::new("target"),
+            &metadata,
+            &Replacement::Symlink,
+        );
+
+        assert!(matches!(
+            replaced,
+            Err(ReplaceError::Io(error)) if error.to_string() == "parent directory is not writable"
+        ));
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_stubs_in_read_only_dirs() {
+        let (tmp, stub) = stub_dir();
+        platform::set_mode(tmp.path(), 0o555).unwrap();
+        // Permissions do not hold back root
+        let writable = fs::write(tmp.path().join("probe"), "").is_ok();
+
+        let outcome = convert_stub(Arc::new(RealFs), &stub, "target", &[]);
+
+        platform::set_mode(tmp.path(), 0o755).unwrap();
+        if !writable {
+            assert_eq!(outcome, Outcome::Failed);
+            assert_eq!(fs::read(&stub).unwrap(), b"target");
+        }
+    }
+
+    /// Whether `needle` occurs in `haystack`
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn keeps_the_stub_when_the_link_cannot_be_renamed_over_it() {
+        let (tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Rename,
+            ErrorKind::PermissionDenied,
+            usize::MAX,
+        ));
+
+        assert_eq!(
+            convert_stub(ops.clone(), &stub, "target", &[]),
+            Outcome::Failed
+        );
+
+        assert_eq!(ops.calls(), 1);
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+        assert_eq!(names(tmp.path()), ["stub", "target"]);
+    }
+
+    #[test]
+    fn gives_up_renaming_after_the_retries() {
+        let (tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(
+            Op::Rename,
+            ErrorKind::ResourceBusy,
+            usize::MAX,
+        ));
+
+        assert_eq!(
+            convert_stub(ops.clone(), &stub, "target", &["--retry", "2"]),
+            Outcome::Failed
+        );
+
+        assert_eq!(ops.calls(), 3);
+        assert_eq!(fs::read(&stub).unwrap(), b"target");
+        assert_eq!(names(tmp.path()), ["stub", "target"]);
+    }
+
+    #[test]
+    fn converts_once_a_retry_succeeds() {
+        let (_tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::failing(Op::Symlink, ErrorKind::ResourceBusy, 2));
+
+        assert_eq!(
+            convert_stub(ops.clone(), &stub, "target", &["--retry", "2"]),
+            Outcome::Converted
+        );
+
+        assert_eq!(ops.calls(), 3);
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn parallel_dirs_only_converts_the_files_of_a_dir_in_walk_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        write_mixed_tree(&root);
+        let walked: Vec<PathBuf> = scan(&root, &ScanOptions::default())
+            .filter(|candidate| {
+                matches!(candidate.classification, Classification::Convertible { .. })
+            })
+            .map(|candidate| candidate.path)
+            .collect();
+        let args = Args::parse_from([
+            OsStr::new("restore_symlink"),
+            OsStr::new("-r"),
+            OsStr::new("--jobs"),
+            OsStr::new("4"),
+            OsStr::new("--parallel-dirs-only"),
+            root.as_os_str(),
+        ]);
+        let options = scan_options(&args).unwrap();
+        let ops = Arc::new(TestFs::recording());
+        let mut summary = Summary::recording();
+
+        convert_dir(
+            &converter(&args, &options, ops.clone()),
+            &root,
+            &options,
+            &args,
+            None,
+            &mut summary,
+        );
+
+        assert_eq!(summary.converted, PARALLEL_CHUNK);
+        let renamed: Vec<PathBuf> = ops.renamed().into_iter().map(|(_, to)| to).collect();
+        for dir in (0..4).map(|i| root.join(format!("dir{i}"))) {
+            let in_dir = |paths: &[PathBuf]| -> Vec<PathBuf> {
+                paths
+                    .iter()
+                    .filter(|path| path.parent() == Some(&dir))
+                    .cloned()
+                    .collect()
+            };
+            assert_eq!(in_dir(&renamed), in_dir(&walked));
+        }
+        let reported: Vec<&PathBuf> = summary
+            .outcomes()
+            .iter()
+            .filter(|(_, outcome)| *outcome == Outcome::Converted)
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(reported, walked.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stops_at_the_deadline_without_walking_the_rest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        write_mixed_tree(&root);
+        let empty = tmp.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        let args = Args::parse_from(["restore_symlink", "-r", "--max-runtime", "1s", "tree"]);
+        let options = scan_options(&args).unwrap();
+        let converter = converter(&args, &options, Arc::new(RealFs));
+
+        let mut summary = Summary::default();
+        convert_dir(
+            &converter,
+            &empty,
+            &options,
+            &args,
+            Some(Instant::now()),
+            &mut summary,
+        );
+        assert!(!summary.stopped_early);
+
+        convert_dir(
+            &converter,
+            &root,
+            &options,
+            &args,
+            Some(Instant::now()),
+            &mut summary,
+        );
+        assert!(summary.stopped_early);
+        assert_eq!(summary.converted + summary.skipped() + summary.failed, 0);
+    }
+
+    #[test]
+    fn never_walks_into_a_stub_converted_into_a_dir_link() {
+        let tmp = tempfile::tempdir().unwrap();
+        for extra in [None, Some("--follow"), Some("--chunked-readdir")] {
+            let root = tmp
+                .path()
+                .join(extra.unwrap_or("plain").trim_start_matches('-'));
+            fs::create_dir_all(root.join("dir")).unwrap();
+            fs::write(root.join("target"), "content").unwrap();
+            fs::write(root.join("dir/stub"), "../target").unwrap();
+            // Sorts before its target, so it is converted before `dir` is walked
+            fs::write(root.join("a-link"), "dir").unwrap();
+            let mut args = vec![OsStr::new("restore_symlink"), OsStr::new("-r")];
+            args.extend(extra.map(OsStr::new));
+            args.extend(["--parallel-walk-order", "stable"].map(OsStr::new));
+            let args = Args::parse_from(args.into_iter().chain([root.as_os_str()]));
+            let options = scan_options(&args).unwrap();
+            let mut summary = Summary::recording();
+
+            convert_dir(
+                &converter(&args, &options, Arc::new(RealFs)),
+                &root,
+                &options,
+                &args,
+                None,
+                &mut summary,
+            );
+
+            let link = root.join("a-link");
+            assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+            let seen: Vec<&PathBuf> = summary
+                .outcomes()
+                .iter()
+                .map(|(path, _)| path)
+                .filter(|path| path.starts_with(&link))
+                .collect();
+            assert_eq!(seen, [&link], "{extra:?}");
+            assert_eq!(summary.converted, 2, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn skips_stubs_differing_only_by_case_with_any_jobs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let collisions = |summary: &Summary| {
+            summary
+                .skipped_by_reason()
+                .find(|(reason, _)| *reason == SkipReason::Collision)
+                .map_or(0, |(_, count)| count)
+        };
+        for jobs in ["1", "2"] {
+            let root = tmp.path().join(format!("jobs{jobs}"));
+            fs::create_dir(&root).unwrap();
+            fs::write(root.join("target"), "content").unwrap();
+            fs::write(root.join("Stub"), "target").unwrap();
+            fs::write(root.join("stub"), "target").unwrap();
+
+            let summary = convert_tree(&root, jobs);
+
+            assert_eq!(summary.converted, 0, "--jobs {jobs}");
+            assert_eq!(collisions(&summary), 2, "--jobs {jobs}");
+        }
+    }
+
+    #[test]
+    fn renames_a_link_made_next_to_the_stub_over_it() {
+        let (tmp, stub) = stub_dir();
+        let ops = Arc::new(TestFs::recording());
+
+        assert_eq!(
+            convert_stub(ops.clone(), &stub, "target", &[]),
+            Outcome::Converted
+        );
+
+        let renamed = ops.renamed();
+        assert_eq!(renamed.len(), 1);
+        let (temp, to) = &renamed[0];
+        assert_eq!(to, &stub);
+        assert_eq!(temp.parent(), stub.parent());
+        assert!(temp
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with(".stub.restore-symlink-"));
+        assert_eq!(fs::read_link(&stub).unwrap(), Path::new("target"));
+        assert_eq!(names(tmp.path()), ["stub", "target"]);
+    }
+
+    #[test]
+    fn strict_stops_taking_entries_on_every_thread_after_a_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        write_mixed_tree(&root);
+        for parallel_dirs_only in [false, true] {
+            let mut args = vec!["restore_symlink", "-r", "--strict", "--jobs", "4"];
+            args.extend(parallel_dirs_only.then_some("--parallel-dirs-only"));
+            let args = Args::parse_from(args.into_iter().map(OsStr::new).chain([root.as_os_str()]));
+            let options = scan_options(&args).unwrap();
+            let ops = Arc::new(TestFs::failing(
+                Op::Symlink,
+                ErrorKind::PermissionDenied,
+                usize::MAX,
+            ));
+            let mut summary = Summary::default();
+
+            convert_dir(
+                &converter(&args, &options, ops.clone()),
+                &root,
+                &options,
+                &args,
+                None,
+                &mut summary,
+            );
+
+            assert!(summary.failed >= 1);
+            assert!(
+                summary.failed <= rayon::current_num_threads(),
+                "{summary:?}"
+            );
         }
-        Err(error) => print_error(&args.path, &error.to_string()),
     }
 }
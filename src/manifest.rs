@@ -0,0 +1,51 @@
+//! Manifest of original file contents, written after each conversion.
+
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use sha2::{Digest, Sha256};
+
+static MANIFEST: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Append an entry for every following conversion to the file at `path`
+pub fn open(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = MANIFEST.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Record `path\tsha256\ttarget` for a file just converted
+///
+/// Both paths are written as raw bytes, so the manifest keeps targets that
+/// are not valid UTF-8 exactly.
+pub fn record(file_path: &Path, content: &[u8], link: &OsStr) -> io::Result<()> {
+    let Some(file) = MANIFEST.get() else {
+        return Ok(());
+    };
+    let mut file = file.lock().unwrap_or_else(|error| error.into_inner());
+    file.write_all(&entry(file_path, content, link))?;
+    file.flush()
+}
+
+/// One `path\tsha256\ttarget` line, as raw bytes
+pub fn entry(file_path: &Path, content: &[u8], link: &OsStr) -> Vec<u8> {
+    let mut entry = file_path.as_os_str().as_encoded_bytes().to_vec();
+    entry.push(b'\t');
+    entry.extend_from_slice(sha256_hex(content).as_bytes());
+    entry.push(b'\t');
+    entry.extend_from_slice(link.as_encoded_bytes());
+    entry.push(b'\n');
+    entry
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
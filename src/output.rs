@@ -0,0 +1,454 @@
+//! User-facing result lines, and the log of what a run did.
+//!
+//! Results go to stdout and errors to stderr, except with [`json_lines`]
+//! where every line is an object on stdout. The log gets a timestamped
+//! record per outcome, error and message, whatever is printed, filtered
+//! by [`Level`] and written to a file and/or syslog.
+
+#[cfg(unix)]
+use std::ffi::CString;
+use std::{
+    cell::RefCell,
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::ValueEnum;
+use restore_symlink::paths::{make_relative, normalize};
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Whether log records also go to syslog, see [`open_syslog`]
+#[cfg(unix)]
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+
+/// Most detailed [`Level`] logged, see [`log_level`]
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Whether per-entry result lines are printed, see [`hide_entry_lines`]
+static ENTRY_LINES: AtomicBool = AtomicBool::new(true);
+
+/// Whether result lines are marked as not really done, see [`mark_dry_run`]
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Whether invalid and control bytes are printed as escapes, see [`escape_bytes`]
+static ESCAPE_BYTES: AtomicBool = AtomicBool::new(false);
+
+/// Whether every line is printed as a JSON object, see [`json_lines`]
+static JSON_LINES: AtomicBool = AtomicBool::new(false);
+
+/// Whether only IO errors are printed, see [`io_errors_only`]
+static IO_ERRORS_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// The progress line on stderr, if shown, see [`show_progress`]
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Time between two redraws of the progress line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Directory printed paths are made relative to, see [`report_relative_to`]
+static RELATIVE_TO: OnceLock<PathBuf> = OnceLock::new();
+
+/// Lines held back from stdout, see [`hold_back`]
+static HELD_BACK: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+thread_local! {
+    /// Lines kept back on this thread, see [`capture`]
+    static CAPTURED: RefCell<Option<Vec<Captured>>> = const { RefCell::new(None) };
+}
+
+/// State of the progress line
+struct Progress {
+    /// When it was last drawn
+    drawn_at: Option<Instant>,
+    /// Whether it is on screen now, and must be erased before printing a line
+    visible: bool,
+}
+
+/// A line kept back by [`capture`]
+pub enum Captured {
+    Printed(String),
+    Failed(String),
+    Logged(Level, String),
+}
+
+/// How much a log record matters, from most to least
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    /// Failures and the errors explaining them
+    Error,
+    /// Conversions, deletions and messages such as the summary
+    Info,
+    /// Skipped entries too, with why they were skipped
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    #[cfg(unix)]
+    fn syslog_priority(self) -> libc::c_int {
+        match self {
+            Level::Error => libc::LOG_ERR,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug => libc::LOG_DEBUG,
+        }
+    }
+}
+
+/// Append every following log record to the file at `path`
+pub fn open_log(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Send every following log record to syslog too, where journald picks them up
+#[cfg(unix)]
+pub fn open_syslog() {
+    // SAFETY: the ident is a static C string, as openlog keeps the pointer
+    // for every later syslog call.
+    unsafe { libc::openlog(c"restore_symlink".as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+    SYSLOG.store(true, Ordering::Relaxed);
+}
+
+/// Log records up to `level` from now on, [`Level::Info`] by default
+pub fn log_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Log a record at `level`, with a `[dry run]` mark like result lines
+pub fn record(level: Level, text: &str) {
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log(level, &format!("[dry run] {text}"))
+    } else {
+        log(level, text)
+    }
+}
+
+/// Stop printing per-entry result lines, for formats rendering them at the end
+pub fn hide_entry_lines() {
+    ENTRY_LINES.store(false, Ordering::Relaxed);
+}
+
+/// Print JSON objects from [`event`] instead of per-entry result lines
+///
+/// Other lines are wrapped into `message` events, so every line is an object.
+pub fn json_lines() {
+    hide_entry_lines();
+    JSON_LINES.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`json_lines`] was called
+pub fn is_json() -> bool {
+    JSON_LINES.load(Ordering::Relaxed)
+}
+
+/// Print the JSON object `json` about one event, with [`json_lines`]
+pub fn event(json: &str) {
+    if is_json() && !IO_ERRORS_ONLY.load(Ordering::Relaxed) {
+        print(json);
+    }
+}
+
+/// `text` as a quoted JSON string
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Start every following per-entry result line with `[dry run]`
+pub fn mark_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Print nothing but [`io_error`] lines from now on
+///
+/// The log is left as it is.
+pub fn io_errors_only() {
+    IO_ERRORS_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// Show bytes of paths and targets that are not printable UTF-8 as `\xNN`
+/// from now on, instead of as U+FFFD, with `\` itself shown as `\\`
+pub fn escape_bytes() {
+    ESCAPE_BYTES.store(true, Ordering::Relaxed);
+}
+
+/// Print every following entry path relative to `dir`
+pub fn report_relative_to(dir: &Path) {
+    let _ = RELATIVE_TO.set(absolute(dir));
+}
+
+/// `path` the way result lines show it
+pub fn shown(path: &Path) -> String {
+    match RELATIVE_TO.get() {
+        Some(dir) => text(make_relative(dir, &absolute(path))),
+        None => text(path),
+    }
+}
+
+/// A path, target or name the way result lines show it
+pub fn text(text: impl AsRef<OsStr>) -> String {
+    let text = text.as_ref();
+    if !ESCAPE_BYTES.load(Ordering::Relaxed) {
+        return text.to_string_lossy().into_owned();
+    }
+    let mut shown = String::new();
+    for chunk in text.as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => shown.push_str("\\\\"),
+                c if c.is_control() => {
+                    for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                        shown.push_str(&format!("\\x{byte:02x}"));
+                    }
+                }
+                c => shown.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            shown.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    shown
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    normalize(&std::path::absolute(path).unwrap_or_else(|_| path.to_owned()))
+}
+
+/// Keep lines from stdout until [`release`] decides whether to print them
+pub fn hold_back() {
+    *held_back() = Some(Vec::new());
+}
+
+/// Keep a progress line on stderr from now on, redrawn by [`progress`]
+pub fn show_progress() {
+    *progress_line() = Some(Progress {
+        drawn_at: None,
+        visible: false,
+    });
+}
+
+/// Redraw the progress line with the text made by `text`, at most every
+/// [`PROGRESS_INTERVAL`] and only after [`show_progress`]
+pub fn progress(text: impl FnOnce() -> String) {
+    let mut line = progress_line();
+    let Some(progress) = line.as_mut() else {
+        return;
+    };
+    if progress
+        .drawn_at
+        .is_some_and(|drawn_at| drawn_at.elapsed() < PROGRESS_INTERVAL)
+    {
+        return;
+    }
+    progress.drawn_at = Some(Instant::now());
+    progress.visible = true;
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "\r\x1b[K{}", text());
+    let _ = stderr.flush();
+}
+
+/// Remove the progress line for good
+pub fn end_progress() {
+    if progress_line()
+        .take()
+        .is_some_and(|progress| progress.visible)
+    {
+        clear_progress()
+    }
+}
+
+fn progress_line() -> MutexGuard<'static, Option<Progress>> {
+    PROGRESS.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Erase the progress line if on screen, so a line can be printed in its place
+fn hide_progress() {
+    if let Some(progress) = progress_line().as_mut().filter(|progress| progress.visible) {
+        clear_progress();
+        progress.visible = false;
+    }
+}
+
+fn clear_progress() {
+    eprint!("\r\x1b[K");
+}
+
+/// Print the lines held back since [`hold_back`], or drop them if `discard`
+pub fn release(discard: bool) {
+    if let Some(lines) = held_back().take() {
+        if !discard {
+            for line in lines {
+                println!("{line}");
+            }
+        }
+    }
+}
+
+/// Run `f` keeping the lines it prints on this thread, for [`replay`] to
+/// print them in one piece
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<Captured>) {
+    CAPTURED.with_borrow_mut(|captured| *captured = Some(Vec::new()));
+    let result = f();
+    let lines = CAPTURED.with_borrow_mut(Option::take).unwrap_or_default();
+    (result, lines)
+}
+
+/// Print and log the lines kept back by [`capture`]
+pub fn replay(lines: Vec<Captured>) {
+    for line in lines {
+        match line {
+            Captured::Printed(text) => print(&text),
+            Captured::Failed(text) => print_error(&text),
+            Captured::Logged(level, text) => log(level, &text),
+        }
+    }
+}
+
+/// Print a per-entry result line to stdout
+///
+/// The log has its own record of the outcome, see [`record`].
+pub fn line(text: &str) {
+    let marked;
+    let text = if DRY_RUN.load(Ordering::Relaxed) {
+        marked = format!("[dry run] {text}");
+        &marked
+    } else {
+        text
+    };
+    if ENTRY_LINES.load(Ordering::Relaxed) && !IO_ERRORS_ONLY.load(Ordering::Relaxed) {
+        print(text);
+    }
+}
+
+/// Print a line that is shown whatever the format, and log it
+pub fn message(text: &str) {
+    if !IO_ERRORS_ONLY.load(Ordering::Relaxed) {
+        print(&as_message(text));
+    }
+    log(Level::Info, text);
+}
+
+/// Print a line about an entry that cannot be handled to stderr
+pub fn error(text: &str) {
+    if !IO_ERRORS_ONLY.load(Ordering::Relaxed) {
+        if is_json() {
+            print(&as_message(text));
+        } else {
+            print_error(text);
+        }
+    }
+    log(Level::Error, text);
+}
+
+/// Print a line about a failing filesystem to stderr, which is always shown
+pub fn io_error(text: &str) {
+    if is_json() {
+        print(&as_message(text));
+    } else {
+        print_error(text);
+    }
+    log(Level::Error, text);
+}
+
+/// `text` wrapped into a `message` event with [`json_lines`]
+fn as_message(text: &str) -> String {
+    if is_json() {
+        format!("{{\"event\":\"message\",\"text\":{}}}", json_string(text))
+    } else {
+        text.to_owned()
+    }
+}
+
+fn print(text: &str) {
+    if kept_back(|| Captured::Printed(text.to_owned())) {
+        return;
+    }
+    match held_back().as_mut() {
+        Some(lines) => lines.push(text.to_owned()),
+        None => {
+            hide_progress();
+            println!("{text}")
+        }
+    }
+}
+
+fn print_error(text: &str) {
+    if kept_back(|| Captured::Failed(text.to_owned())) {
+        return;
+    }
+    hide_progress();
+    eprintln!("{text}")
+}
+
+fn held_back() -> MutexGuard<'static, Option<Vec<String>>> {
+    HELD_BACK.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Keep the line made by `line` if capturing on this thread, see [`capture`]
+fn kept_back(line: impl FnOnce() -> Captured) -> bool {
+    CAPTURED.with_borrow_mut(|captured| match captured {
+        Some(lines) => {
+            lines.push(line());
+            true
+        }
+        None => false,
+    })
+}
+
+fn log(level: Level, text: &str) {
+    if level as u8 > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    if kept_back(|| Captured::Logged(level, text.to_owned())) {
+        return;
+    }
+    if let Some(file) = LOG_FILE.get() {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now());
+        let mut file = file.lock().unwrap_or_else(|error| error.into_inner());
+        let _ = writeln!(file, "{time} {:<5} {text}", level.name());
+        let _ = file.flush();
+    }
+    #[cfg(unix)]
+    if SYSLOG.load(Ordering::Relaxed) {
+        let text = CString::new(text.replace('\0', "\\0")).unwrap_or_default();
+        // SAFETY: the format is a C string with a single %s, matched by `text`,
+        // a NUL-terminated string living through the call.
+        unsafe { libc::syslog(level.syslog_priority(), c"%s".as_ptr(), text.as_ptr()) };
+    }
+}
+
+/// `println!` for result lines, see [`line`]
+macro_rules! report {
+    ($($arg:tt)*) => {
+        $crate::output::line(&format!($($arg)*))
+    };
+}
+pub(crate) use report;
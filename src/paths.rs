@@ -0,0 +1,114 @@
+//! Pure path helpers. Nothing here touches the filesystem.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Lexically normalize a path: drop `.` components and fold `name/..` pairs.
+///
+/// Leading `..` components of a relative path are kept as there is nothing
+/// to fold them into, while `..` right after the root is dropped.
+pub fn normalize(path: &Path) -> PathBuf {
+    normalized_components(path).iter().collect()
+}
+
+/// Compute a path to `to` relative to the directory `from_dir`.
+///
+/// Both paths are normalized lexically first. If they cannot be related
+/// without looking at the filesystem (one is absolute and the other is not,
+/// they have different roots, or `from_dir` climbs above the common prefix)
+/// `to` is returned unchanged.
+pub fn make_relative(from_dir: &Path, to: &Path) -> PathBuf {
+    if from_dir.is_absolute() != to.is_absolute() {
+        return to.to_owned();
+    }
+
+    let from = normalized_components(from_dir);
+    let to_components = normalized_components(to);
+    if root_of(&from) != root_of(&to_components) {
+        return to.to_owned();
+    }
+
+    let common = from
+        .iter()
+        .zip(&to_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if from[common..].contains(&Component::ParentDir) {
+        return to.to_owned();
+    }
+
+    let relative: PathBuf = std::iter::repeat_n(Component::ParentDir, from.len() - common)
+        .chain(to_components[common..].iter().copied())
+        .collect();
+    if relative.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        relative
+    }
+}
+
+/// Number of `..` components `path` starts with, how far up a relative path climbs before going down
+pub fn leading_parent_dirs(path: &Path) -> usize {
+    path.components()
+        .take_while(|component| *component == Component::ParentDir)
+        .count()
+}
+
+fn normalized_components(path: &Path) -> Vec<Component<'_>> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match components.last() {
+                Some(Component::Normal(_)) => {
+                    components.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => components.push(component),
+            },
+            _ => components.push(component),
+        }
+    }
+    components
+}
+
+fn root_of<'a>(components: &'a [Component<'a>]) -> &'a [Component<'a>] {
+    let len = components
+        .iter()
+        .take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+        .count();
+    &components[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relative(from_dir: &str, to: &str) -> PathBuf {
+        make_relative(Path::new(from_dir), Path::new(to))
+    }
+
+    #[test]
+    fn relates_siblings() {
+        assert_eq!(relative("/a/b", "/a/c/file"), Path::new("../c/file"));
+        assert_eq!(relative("a/b", "a/c"), Path::new("../c"));
+    }
+
+    #[test]
+    fn relates_ancestors() {
+        assert_eq!(relative("/a/b/c", "/a"), Path::new("../.."));
+        assert_eq!(relative("/a/b", "/a/b"), Path::new("."));
+    }
+
+    #[test]
+    fn relates_descendants() {
+        assert_eq!(relative("/a", "/a/b/c"), Path::new("b/c"));
+        assert_eq!(relative("/a/./x/..", "/a/b"), Path::new("b"));
+    }
+
+    #[test]
+    fn keeps_unrelated_paths() {
+        assert_eq!(relative("/a", "b/c"), Path::new("b/c"));
+        assert_eq!(relative("a", "/b/c"), Path::new("/b/c"));
+        assert_eq!(relative("../a", "b"), Path::new("b"));
+    }
+}
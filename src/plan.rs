@@ -0,0 +1,129 @@
+//! Plans listing the conversions a run would make, to review before applying them.
+//!
+//! A plan has the format of the checksum manifest: a `path\tsha256\ttarget`
+//! line per stub, paths as raw bytes.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use restore_symlink::{
+    convert::Converter,
+    platform,
+    scan::{Candidate, Classification},
+    summary::{Outcome, SkipReason, Summary},
+};
+
+use crate::{convert_file, manifest, print_error, print_io_error, report_batch, stop_early, Args};
+
+/// Write a plan with every convertible candidate to a new file at `path`
+///
+/// Paths are written absolute so the plan can be applied from anywhere.
+/// Returns how many conversions were planned. Stubs whose path or target
+/// holds a tab or a newline cannot be listed and are reported instead.
+pub fn dump<'a>(path: &Path, candidates: impl Iterator<Item = &'a Candidate>) -> io::Result<usize> {
+    let mut plan = BufWriter::new(File::create(path)?);
+    let mut planned = 0;
+    for candidate in candidates {
+        let Classification::Convertible { target } = &candidate.classification else {
+            continue;
+        };
+        if !listable(candidate.path.as_os_str()) || !listable(target) {
+            print_error(
+                &candidate.path,
+                "path or target cannot be written to a plan",
+            );
+            continue;
+        }
+        let absolute = std::path::absolute(&candidate.path)?;
+        match fs::read(&candidate.path) {
+            Ok(content) => {
+                plan.write_all(&manifest::entry(&absolute, &content, target))?;
+                planned += 1;
+            }
+            Err(error) => print_io_error(&candidate.path, &error.to_string()),
+        }
+    }
+    plan.flush()?;
+    Ok(planned)
+}
+
+/// Make exactly the conversions listed in the plan file `plan`
+///
+/// Nothing is scanned or classified again; a stub is only converted if it is
+/// still a regular file with the content it had when the plan was made.
+pub fn apply(plan: &Path, converter: &Converter, args: &Args, summary: &mut Summary) {
+    let ops = converter.ops();
+    let lines = match fs::read(plan) {
+        Ok(content) => content,
+        Err(error) => {
+            print_io_error(plan, &error.to_string());
+            summary.add(plan, Outcome::Failed);
+            return;
+        }
+    };
+
+    for (number, line) in lines.split(|&byte| byte == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split(|&byte| byte == b'\t');
+        let (Some(path), Some(sha256), Some(target), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            print_error(
+                plan,
+                &format!("line {} is not path, checksum and target", number + 1),
+            );
+            summary.add(plan, Outcome::Failed);
+            continue;
+        };
+        let path = platform::os_str(path);
+        let path = Path::new(&path);
+        let outcome = match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.is_file() => {
+                print_error(
+                    path,
+                    "is no regular file any more, refusing the planned conversion",
+                );
+                Outcome::Skipped(SkipReason::Stale)
+            }
+            Ok(metadata) => match ops.read(path) {
+                Ok(content) if manifest::sha256_hex(&content).as_bytes() == sha256 => {
+                    convert_file(converter, path, &platform::os_str(target), &metadata, args)
+                }
+                Ok(_) => {
+                    print_error(
+                        path,
+                        "content changed since planned, refusing the conversion",
+                    );
+                    Outcome::Skipped(SkipReason::Stale)
+                }
+                Err(error) => {
+                    print_io_error(path, &error.to_string());
+                    Outcome::Failed
+                }
+            },
+            Err(error) => {
+                print_io_error(path, &error.to_string());
+                Outcome::Failed
+            }
+        };
+        summary.add(path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+/// Whether `text` can be a field of a tab-separated line
+pub fn listable(text: &OsStr) -> bool {
+    !text
+        .as_encoded_bytes()
+        .iter()
+        .any(|&byte| byte == b'\t' || byte == b'\n')
+}
@@ -0,0 +1,263 @@
+//! The few filesystem calls that differ between Unix and Windows.
+//!
+//! Everywhere else, paths and targets are handled as bytes: the bytes the OS
+//! has on Unix, their UTF-8 form on Windows.
+
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fs::{self, File, Metadata, OpenOptions},
+    io,
+    path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::{
+    ffi::{OsStrExt, OsStringExt},
+    fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+};
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+#[cfg(windows)]
+use std::{
+    os::windows::fs::{symlink_dir, symlink_file, OpenOptionsExt},
+    path::PathBuf,
+};
+
+/// Longest path the OS accepts, the default limit for targets
+#[cfg(unix)]
+pub const PATH_MAX: usize = libc::PATH_MAX as usize;
+#[cfg(windows)]
+pub const PATH_MAX: usize = 32_767;
+
+/// Longest name the OS accepts for a single path component
+#[cfg(unix)]
+pub const NAME_MAX: usize = libc::NAME_MAX as usize;
+#[cfg(windows)]
+pub const NAME_MAX: usize = 255;
+
+/// Umask most systems start processes with
+const DEFAULT_UMASK: u32 = 0o022;
+
+/// Opens the reparse point itself, rather than the file a symlink points to
+#[cfg(windows)]
+const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+
+/// Returned when the process may not create symlinks
+#[cfg(windows)]
+const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+/// Identifies a file whatever path reaches it, to notice dirs met twice
+#[cfg(unix)]
+pub type FileId = (u64, u64);
+#[cfg(windows)]
+pub type FileId = PathBuf;
+
+/// `bytes` read from a file as an OS string
+#[cfg(unix)]
+pub fn os_string(bytes: Vec<u8>) -> OsString {
+    OsString::from_vec(bytes)
+}
+
+/// `bytes` read from a file as an OS string
+///
+/// Windows names are text, so bytes that are not UTF-8 are replaced.
+#[cfg(windows)]
+pub fn os_string(bytes: Vec<u8>) -> OsString {
+    match String::from_utf8(bytes) {
+        Ok(text) => text.into(),
+        Err(error) => String::from_utf8_lossy(error.as_bytes())
+            .into_owned()
+            .into(),
+    }
+}
+
+/// `bytes` as an OS string, converted like [`os_string`] does
+#[cfg(unix)]
+pub fn os_str(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Borrowed(OsStr::from_bytes(bytes))
+}
+
+/// `bytes` as an OS string, converted like [`os_string`] does
+#[cfg(windows)]
+pub fn os_str(bytes: &[u8]) -> Cow<'_, OsStr> {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(text) => Cow::Borrowed(OsStr::new(text)),
+        Cow::Owned(text) => Cow::Owned(text.into()),
+    }
+}
+
+/// [`FileId`] of the file at `path` with `metadata`
+#[cfg(unix)]
+pub fn file_id(_path: &Path, metadata: &Metadata) -> FileId {
+    (metadata.dev(), metadata.ino())
+}
+
+/// [`FileId`] of the file at `path` with `metadata`
+#[cfg(windows)]
+pub fn file_id(path: &Path, _metadata: &Metadata) -> FileId {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Device the file of `metadata` is on
+#[cfg(unix)]
+pub fn device(metadata: &Metadata) -> u64 {
+    metadata.dev()
+}
+
+/// Device the file of `metadata` is on, unknown and so the same for every file
+#[cfg(windows)]
+pub fn device(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// User id owning the file of `metadata`
+#[cfg(unix)]
+pub fn owner(metadata: &Metadata) -> Option<u32> {
+    Some(metadata.uid())
+}
+
+/// User id owning the file of `metadata`, Windows has none
+#[cfg(windows)]
+pub fn owner(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// When the inode of the file of `metadata` last changed, if the OS tells
+#[cfg(unix)]
+pub fn changed_at(metadata: &Metadata) -> Option<(i64, i64)> {
+    Some((metadata.ctime(), metadata.ctime_nsec()))
+}
+
+/// When the inode of the file of `metadata` last changed, which Windows does not tell
+#[cfg(windows)]
+pub fn changed_at(_metadata: &Metadata) -> Option<(i64, i64)> {
+    None
+}
+
+/// Permission bits of the file of `metadata`
+#[cfg(unix)]
+pub fn mode(metadata: &Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Permission bits of the file of `metadata`, made up from its read-only flag
+#[cfg(windows)]
+pub fn mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Permission bits of a file newly created by this process, `0o666` less its umask
+pub fn new_file_mode() -> u32 {
+    0o666 & !umask()
+}
+
+/// Umask of the process, read once from `/proc`
+///
+/// Setting the umask to read it back would race with files created
+/// meanwhile by other threads, so the common 022 is assumed if it cannot be
+/// read.
+#[cfg(target_os = "linux")]
+fn umask() -> u32 {
+    static UMASK: OnceLock<u32> = OnceLock::new();
+    *UMASK.get_or_init(|| {
+        fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Umask:"))
+                    .and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok())
+            })
+            .unwrap_or(DEFAULT_UMASK)
+    })
+}
+
+/// Umask of the process, assumed to be the common 022
+#[cfg(not(target_os = "linux"))]
+fn umask() -> u32 {
+    DEFAULT_UMASK
+}
+
+/// Give the file at `path` the permission bits `mode`
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Give the file at `path` the permission bits `mode`, of which Windows only
+/// keeps whether the owner may write
+#[cfg(windows)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Whether `a` and `b` are the metadata of the same file
+#[cfg(unix)]
+pub fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+/// Whether `a` and `b` are the metadata of the same file
+///
+/// File ids are not stable on Windows, so the size and times must match.
+#[cfg(windows)]
+pub fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    a.len() == b.len()
+        && a.modified().ok() == b.modified().ok()
+        && a.created().ok() == b.created().ok()
+}
+
+/// Open the file at `path` for reading, failing rather than following a
+/// symlink there, and without blocking on a FIFO
+#[cfg(unix)]
+pub fn open_no_follow(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// Open the file at `path` for reading, opening a symlink there itself
+/// rather than what it points to
+#[cfg(windows)]
+pub fn open_no_follow(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT)
+        .open(path)
+}
+
+/// Create a symlink at `link` to `target`
+#[cfg(unix)]
+pub fn symlink(target: &OsStr, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Create a symlink at `link` to `target`
+///
+/// Windows tells file from directory symlinks, so `target` resolved from the
+/// dir of `link` is looked at to pick one. Lacking the privilege to create
+/// symlinks is reported as such.
+#[cfg(windows)]
+pub fn symlink(target: &OsStr, link: &Path) -> io::Result<()> {
+    let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+    let created = if resolved.is_dir() {
+        symlink_dir(target, link)
+    } else {
+        symlink_file(target, link)
+    };
+    created.map_err(|error| match error.raw_os_error() {
+        Some(ERROR_PRIVILEGE_NOT_HELD) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "creating symlinks needs Developer Mode or administrator rights",
+        ),
+        _ => error,
+    })
+}
@@ -0,0 +1,121 @@
+//! Pointer files git-annex and git-LFS leave in a work tree in place of content.
+//!
+//! Their objects live in the git dir of the repository holding the pointer,
+//! found by looking for `.git` in the directories above it.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    str,
+    sync::Mutex,
+};
+
+use crate::platform;
+
+/// Start of the content of an unlocked git-annex file
+const ANNEX_PREFIX: &[u8] = b"/annex/objects/";
+
+/// First line of a git-LFS pointer
+const LFS_VERSION: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+/// Object files of each annex, by key, read once per objects dir
+static ANNEX_OBJECTS: Mutex<BTreeMap<PathBuf, HashMap<OsString, PathBuf>>> =
+    Mutex::new(BTreeMap::new());
+
+/// The annex key an unlocked git-annex file with `content` stands for
+pub fn annex_key(content: &[u8]) -> Option<Cow<'_, OsStr>> {
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    let key = content.strip_prefix(ANNEX_PREFIX)?;
+    let valid = !key.is_empty()
+        && !key
+            .iter()
+            .any(|&byte| byte == b'/' || byte.is_ascii_whitespace() || byte.is_ascii_control());
+    valid.then(|| platform::os_str(key))
+}
+
+/// The SHA-256 object id a git-LFS pointer with `content` stands for
+pub fn lfs_oid(content: &[u8]) -> Option<String> {
+    let mut lines = content.split(|&byte| byte == b'\n');
+    if lines.next()? != LFS_VERSION {
+        return None;
+    }
+    let oid = lines.find_map(|line| line.strip_prefix(b"oid sha256:"))?;
+    let oid = str::from_utf8(oid).ok()?;
+    (oid.len() == 64 && oid.bytes().all(|byte| byte.is_ascii_hexdigit()))
+        .then(|| oid.to_ascii_lowercase())
+}
+
+/// The git dir of the repository holding `path`, if any
+fn git_dir(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    path.ancestors().skip(1).find_map(|dir| {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        // Worktrees and submodules have a file naming their git dir instead.
+        let content = fs::read(&dot_git).ok()?;
+        let content = content.strip_suffix(b"\n").unwrap_or(&content);
+        let git_dir = content.strip_prefix(b"gitdir: ")?;
+        Some(dir.join(platform::os_str(git_dir)))
+    })
+}
+
+/// The object file of the annex `key`, in the repository holding `pointer`
+///
+/// The objects dir is read the first time it is needed, objects added
+/// later are not found.
+pub fn annex_object(pointer: &Path, key: &OsStr) -> Option<PathBuf> {
+    let objects = git_dir(pointer)?.join("annex").join("objects");
+    let mut annexes = ANNEX_OBJECTS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    annexes
+        .entry(objects)
+        .or_insert_with_key(|objects| read_annex(objects))
+        .get(key)
+        .cloned()
+}
+
+/// Every `HASH1/HASH2/KEY/KEY` object file below `objects`, by key
+///
+/// Both the mixed and the lower case hash dirs git-annex uses have two levels.
+fn read_annex(objects: &Path) -> HashMap<OsString, PathBuf> {
+    let entries = |dir: &Path| {
+        fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>()
+    };
+    let mut keys = HashMap::new();
+    for first in entries(objects) {
+        for second in entries(&first) {
+            for key_dir in entries(&second) {
+                let Some(key) = key_dir.file_name() else {
+                    continue;
+                };
+                let object = key_dir.join(key);
+                if object.is_file() {
+                    keys.insert(key.to_owned(), object);
+                }
+            }
+        }
+    }
+    keys
+}
+
+/// The object file of the LFS `oid`, in the repository holding `pointer`, if fetched
+pub fn lfs_object(pointer: &Path, oid: &str) -> Option<PathBuf> {
+    let object = git_dir(pointer)?
+        .join("lfs")
+        .join("objects")
+        .join(oid.get(..2)?)
+        .join(oid.get(2..4)?)
+        .join(oid);
+    object.is_file().then_some(object)
+}
@@ -0,0 +1,136 @@
+//! Questions asked with `--interactive`, and the answers that outlast them.
+
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use crate::Answer;
+
+/// Whether `a` was answered, so every following question is taken as yes
+static YES_TO_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Whether `q` was answered, so the run stops and every following question is taken as no
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+/// Directories `s` was answered for, whose remaining stubs are left alone
+static SKIPPED_DIRS: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// What to do with one stub
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Yes,
+    No,
+    /// Create the link with this target instead
+    Edit(OsString),
+    /// Leave this stub and the rest of its directory alone
+    SkipDir,
+}
+
+/// Ask `question`, answered with yes, no, all or quit, and whether it was yes
+pub fn confirm(question: &str, default: Option<Answer>) -> bool {
+    ask(question, default, false) == Reply::Yes
+}
+
+/// Ask whether to convert the stub at `path`, also offering to edit its
+/// target or to skip the rest of its directory
+pub fn ask_conversion(question: &str, path: &Path, default: Option<Answer>) -> Reply {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if skipped_dirs()
+        .as_ref()
+        .is_some_and(|dirs| dirs.contains(dir))
+    {
+        return Reply::No;
+    }
+    let reply = ask(question, default, true);
+    if reply == Reply::SkipDir {
+        skipped_dirs()
+            .get_or_insert_with(HashSet::new)
+            .insert(dir.to_owned());
+    }
+    reply
+}
+
+/// Whether `q` was answered to some question
+pub fn quit_requested() -> bool {
+    QUIT.load(Ordering::Relaxed)
+}
+
+fn ask(question: &str, default: Option<Answer>, per_stub: bool) -> Reply {
+    if quit_requested() {
+        return Reply::No;
+    }
+    if YES_TO_ALL.load(Ordering::Relaxed) {
+        return Reply::Yes;
+    }
+    let (yes, no) = match default {
+        Some(Answer::Yes) => ("Y", "n"),
+        Some(Answer::No) => ("y", "N"),
+        None => ("y", "n"),
+    };
+    let more = if per_stub { "/e/s" } else { "" };
+    println!("{question} [{yes}/{no}/a/q{more}/?]");
+    loop {
+        let Some(input) = read_line() else {
+            return default_reply(default);
+        };
+        match (input.trim(), default) {
+            ("y" | "Y", _) => return Reply::Yes,
+            ("n" | "N", _) => return Reply::No,
+            ("a" | "A", _) => {
+                YES_TO_ALL.store(true, Ordering::Relaxed);
+                return Reply::Yes;
+            }
+            ("q" | "Q", _) => {
+                QUIT.store(true, Ordering::Relaxed);
+                return Reply::No;
+            }
+            ("e" | "E", _) if per_stub => {
+                println!("New target (empty to keep it):");
+                match read_line().map(|line| line.trim_end_matches(['\r', '\n']).to_owned()) {
+                    Some(target) if !target.is_empty() => return Reply::Edit(target.into()),
+                    Some(_) => println!("{question} [{yes}/{no}/a/q{more}/?]"),
+                    None => return default_reply(default),
+                }
+            }
+            ("s" | "S", _) if per_stub => return Reply::SkipDir,
+            ("", Some(default)) => return default_reply(Some(default)),
+            _ => {
+                println!("y: yes, n: no, a: yes to all remaining, q: quit");
+                if per_stub {
+                    println!("e: edit the target first, s: skip the rest of this directory")
+                }
+            }
+        }
+    }
+}
+
+/// A line from stdin, `None` at its end or on a read error
+fn read_line() -> Option<String> {
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(input),
+    }
+}
+
+/// Reply taken when stdin has ended: the default, or no without one
+fn default_reply(default: Option<Answer>) -> Reply {
+    if default == Some(Answer::Yes) {
+        Reply::Yes
+    } else {
+        Reply::No
+    }
+}
+
+fn skipped_dirs() -> MutexGuard<'static, Option<HashSet<PathBuf>>> {
+    SKIPPED_DIRS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+}
@@ -0,0 +1,106 @@
+//! Removing directories a run left empty.
+
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use restore_symlink::fs_ops::FsOps;
+
+use crate::{
+    output::{self, report},
+    print_io_error, prompt, Args,
+};
+
+/// Remove the directories below `root` emptied by deleting files in `touched`
+///
+/// A directory that becomes empty by removing one below it is removed as
+/// well. With `all`, every empty directory below `root` is removed, not
+/// just those the run emptied. `root` itself is always kept.
+pub fn prune_empty_dirs(
+    ops: &dyn FsOps,
+    root: &Path,
+    touched: BTreeSet<PathBuf>,
+    all: bool,
+    args: &Args,
+) {
+    let mut dirs = touched;
+    if all {
+        if let Err(error) = collect_dirs(ops, root, &mut dirs) {
+            print_io_error(root, &error.to_string());
+        }
+    }
+
+    // Reverse order visits every directory before the ones containing it
+    while let Some(dir) = dirs.pop_last() {
+        if dir == root || !dir.starts_with(root) {
+            continue;
+        }
+        match ops
+            .read_dir(&dir)
+            .map(|mut entries| entries.next().is_none())
+        {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(error) => {
+                print_io_error(&dir, &error.to_string());
+                continue;
+            }
+        }
+        if args.interactive
+            && !prompt::confirm(
+                &format!("Remove empty directory '{}'?", output::shown(&dir)),
+                args.interactive_default,
+            )
+        {
+            continue;
+        }
+        if let Err(error) = ops.remove_dir(&dir) {
+            print_io_error(&dir, &error.to_string());
+            continue;
+        }
+        if !args.silent {
+            report!("Removed empty directory: {}", output::shown(&dir))
+        }
+        if let Some(parent) = dir.parent() {
+            dirs.insert(parent.to_owned());
+        }
+    }
+}
+
+/// Add every directory below `dir` to `dirs`, not following symlinks
+fn collect_dirs(ops: &dyn FsOps, dir: &Path, dirs: &mut BTreeSet<PathBuf>) -> io::Result<()> {
+    for path in ops.read_dir(dir)? {
+        let path = path?;
+        if ops.metadata(&path)?.is_dir() {
+            collect_dirs(ops, &path, dirs)?;
+            dirs.insert(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use restore_symlink::fs_ops::MemFs;
+
+    use super::*;
+
+    #[test]
+    fn removes_dirs_emptied_up_to_the_root() {
+        let ops = MemFs::new();
+        ops.create_dir_all(Path::new("/root/a/b")).unwrap();
+        ops.create_dir_all(Path::new("/root/c")).unwrap();
+        ops.write(Path::new("/root/c/file"), b"", 0o644).unwrap();
+        let args = Args::parse_from(["restore_symlink", "--silent", "/root"]);
+
+        let touched = BTreeSet::from([PathBuf::from("/root/a/b"), PathBuf::from("/root/c")]);
+        prune_empty_dirs(&ops, Path::new("/root"), touched, false, &args);
+
+        assert_eq!(ops.node(Path::new("/root/a")), None);
+        assert!(ops.node(Path::new("/root/c")).is_some());
+        assert!(ops.node(Path::new("/root")).is_some());
+    }
+}
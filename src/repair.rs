@@ -0,0 +1,118 @@
+//! Re-creating broken symlinks from stubs left next to them.
+
+use std::{ffi::OsString, fs, path::Path};
+
+use restore_symlink::{
+    fs_ops::FsOps,
+    scan::{inspect, scan, Classification, ScanOptions},
+    summary::{Outcome, SkipReason, Summary},
+};
+
+use crate::{
+    output::{self, report},
+    print_error, print_io_error, prompt, record_converted, refuse_unsafe_dir, report_batch,
+    stop_early, with_retry, Args,
+};
+
+/// Extension of the stub kept next to a symlink, holding the target to repair it with
+const SIDECAR_EXTENSION: &str = "target";
+
+/// Point every broken symlink below `root` to the target of its sidecar stub
+///
+/// The sidecar of `dir/link` is `dir/link.target`, read like any stub but
+/// left in place. Healthy symlinks and everything else are not touched.
+pub fn repair_broken(
+    ops: &dyn FsOps,
+    root: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    for candidate in scan(root, options) {
+        let path = &candidate.path;
+        if !matches!(candidate.classification, Classification::Symlink { .. })
+            || fs::metadata(path).is_ok()
+        {
+            continue;
+        }
+
+        let mut sidecar = OsString::from(path);
+        sidecar.push(".");
+        sidecar.push(SIDECAR_EXTENSION);
+        let sidecar = Path::new(&sidecar);
+        let outcome = if fs::symlink_metadata(sidecar).is_err() {
+            if args.verbose > 0 {
+                report!(
+                    "Broken symlink {} has no {}",
+                    output::shown(path),
+                    output::shown(sidecar)
+                )
+            }
+            Outcome::Skipped(SkipReason::Symlink)
+        } else {
+            match inspect(sidecar, options).classification {
+                Classification::Convertible { target } => relink(ops, path, &target, args),
+                Classification::TargetMissing { target } => {
+                    if args.verbose > 0 {
+                        report!(
+                            "Sidecar {} of broken symlink {} points to missing {}",
+                            output::shown(sidecar),
+                            output::shown(path),
+                            output::text(target)
+                        )
+                    }
+                    Outcome::Skipped(SkipReason::TargetMissing)
+                }
+                _ => {
+                    print_error(
+                        path,
+                        &format!("'{}' holds no usable target", output::shown(sidecar)),
+                    );
+                    Outcome::Failed
+                }
+            }
+        };
+        summary.add(path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+fn relink(ops: &dyn FsOps, link: &Path, target: &OsString, args: &Args) -> Outcome {
+    if refuse_unsafe_dir(link, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+    if args.interactive
+        && !prompt::confirm(
+            &format!(
+                "Repair broken symlink '{}' to point to '{}'?",
+                output::shown(link),
+                output::text(target)
+            ),
+            args.interactive_default,
+        )
+    {
+        return Outcome::Skipped(SkipReason::Declined);
+    }
+
+    if let Err(error) = with_retry(args.retry, || ops.remove_file(link)) {
+        print_io_error(link, &error.to_string());
+        return Outcome::Failed;
+    }
+    if let Err(error) = with_retry(args.retry, || ops.symlink(target, link)) {
+        print_io_error(link, &error.to_string());
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!(
+            "Repaired symlink: {} -> {}",
+            output::shown(link),
+            output::text(target)
+        )
+    }
+    record_converted(link, args);
+    Outcome::Converted
+}
@@ -0,0 +1,95 @@
+//! Turning symlinks back into stubs, for filesystems that cannot hold symlinks.
+
+use std::path::Path;
+
+use restore_symlink::{
+    fs_ops::FsOps,
+    scan::{scan, Classification, ScanOptions},
+    summary::{Outcome, SkipReason, Summary},
+};
+
+use crate::{
+    output::{self, report},
+    print_io_error, prompt, record_converted, refuse_unsafe_dir, report_batch, stop_early,
+    with_retry, Args, DEFAULT_FILE_MODE,
+};
+
+/// Replace every symlink below `root` with a file holding its target
+///
+/// The files are what a later run turns back into the same symlinks.
+/// Everything but symlinks is left alone and not counted.
+pub fn materialize(
+    ops: &dyn FsOps,
+    root: &Path,
+    options: &ScanOptions,
+    args: &Args,
+    summary: &mut Summary,
+) {
+    for candidate in scan(root, options) {
+        let Classification::Symlink { target } = &candidate.classification else {
+            continue;
+        };
+        let outcome = materialize_link(ops, &candidate.path, target, args);
+        summary.add(&candidate.path, outcome);
+        report_batch(summary, outcome, args);
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+fn materialize_link(ops: &dyn FsOps, link: &Path, target: &Path, args: &Args) -> Outcome {
+    let content = target.as_os_str().as_encoded_bytes();
+    if content.len() as u64 > args.len {
+        if args.verbose > 0 {
+            report!(
+                "Target of symlink {} is too long to fit a stub({} > {})",
+                output::shown(link),
+                content.len(),
+                args.len
+            )
+        }
+        return Outcome::Skipped(SkipReason::TooBig);
+    }
+    if refuse_unsafe_dir(link, args) {
+        return Outcome::Skipped(SkipReason::UnsafeParent);
+    }
+
+    if args.interactive
+        && !prompt::confirm(
+            &format!(
+                "Convert symlink '{}' -> '{}' into file?",
+                output::shown(link),
+                output::text(target)
+            ),
+            args.interactive_default,
+        )
+    {
+        return Outcome::Skipped(SkipReason::Declined);
+    }
+
+    if let Err(error) = with_retry(args.retry, || ops.remove_file(link)) {
+        print_io_error(link, &error.to_string());
+        return Outcome::Failed;
+    }
+    if let Err(error) = with_retry(args.retry, || ops.write(link, content, DEFAULT_FILE_MODE)) {
+        match ops.symlink(target.as_os_str(), link) {
+            Ok(()) => print_io_error(link, &format!("{error}, symlink restored")),
+            Err(restore_error) => print_io_error(
+                link,
+                &format!("{error}, cannot restore symlink: {restore_error}"),
+            ),
+        }
+        return Outcome::Failed;
+    }
+
+    if !args.silent {
+        report!(
+            "Converted to file: {} -> {}",
+            output::shown(link),
+            output::text(target)
+        )
+    }
+    record_converted(link, args);
+    Outcome::Converted
+}
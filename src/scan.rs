@@ -0,0 +1,2377 @@
+//! Read-only discovery of files that look like flattened symlinks.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, Metadata},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    str,
+    time::SystemTime,
+    vec,
+};
+
+use encoding_rs::Encoding;
+use globset::{Glob, GlobMatcher};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use rayon::prelude::*;
+use regex::bytes::Regex;
+
+use crate::{
+    fs_ops::{DirEntries, FsOps, RealFs},
+    paths,
+    platform::{self, FileId},
+    pointers,
+};
+
+/// Number of stubs `flatten` follows before giving up on a chain.
+const MAX_CHAIN_LEN: usize = 32;
+
+/// What a scan takes into account when classifying files.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Maximum file length to be considered as possible link
+    pub len: u64,
+    /// Minimum file length to be considered as possible link
+    pub min_len: u64,
+    /// Drop a single newline ending the file content
+    pub strip_trailing_newline: bool,
+    /// Extended attribute to read the target from before falling back to content
+    pub from_xattr: Option<String>,
+    /// Strip a trailing comment starting with this char from the target
+    pub strip_comment: Option<char>,
+    /// Trim these characters along with whitespace from both ends of the target
+    pub trim_chars: Option<String>,
+    /// Do not yield entries more than this many levels below the root
+    pub max_depth: Option<usize>,
+    /// Do not yield files less than this many levels below the root
+    pub min_depth: Option<usize>,
+    /// Do not yield files last modified at or before this time
+    pub modified_since: Option<SystemTime>,
+    /// If not empty, targets must resolve to a path under one of these dirs
+    pub allowed_roots: Vec<PathBuf>,
+    /// Exclude entries matched by `.gitignore` and `.ignore` files met during the walk
+    pub gitignore: bool,
+    /// Exclude entries below the root whose name starts with a dot
+    pub skip_hidden: bool,
+    /// Exclude directories on another filesystem than the root
+    pub one_file_system: bool,
+    /// If given, only read files owned by this user id
+    pub owner: Option<u32>,
+    /// Link git-annex pointer files to their object in the annex
+    pub annex: bool,
+    /// Classify git-LFS pointer files as [`Classification::LfsPointer`]
+    pub lfs: bool,
+    /// Exclude entries matching any of these, directories are not descended into
+    pub exclude: Vec<GlobMatcher>,
+    /// If not empty, exclude files matching none of these
+    pub include: Vec<GlobMatcher>,
+    /// If given, exclude files not in this set
+    pub only_files: Option<HashSet<PathBuf>>,
+    /// Turn `file://` URLs into the path they contain instead of skipping them
+    pub strip_file_url: bool,
+    /// Follow targets that are stubs themselves to the final target
+    pub flatten: bool,
+    /// Longest target in bytes to accept, `PATH_MAX` by default
+    pub max_target_len: usize,
+    /// Most leading `..` components to accept in a relative target
+    pub max_relative_depth: Option<usize>,
+    /// Read directories as the walk goes instead of completely when entering them
+    pub streaming: bool,
+    /// Classify the files of each directory concurrently when entering it
+    pub parallel: bool,
+    /// Walk the entries of each directory sorted by name
+    pub sorted: bool,
+    /// Only accept targets the current user can open for reading
+    pub require_readable: bool,
+    /// Leading paths replaced in targets, the first one matching wins
+    pub prefix_maps: Vec<(PathBuf, PathBuf)>,
+    /// Literal replacements applied to every target after `prefix_maps`, in order
+    pub rewrites: Vec<(OsString, OsString)>,
+    /// Regex replacements applied to every target after `rewrites`, in order
+    pub regex_rewrites: Vec<(Regex, OsString)>,
+    /// Reject targets with control characters other than surrounding whitespace
+    pub strict_path: bool,
+    /// Encoding of file contents, taken as raw bytes if not set
+    pub encoding: Option<&'static Encoding>,
+    /// Also yield a [`Classification::Directory`] candidate for every directory entered
+    pub report_dirs: bool,
+    /// Leave out every entry that sorts before this path, see [`ScanOptionsBuilder::resume_from`]
+    pub resume_from: Option<PathBuf>,
+    /// Use the first line of content spanning several lines instead of skipping it
+    pub first_line: bool,
+    /// What to do with symlinks met during the walk
+    pub on_symlink: OnSymlink,
+    /// Take `\` in targets as path separator, for stubs written on Windows
+    pub normalize_separators: bool,
+    /// Look for a missing target under names differing only by case
+    pub ignore_case_targets: bool,
+    /// Accept targets that do not exist if they look enough like a path
+    pub allow_missing_targets: bool,
+}
+
+/// What a scan does with a symlink it meets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnSymlink {
+    /// Yield it as [`Classification::Symlink`]
+    #[default]
+    Skip,
+    /// Descend into symlinked directories, yielding symlinks to anything else
+    Follow,
+    /// Classify the file or walk the directory the symlink resolves to, under its real path
+    ConvertTarget,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            len: 512,
+            min_len: 1,
+            strip_trailing_newline: true,
+            from_xattr: None,
+            strip_comment: None,
+            trim_chars: None,
+            max_depth: None,
+            min_depth: None,
+            modified_since: None,
+            allowed_roots: Vec::new(),
+            gitignore: true,
+            skip_hidden: true,
+            one_file_system: false,
+            owner: None,
+            annex: false,
+            lfs: false,
+            exclude: Vec::new(),
+            include: Vec::new(),
+            only_files: None,
+            strip_file_url: false,
+            flatten: false,
+            max_target_len: platform::PATH_MAX,
+            max_relative_depth: None,
+            streaming: false,
+            parallel: false,
+            sorted: false,
+            require_readable: false,
+            prefix_maps: Vec::new(),
+            rewrites: Vec::new(),
+            regex_rewrites: Vec::new(),
+            strict_path: true,
+            encoding: None,
+            report_dirs: false,
+            resume_from: None,
+            first_line: false,
+            on_symlink: OnSymlink::Skip,
+            normalize_separators: false,
+            ignore_case_targets: false,
+            allow_missing_targets: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Set up options starting from the defaults, which are those of the
+    /// command line tool walking recursively
+    pub fn builder() -> ScanOptionsBuilder {
+        ScanOptionsBuilder {
+            options: ScanOptions::default(),
+        }
+    }
+}
+
+/// Builds [`ScanOptions`] step by step, checking them in [`build`](Self::build).
+#[derive(Debug, Clone)]
+pub struct ScanOptionsBuilder {
+    options: ScanOptions,
+}
+
+impl ScanOptionsBuilder {
+    /// Skip files longer than `len` bytes as too big, 512 by default
+    pub fn len(mut self, len: u64) -> Self {
+        self.options.len = len;
+        self
+    }
+
+    /// Skip files shorter than `min_len` bytes as too small, 1 by default
+    pub fn min_len(mut self, min_len: u64) -> Self {
+        self.options.min_len = min_len;
+        self
+    }
+
+    /// Whether to drop a single `\n` ending the content before anything
+    /// else, as many editors and tools write one. On by default.
+    pub fn strip_trailing_newline(mut self, strip_trailing_newline: bool) -> Self {
+        self.options.strip_trailing_newline = strip_trailing_newline;
+        self
+    }
+
+    /// Read the target from the extended attribute `name` of a file,
+    /// falling back to its content if it has none. Unix only.
+    pub fn from_xattr(mut self, name: impl Into<String>) -> Self {
+        self.options.from_xattr = Some(name.into());
+        self
+    }
+
+    /// Strip a comment starting with `marker` from the end of targets
+    pub fn strip_comment(mut self, marker: char) -> Self {
+        self.options.strip_comment = Some(marker);
+        self
+    }
+
+    /// Trim every character of `chars` and whitespace from both ends of targets,
+    /// right after stripping a comment
+    pub fn trim_chars(mut self, chars: impl Into<String>) -> Self {
+        self.options.trim_chars = Some(chars.into());
+        self
+    }
+
+    /// Leave out entries more than `max_depth` levels below the root,
+    /// without descending into directories at that level
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Leave out files less than `min_depth` levels below the root, directories
+    /// above it are still walked.
+    pub fn min_depth(mut self, min_depth: usize) -> Self {
+        self.options.min_depth = Some(min_depth);
+        self
+    }
+
+    /// Leave out files not modified after `time`, for runs over a tree
+    /// that has mostly been handled before.
+    pub fn modified_since(mut self, time: SystemTime) -> Self {
+        self.options.modified_since = Some(time);
+        self
+    }
+
+    /// Only accept targets resolving under `dir`. May be given several times.
+    pub fn target_must_be_under(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.allowed_roots.push(dir.into());
+        self
+    }
+
+    /// Whether to leave out entries ignored by the `.gitignore` and
+    /// `.ignore` files met during the walk. On by default.
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.options.gitignore = gitignore;
+        self
+    }
+
+    /// Leave out hidden files and dirs below the root, without descending
+    /// into them. The root itself is walked even if hidden. On by default.
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.options.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Leave out directories on another filesystem than the root, without
+    /// descending into them, telling filesystems apart by their device number
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.options.one_file_system = one_file_system;
+        self
+    }
+
+    /// Classify files owned by another user than `uid` as
+    /// [`Classification::OtherOwner`] without reading them
+    pub fn owned_by(mut self, uid: u32) -> Self {
+        self.options.owner = Some(uid);
+        self
+    }
+
+    /// Take files holding `/annex/objects/KEY` as stubs for the object of
+    /// KEY in the annex of their repository, linked to relatively
+    pub fn annex(mut self, annex: bool) -> Self {
+        self.options.annex = annex;
+        self
+    }
+
+    /// Classify git-LFS pointer files as [`Classification::LfsPointer`]
+    /// instead of looking at their content as a path
+    pub fn lfs(mut self, lfs: bool) -> Self {
+        self.options.lfs = lfs;
+        self
+    }
+
+    /// Link to the path of `file://` URLs instead of skipping them as URLs
+    pub fn strip_file_url(mut self, strip_file_url: bool) -> Self {
+        self.options.strip_file_url = strip_file_url;
+        self
+    }
+
+    /// Follow targets that are stubs themselves to the final target, up to
+    /// 32 stubs deep
+    pub fn flatten(mut self, flatten: bool) -> Self {
+        self.options.flatten = flatten;
+        self
+    }
+
+    /// Reject targets, as stored or as made by [`flatten`](Self::flatten),
+    /// starting with more than `depth` `..` components
+    pub fn max_relative_depth(mut self, depth: usize) -> Self {
+        self.options.max_relative_depth = Some(depth);
+        self
+    }
+
+    /// Reject targets longer than `max_target_len` bytes, `PATH_MAX` by default
+    pub fn max_target_len(mut self, max_target_len: usize) -> Self {
+        self.options.max_target_len = max_target_len;
+        self
+    }
+
+    /// Keep memory bounded in huge directories, at the cost of one open
+    /// directory handle per level of the walk
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.options.streaming = streaming;
+        self
+    }
+
+    /// Read the files of a directory on the rayon thread pool as it is
+    /// entered, yielding them in the same order; ignored when streaming
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.options.parallel = parallel;
+        self
+    }
+
+    /// Yield the entries of each directory sorted by name rather than in the
+    /// order the filesystem lists them, so walks are the same on every run;
+    /// ignored when streaming
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.options.sorted = sorted;
+        self
+    }
+
+    /// Reject targets the current user cannot open for reading
+    pub fn require_readable(mut self, require_readable: bool) -> Self {
+        self.options.require_readable = require_readable;
+        self
+    }
+
+    /// Leave out entries below the root matching `glob`, without descending
+    /// into such directories. May be given several times. A glob without a
+    /// `/` is matched against the entry name, others against the path
+    /// relative to the root.
+    pub fn exclude(mut self, glob: Glob) -> Self {
+        self.options.exclude.push(glob.compile_matcher());
+        self
+    }
+
+    /// Leave out files below the root matching none of the globs given this
+    /// way, matched like [`exclude`](Self::exclude). Directories are still
+    /// walked, and excludes win over includes.
+    pub fn include(mut self, glob: Glob) -> Self {
+        self.options.include.push(glob.compile_matcher());
+        self
+    }
+
+    /// Exclude every file that is not among `files`, given the way the walk
+    /// reaches them: joined to the root as passed to [`scan`].
+    pub fn only_files(mut self, files: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.options.only_files = Some(files.into_iter().collect());
+        self
+    }
+
+    /// Replace the leading path `from` of targets with `to`, matching whole
+    /// components only. May be given several times, the first one matching a
+    /// target is used. Applied before [`rewrite`](Self::rewrite).
+    pub fn map_prefix(mut self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) -> Self {
+        self.options.prefix_maps.push((from.into(), to.into()));
+        self
+    }
+
+    /// Replace every `from` in targets with `to`. May be given several times,
+    /// the replacements are applied in order.
+    pub fn rewrite(mut self, from: impl Into<OsString>, to: impl Into<OsString>) -> Self {
+        self.options.rewrites.push((from.into(), to.into()));
+        self
+    }
+
+    /// Replace every match of `pattern` in targets with `replacement`, which
+    /// may refer to capture groups as `$1`. Applied after [`rewrite`](Self::rewrite).
+    pub fn rewrite_regex(mut self, pattern: Regex, replacement: impl Into<OsString>) -> Self {
+        self.options
+            .regex_rewrites
+            .push((pattern, replacement.into()));
+        self
+    }
+
+    /// Whether to reject targets with control characters other than
+    /// surrounding whitespace. On by default.
+    pub fn strict_path(mut self, strict_path: bool) -> Self {
+        self.options.strict_path = strict_path;
+        self
+    }
+
+    /// Decode file contents from `encoding` instead of taking them as raw bytes
+    pub fn encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.options.encoding = Some(encoding);
+        self
+    }
+
+    /// Also yield a [`Classification::Directory`] candidate for every
+    /// directory entered
+    pub fn report_dirs(mut self, report_dirs: bool) -> Self {
+        self.options.report_dirs = report_dirs;
+        self
+    }
+
+    /// Leave out entries whose path sorts before `path`, component by
+    /// component, to continue an interrupted run. Directories sorting before
+    /// it are not descended into. As directories are not walked in sorted
+    /// order, entries an interrupted run had not reached yet may be left out
+    /// as well.
+    pub fn resume_from(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.resume_from = Some(path.into());
+        self
+    }
+
+    /// Take the first line of content spanning several lines as the target,
+    /// instead of skipping it as multiple paths
+    pub fn first_line(mut self, first_line: bool) -> Self {
+        self.options.first_line = first_line;
+        self
+    }
+
+    /// What to do with symlinks met during the walk, yield them by default
+    pub fn on_symlink(mut self, on_symlink: OnSymlink) -> Self {
+        self.options.on_symlink = on_symlink;
+        self
+    }
+
+    /// Replace every `\` in targets with `/` before the rewrites. Off by
+    /// default, as a backslash is a valid character of Unix file names.
+    pub fn normalize_separators(mut self, normalize_separators: bool) -> Self {
+        self.options.normalize_separators = normalize_separators;
+        self
+    }
+
+    /// When a target does not exist, look up each missing component among the
+    /// entries of its directory ignoring case, and link to the real names if
+    /// every one of them matches exactly one entry.
+    pub fn ignore_case_targets(mut self, ignore_case_targets: bool) -> Self {
+        self.options.ignore_case_targets = ignore_case_targets;
+        self
+    }
+
+    /// Accept a target that does not exist, for trees restored in stages,
+    /// as long as it is valid UTF-8 without whitespace or control
+    /// characters, contains a `/` or a `.` and has no component longer than
+    /// `NAME_MAX`. Such a target is not flattened nor checked for being readable.
+    pub fn allow_missing_targets(mut self, allow_missing_targets: bool) -> Self {
+        self.options.allow_missing_targets = allow_missing_targets;
+        self
+    }
+
+    /// The options, or why the ones given do not go together
+    pub fn build(self) -> Result<ScanOptions, InvalidOptions> {
+        let mut options = self.options;
+        if options.len == 0 {
+            return Err(InvalidOptions(
+                "length limit must be at least 1, no stub is empty".to_owned(),
+            ));
+        }
+        if options.min_len > options.len {
+            return Err(InvalidOptions(format!(
+                "minimum length {} is beyond length limit {}",
+                options.min_len, options.len
+            )));
+        }
+        if options.from_xattr.as_deref() == Some("") {
+            return Err(InvalidOptions(
+                "extended attribute name must not be empty".to_owned(),
+            ));
+        }
+        if cfg!(not(unix)) && options.from_xattr.is_some() {
+            return Err(InvalidOptions(
+                "extended attributes are only supported on Unix".to_owned(),
+            ));
+        }
+        if let (Some(min_depth), Some(max_depth)) = (options.min_depth, options.max_depth) {
+            if min_depth > max_depth {
+                return Err(InvalidOptions(format!(
+                    "minimum depth {min_depth} is beyond maximum depth {max_depth}"
+                )));
+            }
+        }
+        if options
+            .prefix_maps
+            .iter()
+            .any(|(from, _)| from.as_os_str().is_empty())
+        {
+            return Err(InvalidOptions("prefix to map must not be empty".to_owned()));
+        }
+        if options.rewrites.iter().any(|(from, _)| from.is_empty()) {
+            return Err(InvalidOptions(
+                "text to rewrite must not be empty".to_owned(),
+            ));
+        }
+        if let Some(path) = &mut options.resume_from {
+            *path = absolute(path);
+        }
+        for root in &mut options.allowed_roots {
+            *root = root.canonicalize().map_err(|error| {
+                InvalidOptions(format!(
+                    "cannot use '{}' as allowed target root: {}",
+                    root.to_string_lossy(),
+                    error
+                ))
+            })?;
+        }
+        Ok(options)
+    }
+}
+
+/// Reason why a [`ScanOptionsBuilder`] refused to build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidOptions(pub String);
+
+impl fmt::Display for InvalidOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid options: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidOptions {}
+
+/// An entry met during a scan, together with what it looks like.
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    /// Number of directories between the scan root and this entry
+    pub depth: usize,
+    /// Metadata of the entry, if it could be read
+    pub metadata: Option<Metadata>,
+    pub classification: Classification,
+}
+
+#[derive(Debug)]
+pub enum Classification {
+    /// File content names an existing path, so it can become a symlink to it
+    Convertible { target: OsString },
+    /// File content names an existing path outside of the allowed roots
+    OutsideAllowedRoots { target: OsString },
+    /// File content has several non-empty lines, like a list of paths
+    MultiplePaths,
+    /// File content is a URL rather than a path
+    Url { target: OsString },
+    /// Target is a stub again, but following the chain of stubs failed
+    BrokenChain {
+        target: OsString,
+        reason: &'static str,
+    },
+    /// File content is too long to be a path
+    TargetTooLong { len: usize },
+    /// Target climbs up more directories than allowed before going down
+    RelativeTooDeep { target: OsString, depth: usize },
+    /// File content names a path that does not exist
+    TargetMissing { target: OsString },
+    /// File content names an existing path the current user cannot read
+    TargetUnreadable { target: OsString },
+    /// File content has control characters inside, a sign of corruption
+    ControlCharacters,
+    /// File content is not valid UTF-8 and does not name an existing path
+    NotUtf8,
+    /// File has no content at all
+    Empty,
+    /// File is longer than the length limit
+    TooBig { len: u64 },
+    /// File is shorter than the minimum length
+    TooSmall { len: u64 },
+    /// File content could not be read
+    Unreadable(io::Error),
+    /// File belongs to another user than the one asked for
+    OtherOwner { uid: u32 },
+    /// File is a git-LFS pointer, with the object file if it was fetched
+    LfsPointer {
+        oid: String,
+        object: Option<PathBuf>,
+    },
+    /// Directory about to be walked, with its number of entries unless streaming
+    Directory { entries: Option<usize> },
+    /// Entry is excluded from the scan, directories are not descended into
+    Excluded,
+    /// Entry already is a symlink, or one that would lead the walk in a loop
+    Symlink { target: PathBuf },
+    /// Entry is not a directory, a file or a symlink
+    Other,
+    /// Entry or directory could not be inspected
+    Error(io::Error),
+}
+
+/// Lazily walk `root` and classify every file, symlink and unreadable entry below it.
+///
+/// If `root` is a file it is the only candidate. Nothing gets modified.
+///
+/// Entries are classified from metadata read without following symlinks,
+/// when their directory is entered or, when streaming, when they are
+/// reached. A file turned into a symlink to a directory while the scan is
+/// running is therefore never descended into.
+pub fn scan<'a>(root: &Path, options: &'a ScanOptions) -> impl Iterator<Item = Candidate> + 'a {
+    scan_with(&RealFs, root, options)
+}
+
+/// [`scan`], listing directories and reading their entries' metadata through `ops`
+pub fn scan_with<'a>(
+    ops: &'a dyn FsOps,
+    root: &Path,
+    options: &'a ScanOptions,
+) -> impl Iterator<Item = Candidate> + 'a {
+    Scan {
+        ops,
+        root: Some(root.to_owned()),
+        root_dev: None,
+        base: root.to_owned(),
+        dirs: Vec::new(),
+        entered: HashSet::new(),
+        options,
+    }
+}
+
+/// Group convertible candidates whose paths differ only by letter case.
+///
+/// On a case-insensitive filesystem the symlinks for such candidates would
+/// land on the same name. Each group has at least two paths, in scan order.
+pub fn find_collisions(candidates: &[Candidate]) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut group_of = HashMap::new();
+    for candidate in candidates {
+        if !matches!(candidate.classification, Classification::Convertible { .. }) {
+            continue;
+        }
+        let key = candidate.path.to_string_lossy().to_lowercase();
+        let group = *group_of.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(candidate.path.clone());
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// Group convertible candidates by the canonical path their targets resolve to.
+///
+/// Only targets shared by at least two candidates are returned, sorted by
+/// target, each with its candidates in scan order.
+pub fn find_duplicates(candidates: &[Candidate]) -> Vec<(PathBuf, Vec<PathBuf>)> {
+    let mut groups: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for candidate in candidates {
+        if let Classification::Convertible { target } = &candidate.classification {
+            if let Some(resolved) = resolve_target(candidate.path.parent(), target) {
+                groups
+                    .entry(resolved)
+                    .or_default()
+                    .push(candidate.path.clone());
+            }
+        }
+    }
+    groups.retain(|_, paths| paths.len() > 1);
+    groups.into_iter().collect()
+}
+
+/// Classify a single file without walking anything.
+pub fn inspect(path: &Path, options: &ScanOptions) -> Candidate {
+    match fs::metadata(path) {
+        Ok(metadata) => classify_file(path.to_owned(), 0, metadata, options),
+        Err(error) => Candidate::error(path.to_owned(), 0, error),
+    }
+}
+
+/// A directory entry read ahead of processing, see [`Scan::enter`]
+type Entry = io::Result<(PathBuf, io::Result<Metadata>)>;
+
+/// Path of `entry`, if it could be read, to sort entries by
+fn entry_path(entry: &Entry) -> Option<&Path> {
+    entry.as_ref().ok().map(|(path, _)| path.as_path())
+}
+
+/// Remaining entries of a directory, read ahead or still to be read with `ops`
+enum Entries<'a> {
+    Buffered(vec::IntoIter<Entry>),
+    Streaming {
+        ops: &'a dyn FsOps,
+        dir: DirEntries<'a>,
+    },
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        match self {
+            Entries::Buffered(entries) => entries.next(),
+            Entries::Streaming { ops, dir } => dir.next().map(|path| {
+                path.map(|path| {
+                    let metadata = ops.metadata(&path);
+                    (path, metadata)
+                })
+            }),
+        }
+    }
+}
+
+/// A directory being walked, with its remaining entries
+struct Dir<'a> {
+    path: PathBuf,
+    entries: Entries<'a>,
+    depth: usize,
+    /// Rules of the `.gitignore` and `.ignore` files in this directory, if asked for
+    gitignore: Option<Gitignore>,
+    /// Files of this directory classified ahead, see [`ScanOptions::parallel`]
+    classified: HashMap<PathBuf, Classification>,
+}
+
+struct Scan<'a> {
+    ops: &'a dyn FsOps,
+    root: Option<PathBuf>,
+    /// Device of the root, which `one_file_system` keeps the walk on
+    root_dev: Option<u64>,
+    /// The root, which include and exclude globs are matched relative to
+    base: PathBuf,
+    /// Directories being walked, innermost last
+    dirs: Vec<Dir<'a>>,
+    /// Device and inode of every directory entered, when symlinks are followed
+    entered: HashSet<FileId>,
+    options: &'a ScanOptions,
+}
+
+impl<'a> Scan<'a> {
+    /// Queue a directory for walking, returning a candidate if it cannot be
+    /// opened or directories are reported
+    ///
+    /// Unless streaming, the directory is read completely right away so that
+    /// its handle gets closed before descending; keeping one open per level
+    /// runs out of file descriptors on deep trees.
+    fn enter(&mut self, path: PathBuf, depth: usize, metadata: Metadata) -> Option<Candidate> {
+        if self.options.one_file_system
+            && self
+                .root_dev
+                .is_some_and(|dev| dev != platform::device(&metadata))
+        {
+            return Some(Candidate {
+                path,
+                depth,
+                metadata: Some(metadata),
+                classification: Classification::Excluded,
+            });
+        }
+        let ops = self.ops;
+        match ops.read_dir(&path) {
+            Ok(dir) => {
+                let entries = if self.options.streaming {
+                    Entries::Streaming { ops, dir }
+                } else {
+                    let mut entries: Vec<Entry> = Entries::Streaming { ops, dir }.collect();
+                    if self.options.sorted {
+                        entries.sort_by(|a, b| entry_path(a).cmp(&entry_path(b)));
+                    }
+                    Entries::Buffered(entries.into_iter())
+                };
+                let gitignore = self.options.gitignore.then(|| ignore_rules(&path));
+                if self.options.on_symlink != OnSymlink::Skip {
+                    self.entered.insert(platform::file_id(&path, &metadata));
+                }
+                let reported = self.options.report_dirs.then(|| Candidate {
+                    path: path.clone(),
+                    depth,
+                    metadata: Some(metadata),
+                    classification: Classification::Directory {
+                        entries: match &entries {
+                            Entries::Buffered(entries) => Some(entries.len()),
+                            Entries::Streaming { .. } => None,
+                        },
+                    },
+                });
+                self.dirs.push(Dir {
+                    path,
+                    entries,
+                    depth,
+                    gitignore,
+                    classified: HashMap::new(),
+                });
+                if self.options.parallel {
+                    self.classify_ahead(depth + 1);
+                }
+                reported
+            }
+            Err(error) => Some(Candidate::error(path, depth, error)),
+        }
+    }
+
+    /// Classify the files of the innermost directory that the walk will
+    /// classify, in parallel, unless its entries are streamed
+    ///
+    /// The directory must be entered first, for its ignore rules to apply.
+    fn classify_ahead(&mut self, depth: usize) {
+        let Some(Dir {
+            entries: Entries::Buffered(entries),
+            ..
+        }) = self.dirs.last()
+        else {
+            return;
+        };
+        let classified = entries
+            .as_slice()
+            .par_iter()
+            .filter_map(|entry| match entry {
+                Ok((path, Ok(metadata))) if self.classifies(path, depth, metadata) => {
+                    Some((path.clone(), classify(path, metadata, self.options)))
+                }
+                _ => None,
+            })
+            .collect();
+        if let Some(dir) = self.dirs.last_mut() {
+            dir.classified = classified;
+        }
+    }
+
+    /// Whether the walk classifies the entry at `path` with `metadata`, a
+    /// regular file passing every filter
+    fn classifies(&self, path: &Path, depth: usize, metadata: &Metadata) -> bool {
+        metadata.is_file()
+            && !self.before_resume_point(path)
+            && !self.is_ignored(path, false)
+            && !self.is_excluded(path, false)
+            && self.wanted(depth, metadata)
+            && self
+                .options
+                .only_files
+                .as_ref()
+                .is_none_or(|files| files.contains(path))
+    }
+
+    /// Whether `path` is hidden and left out for it, or the innermost ignore
+    /// rule matching it ignores it
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.options.skip_hidden
+            && path
+                .file_name()
+                .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+        {
+            return true;
+        }
+        for gitignore in self
+            .dirs
+            .iter()
+            .rev()
+            .filter_map(|dir| dir.gitignore.as_ref())
+        {
+            match gitignore.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Whether `path` is left out by the include and exclude globs
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let matches = |glob: &GlobMatcher| {
+            if glob.glob().glob().contains('/') {
+                glob.is_match(path.strip_prefix(&self.base).unwrap_or(path))
+            } else {
+                path.file_name().is_some_and(|name| glob.is_match(name))
+            }
+        };
+        self.options.exclude.iter().any(matches)
+            || !is_dir
+                && !self.options.include.is_empty()
+                && !self.options.include.iter().any(matches)
+    }
+
+    /// Handle the symlink at `path` as [`OnSymlink`] asks, returning a candidate if there is one
+    fn symlink(&mut self, path: PathBuf, depth: usize, metadata: Metadata) -> Option<Candidate> {
+        let followed = match self.options.on_symlink {
+            OnSymlink::Skip => None,
+            OnSymlink::Follow => fs::metadata(&path)
+                .ok()
+                .map(|followed| (path.clone(), followed)),
+            OnSymlink::ConvertTarget => fs::canonicalize(&path)
+                .and_then(|real| Ok((real.clone(), fs::metadata(real)?)))
+                .ok(),
+        };
+
+        match followed {
+            Some((real, followed))
+                if followed.is_dir()
+                    && !self.entered.contains(&platform::file_id(&real, &followed)) =>
+            {
+                if !self.descends_below(depth) {
+                    return None;
+                }
+                return self.enter(real, depth, followed);
+            }
+            Some((real, followed))
+                if followed.is_file() && self.options.on_symlink == OnSymlink::ConvertTarget =>
+            {
+                return self.file(real, depth, followed, None);
+            }
+            _ => {}
+        }
+
+        let target = fs::read_link(&path).unwrap_or_default();
+        Some(Candidate {
+            path,
+            depth,
+            metadata: Some(metadata),
+            classification: Classification::Symlink { target },
+        })
+    }
+
+    /// Whether `path` and everything below it sorts before the resume point
+    fn before_resume_point(&self, path: &Path) -> bool {
+        match &self.options.resume_from {
+            Some(resume_from) => {
+                let path = absolute(path);
+                path < *resume_from && !resume_from.starts_with(&path)
+            }
+            None => false,
+        }
+    }
+
+    /// Classify the regular file at `path`, unless it is shallower than the
+    /// minimum depth or not modified recently enough, reusing `classified` if given
+    fn file(
+        &self,
+        path: PathBuf,
+        depth: usize,
+        metadata: Metadata,
+        classified: Option<Classification>,
+    ) -> Option<Candidate> {
+        if !self.wanted(depth, &metadata) {
+            return None;
+        }
+        if self
+            .options
+            .only_files
+            .as_ref()
+            .is_some_and(|files| !files.contains(&path))
+        {
+            return Some(Candidate {
+                path,
+                depth,
+                metadata: Some(metadata),
+                classification: Classification::Excluded,
+            });
+        }
+        let classification = classified.unwrap_or_else(|| classify(&path, &metadata, self.options));
+        Some(Candidate {
+            path,
+            depth,
+            metadata: Some(metadata),
+            classification,
+        })
+    }
+
+    /// Whether a file at `depth` with `metadata` passes the depth and age filters
+    fn wanted(&self, depth: usize, metadata: &Metadata) -> bool {
+        if self
+            .options
+            .min_depth
+            .is_some_and(|min_depth| depth < min_depth)
+        {
+            return false;
+        }
+        if let Some(since) = self.options.modified_since {
+            if metadata.modified().is_ok_and(|modified| modified <= since) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn descends_below(&self, depth: usize) -> bool {
+        self.options
+            .max_depth
+            .is_none_or(|max_depth| depth < max_depth)
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Candidate;
+
+    fn next(&mut self) -> Option<Candidate> {
+        if let Some(root) = self.root.take() {
+            match fs::metadata(&root) {
+                Ok(metadata) if metadata.is_dir() => {
+                    self.root_dev = Some(platform::device(&metadata));
+                    if let Some(candidate) = self.enter(root, 0, metadata) {
+                        return Some(candidate);
+                    }
+                }
+                Ok(metadata) if metadata.is_file() => return self.file(root, 0, metadata, None),
+                Ok(metadata) => return Some(Candidate::other(root, 0, metadata)),
+                Err(error) => return Some(Candidate::error(root, 0, error)),
+            }
+        }
+
+        loop {
+            let dir = self.dirs.last_mut()?;
+            let depth = dir.depth + 1;
+            let (path, metadata) = match dir.entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(error)) => return Some(Candidate::error(dir.path.clone(), depth, error)),
+                None => {
+                    self.dirs.pop();
+                    continue;
+                }
+            };
+
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(error) => return Some(Candidate::error(path, depth, error)),
+            };
+
+            if self.before_resume_point(&path) {
+                continue;
+            }
+
+            if self.is_ignored(&path, metadata.is_dir())
+                || self.is_excluded(&path, metadata.is_dir())
+            {
+                return Some(Candidate {
+                    path,
+                    depth,
+                    metadata: Some(metadata),
+                    classification: Classification::Excluded,
+                });
+            }
+
+            if metadata.is_dir() {
+                if self.descends_below(depth) {
+                    if let Some(candidate) = self.enter(path, depth, metadata) {
+                        return Some(candidate);
+                    }
+                }
+            } else if metadata.is_file() {
+                let classified = self
+                    .dirs
+                    .last_mut()
+                    .and_then(|dir| dir.classified.remove(&path));
+                if let Some(candidate) = self.file(path, depth, metadata, classified) {
+                    return Some(candidate);
+                }
+            } else if metadata.is_symlink() {
+                if let Some(candidate) = self.symlink(path, depth, metadata) {
+                    return Some(candidate);
+                }
+            } else {
+                return Some(Candidate::other(path, depth, metadata));
+            }
+        }
+    }
+}
+
+/// Ignore rules of the dir at `path`, `.ignore` ones winning over `.gitignore` ones
+///
+/// Missing or unreadable files add no rules.
+fn ignore_rules(path: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(path);
+    for name in [".gitignore", ".ignore"] {
+        let file = path.join(name);
+        if file.is_file() {
+            builder.add(file);
+        }
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+impl Candidate {
+    fn error(path: PathBuf, depth: usize, error: io::Error) -> Self {
+        Candidate {
+            path,
+            depth,
+            metadata: None,
+            classification: Classification::Error(error),
+        }
+    }
+
+    fn other(path: PathBuf, depth: usize, metadata: Metadata) -> Self {
+        Candidate {
+            path,
+            depth,
+            metadata: Some(metadata),
+            classification: Classification::Other,
+        }
+    }
+}
+
+fn classify_file(
+    path: PathBuf,
+    depth: usize,
+    metadata: Metadata,
+    options: &ScanOptions,
+) -> Candidate {
+    let classification = classify(&path, &metadata, options);
+    Candidate {
+        path,
+        depth,
+        metadata: Some(metadata),
+        classification,
+    }
+}
+
+fn classify(path: &Path, metadata: &Metadata, options: &ScanOptions) -> Classification {
+    if let Some(uid) =
+        platform::owner(metadata).filter(|&uid| options.owner.is_some_and(|owner| owner != uid))
+    {
+        return Classification::OtherOwner { uid };
+    }
+    match read_target(path, metadata, options) {
+        Ok(target) => classify_target(path, target, options),
+        Err(classification) => classification,
+    }
+}
+
+/// Check `target` read from the stub at `path` against everything it must point to
+pub fn classify_target(path: &Path, target: OsString, options: &ScanOptions) -> Classification {
+    if target.len() > options.max_target_len {
+        return Classification::TargetTooLong { len: target.len() };
+    }
+
+    if options.strict_path
+        && target
+            .as_encoded_bytes()
+            .trim_ascii()
+            .iter()
+            .any(u8::is_ascii_control)
+    {
+        return Classification::ControlCharacters;
+    }
+
+    let mut missing = false;
+    let target = if link_target_exists(path.parent(), &target) {
+        target
+    } else {
+        let found = options
+            .ignore_case_targets
+            .then(|| find_ignoring_case(path.parent(), &target))
+            .flatten();
+        match found {
+            Some(found) => found,
+            None if options.allow_missing_targets && looks_like_path(&target) => {
+                missing = true;
+                target
+            }
+            None if target.to_str().is_none() => return Classification::NotUtf8,
+            None => return Classification::TargetMissing { target },
+        }
+    };
+
+    let target = if options.flatten && !missing {
+        match flatten(path, target, options) {
+            Ok(target) => target,
+            Err((target, reason)) => return Classification::BrokenChain { target, reason },
+        }
+    } else {
+        target
+    };
+
+    let depth = paths::leading_parent_dirs(Path::new(&target));
+    if options.max_relative_depth.is_some_and(|max| depth > max) {
+        return Classification::RelativeTooDeep { target, depth };
+    }
+
+    if options.require_readable && !missing && !link_target_readable(path.parent(), &target) {
+        return Classification::TargetUnreadable { target };
+    }
+
+    if !is_under_allowed_roots(path.parent(), &target, &options.allowed_roots) {
+        Classification::OutsideAllowedRoots { target }
+    } else {
+        Classification::Convertible { target }
+    }
+}
+
+/// Whether the missing `target` is enough like a path to link to it anyway
+///
+/// Bytes that are not UTF-8 are accepted, as names in other encodings are.
+fn looks_like_path(target: &OsStr) -> bool {
+    let target = target.as_encoded_bytes();
+    !target.is_empty()
+        && !target.utf8_chunks().any(|chunk| {
+            chunk
+                .valid()
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+        })
+        && (target.contains(&b'/') || target.contains(&b'.'))
+        && target
+            .split(|&byte| byte == b'/')
+            .all(|component| component.len() <= platform::NAME_MAX)
+}
+
+/// The target a file stores, or why it does not look like a stub at all
+fn read_target(
+    path: &Path,
+    metadata: &Metadata,
+    options: &ScanOptions,
+) -> Result<OsString, Classification> {
+    let target = match xattr_target(path, options) {
+        Some(target) => target,
+        None => {
+            if metadata.len() == 0 {
+                return Err(Classification::Empty);
+            }
+            if metadata.len() > options.len {
+                return Err(Classification::TooBig {
+                    len: metadata.len(),
+                });
+            }
+            if metadata.len() < options.min_len {
+                return Err(Classification::TooSmall {
+                    len: metadata.len(),
+                });
+            }
+
+            let content = read_same_file(path, metadata).map_err(Classification::Unreadable)?;
+            if let Some(pointed) = pointer_target(path, &content, options) {
+                return pointed;
+            }
+            return target_from_content(content, options);
+        }
+    };
+    parse_target(target, options)
+}
+
+/// The target of the stub at `path` if its `content` is a pointer `options` asks to recognize
+fn pointer_target(
+    path: &Path,
+    content: &[u8],
+    options: &ScanOptions,
+) -> Option<Result<OsString, Classification>> {
+    if options.lfs {
+        if let Some(oid) = pointers::lfs_oid(content) {
+            let object = pointers::lfs_object(path, &oid);
+            return Some(Err(Classification::LfsPointer { oid, object }));
+        }
+    }
+    if options.annex {
+        let key = pointers::annex_key(content)?;
+        return Some(match pointers::annex_object(path, &key) {
+            Some(object) => {
+                let dir = absolute(path.parent().unwrap_or(Path::new("")));
+                Ok(paths::make_relative(&dir, &object).into_os_string())
+            }
+            None => Err(Classification::TargetMissing {
+                target: platform::os_str(content.trim_ascii_end()).into_owned(),
+            }),
+        });
+    }
+    None
+}
+
+/// Content of the regular file at `path`, failing unless it is still the file of `metadata`
+///
+/// The file is opened without following symlinks and checked once open, so a
+/// file swapped in meanwhile is never read by mistake.
+pub fn read_same_file(path: &Path, metadata: &Metadata) -> io::Result<Vec<u8>> {
+    let mut file = platform::open_no_follow(path)?;
+    let opened = file.metadata()?;
+    if !opened.is_file() || !platform::same_file(&opened, metadata) {
+        return Err(io::Error::other(
+            "replaced by another file while being read",
+        ));
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// The target stored as file `content`, decoded as asked for in `options`
+pub fn target_from_content(
+    content: Vec<u8>,
+    options: &ScanOptions,
+) -> Result<OsString, Classification> {
+    if let Some(target) = decode_foreign_link(&content) {
+        return parse_target(target, options);
+    }
+    let target = match options.encoding {
+        Some(encoding) => decode(&content, encoding).ok_or_else(|| {
+            Classification::Unreadable(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("content is not valid {}", encoding.name()),
+            ))
+        })?,
+        None => platform::os_string(content),
+    };
+    parse_target(target, options)
+}
+
+/// Clean up raw `target` text, or tell why it does not look like a path
+fn parse_target(target: OsString, options: &ScanOptions) -> Result<OsString, Classification> {
+    let target = match target.as_encoded_bytes().strip_suffix(b"\n") {
+        Some(stripped) if options.strip_trailing_newline => platform::os_str(stripped).into_owned(),
+        _ => target,
+    };
+    let target = match options.strip_comment {
+        Some(marker) => strip_comment(&target, marker),
+        None => target,
+    };
+    let target = match &options.trim_chars {
+        Some(chars) => trim_chars(&target, chars),
+        None => target,
+    };
+
+    let mut lines = target
+        .as_encoded_bytes()
+        .split(|&byte| byte == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .filter(|line| !line.is_empty());
+    let target = match (lines.next(), lines.next()) {
+        (Some(first), Some(_)) if options.first_line => platform::os_str(first).into_owned(),
+        (Some(_), Some(_)) => return Err(Classification::MultiplePaths),
+        _ => target,
+    };
+
+    let target = match url_scheme(&target) {
+        Some("file") if options.strip_file_url => {
+            platform::os_str(&target.as_encoded_bytes().trim_ascii()["file://".len()..])
+                .into_owned()
+        }
+        Some(_) => return Err(Classification::Url { target }),
+        None => target,
+    };
+
+    let target = if options.normalize_separators {
+        normalize_separators(&target)
+    } else {
+        target
+    };
+    let target = map_prefix(&target, &options.prefix_maps);
+    let target = rewrite(&target, &options.rewrites);
+    Ok(rewrite_regex(&target, &options.regex_rewrites))
+}
+
+/// Follow `target` of the stub at `path` through further stubs to the final target
+///
+/// The result is relative to the directory of `path` unless the first
+/// target is absolute. On failure the last target reached is returned
+/// along with the reason.
+fn flatten(
+    path: &Path,
+    target: OsString,
+    options: &ScanOptions,
+) -> Result<OsString, (OsString, &'static str)> {
+    let start_dir = absolute(path.parent().unwrap_or(Path::new("")));
+    let mut visited = HashSet::from([absolute(path)]);
+    let mut hop = absolute(&start_dir.join(&target));
+    let mut link = target.clone();
+
+    loop {
+        let next = match fs::metadata(&hop) {
+            Ok(metadata) if metadata.is_file() => match read_target(&hop, &metadata, options) {
+                Ok(next) if link_target_exists(hop.parent(), &next) => next,
+                _ => break,
+            },
+            _ => break,
+        };
+        if !visited.insert(hop.clone()) {
+            return Err((link, "stub chain loops back on itself"));
+        }
+        if visited.len() > MAX_CHAIN_LEN {
+            return Err((link, "stub chain is too long"));
+        }
+        hop = absolute(&hop.parent().unwrap_or(Path::new("")).join(&next));
+        link = next;
+    }
+
+    if visited.len() == 1 {
+        Ok(target)
+    } else if Path::new(&target).is_absolute() {
+        Ok(hop.into_os_string())
+    } else {
+        Ok(paths::make_relative(&start_dir, &hop).into_os_string())
+    }
+}
+
+/// `path` made absolute and normalized, without touching the filesystem
+fn absolute(path: &Path) -> PathBuf {
+    paths::normalize(&std::path::absolute(path).unwrap_or_else(|_| path.to_owned()))
+}
+
+/// Whether the canonical form of `link` lies under one of the (canonical) `roots`
+fn is_under_allowed_roots(path: Option<&Path>, link: &OsStr, roots: &[PathBuf]) -> bool {
+    if roots.is_empty() {
+        return true;
+    }
+    match resolve_target(path, link) {
+        Some(target) => roots.iter().any(|root| target.starts_with(root)),
+        None => false,
+    }
+}
+
+/// Canonical path of `link` resolved from the directory `path`, if it exists
+fn resolve_target(path: Option<&Path>, link: &OsStr) -> Option<PathBuf> {
+    let target = match path {
+        Some(path) => path.join(link),
+        None => PathBuf::from(link),
+    };
+    target.canonicalize().ok()
+}
+
+/// `link` with the names of its missing components replaced by the only entry
+/// of their directory differing just by case, if it then exists
+fn find_ignoring_case(path: Option<&Path>, link: &OsStr) -> Option<OsString> {
+    let base = path.unwrap_or(Path::new(""));
+    let mut found = PathBuf::new();
+    for component in Path::new(link).components() {
+        let Component::Normal(name) = component else {
+            found.push(component);
+            continue;
+        };
+        let dir = base.join(&found);
+        if fs::symlink_metadata(dir.join(name)).is_ok() {
+            found.push(name);
+            continue;
+        }
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &dir
+        };
+        let mut matches = fs::read_dir(dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name())
+            .filter(|entry| same_ignoring_case(entry, name));
+        match (matches.next(), matches.next()) {
+            (Some(entry), None) => found.push(entry),
+            _ => return None,
+        }
+    }
+    link_target_exists(path, found.as_os_str()).then(|| found.into_os_string())
+}
+
+/// Whether names `a` and `b` differ at most by case, in ASCII only if either is not UTF-8
+fn same_ignoring_case(a: &OsStr, b: &OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => a
+            .as_encoded_bytes()
+            .eq_ignore_ascii_case(b.as_encoded_bytes()),
+    }
+}
+
+/// Whether `link` resolved from the directory `path` can be opened for reading
+fn link_target_readable(path: Option<&Path>, link: &OsStr) -> bool {
+    let target = match path {
+        Some(path) => path.join(link),
+        None => PathBuf::from(link),
+    };
+    fs::File::open(target).is_ok()
+}
+
+/// Scheme of `link` if it looks like a URL such as `https://host/path`
+fn url_scheme(link: &OsStr) -> Option<&str> {
+    let link = link.as_encoded_bytes().trim_ascii_start();
+    let end = link.windows(3).position(|window| window == b"://")?;
+    let scheme = &link[..end];
+    let valid = scheme.first()?.is_ascii_alphabetic()
+        && scheme
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'+' | b'-' | b'.'));
+    valid.then(|| str::from_utf8(scheme).ok()).flatten()
+}
+
+/// Target stored in the extended attribute requested with `from_xattr`
+#[cfg(unix)]
+fn xattr_target(path: &Path, options: &ScanOptions) -> Option<OsString> {
+    let name = options.from_xattr.as_ref()?;
+    let value = xattr::get(path, name).ok()??;
+    Some(platform::os_string(value))
+}
+
+/// Never any target, as only Unix has extended attributes
+#[cfg(not(unix))]
+fn xattr_target(_path: &Path, _options: &ScanOptions) -> Option<OsString> {
+    None
+}
+
+/// `link` without any of `chars` or ASCII whitespace at either end
+pub fn trim_chars(link: &OsStr, chars: &str) -> OsString {
+    let mut buf = [0; 4];
+    let encoded: Vec<Vec<u8>> = chars
+        .chars()
+        .map(|c| c.encode_utf8(&mut buf).as_bytes().to_vec())
+        .collect();
+    let mut link = link.as_encoded_bytes().trim_ascii();
+    loop {
+        let before = link.len();
+        for encoded in &encoded {
+            link = link.strip_prefix(&encoded[..]).unwrap_or(link);
+            link = link.strip_suffix(&encoded[..]).unwrap_or(link);
+        }
+        link = link.trim_ascii();
+        if link.len() == before {
+            return platform::os_str(link).into_owned();
+        }
+    }
+}
+
+/// Cut everything from the first `marker` to the end of its line and trim the rest
+///
+/// Works on the raw bytes, so targets that are not valid UTF-8 survive intact.
+pub fn strip_comment(link: &OsStr, marker: char) -> OsString {
+    let link = link.as_encoded_bytes();
+    let mut buf = [0; 4];
+    let marker = marker.encode_utf8(&mut buf).as_bytes();
+    let start = link
+        .windows(marker.len())
+        .position(|window| window == marker);
+    let stripped = match start {
+        Some(start) => {
+            let end = link[start..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(link.len(), |len| start + len);
+            [&link[..start], &link[end..]].concat()
+        }
+        None => link.to_vec(),
+    };
+    platform::os_string(stripped.trim_ascii().to_vec())
+}
+
+/// Target of a symlink file written by Cygwin (`!<symlink>`), or by Interix
+/// and MSYS (`IntxLNK`), or `None` if `content` is none of these
+///
+/// Such targets are UTF-16 with a NUL terminator, except for old Cygwin
+/// links holding the target bytes as they are.
+pub fn decode_foreign_link(content: &[u8]) -> Option<OsString> {
+    let target = if let Some(rest) = content.strip_prefix(b"!<symlink>") {
+        match rest.strip_prefix(b"\xff\xfe") {
+            Some(utf16) => decode_utf16_le(utf16)?,
+            None => rest.split(|&byte| byte == 0).next()?.to_vec(),
+        }
+    } else {
+        decode_utf16_le(content.strip_prefix(b"IntxLNK\x01")?)?
+    };
+    Some(platform::os_string(target))
+}
+
+/// UTF-8 bytes of the little endian UTF-16 `text`, up to a NUL
+fn decode_utf16_le(text: &[u8]) -> Option<Vec<u8>> {
+    let units = text
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|&unit| unit != 0);
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+        .map(String::into_bytes)
+}
+
+/// `content` decoded from `encoding`, or `None` if it is malformed
+pub fn decode(content: &[u8], encoding: &'static Encoding) -> Option<OsString> {
+    encoding
+        .decode_without_bom_handling_and_without_replacement(content)
+        .map(|text| text.into_owned().into())
+}
+
+/// `link` with every `\` replaced by `/`
+pub fn normalize_separators(link: &OsStr) -> OsString {
+    let link = link.as_encoded_bytes().iter();
+    platform::os_string(
+        link.map(|&byte| if byte == b'\\' { b'/' } else { byte })
+            .collect(),
+    )
+}
+
+/// Replace the leading path `from` of `link` with `to` for the first `(from, to)` of `maps` it starts with
+///
+/// Whole components are compared, `/home/alice` maps `/home/alice/notes` and
+/// `/home/alice` itself, but not `/home/alicia`.
+pub fn map_prefix(link: &OsStr, maps: &[(PathBuf, PathBuf)]) -> OsString {
+    for (from, to) in maps {
+        if let Ok(rest) = Path::new(link).strip_prefix(from) {
+            return if rest.as_os_str().is_empty() {
+                to.clone().into_os_string()
+            } else {
+                to.join(rest).into_os_string()
+            };
+        }
+    }
+    link.to_owned()
+}
+
+/// Apply each `(from, to)` of `rewrites` in turn, replacing every occurrence of `from`
+pub fn rewrite(link: &OsStr, rewrites: &[(OsString, OsString)]) -> OsString {
+    let mut link = link.as_encoded_bytes().to_vec();
+    for (from, to) in rewrites {
+        let (from, to) = (from.as_encoded_bytes(), to.as_encoded_bytes());
+        let mut rewritten = Vec::with_capacity(link.len());
+        let mut rest = &link[..];
+        while let Some(start) = rest.windows(from.len()).position(|window| window == from) {
+            rewritten.extend_from_slice(&rest[..start]);
+            rewritten.extend_from_slice(to);
+            rest = &rest[start + from.len()..];
+        }
+        rewritten.extend_from_slice(rest);
+        link = rewritten;
+    }
+    platform::os_string(link)
+}
+
+/// Apply each `(pattern, replacement)` of `rewrites` in turn to every match
+pub fn rewrite_regex(link: &OsStr, rewrites: &[(Regex, OsString)]) -> OsString {
+    let mut link = link.as_encoded_bytes().to_vec();
+    for (pattern, replacement) in rewrites {
+        link = pattern
+            .replace_all(&link, replacement.as_encoded_bytes())
+            .into_owned();
+    }
+    platform::os_string(link)
+}
+
+/// Whether `link` names an existing path when resolved from the directory `path`
+pub fn link_target_exists(path: Option<&Path>, link: &OsStr) -> bool {
+    match path {
+        Some(path) => path.join(link).exists(),
+        None => Path::new(link).to_owned().exists(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fs_ops::MemFs;
+
+    #[test]
+    fn builds_valid_options() {
+        let options = ScanOptions::builder()
+            .len(1024)
+            .min_len(2)
+            .min_depth(1)
+            .max_depth(3)
+            .exclude(Glob::new("*.md").unwrap())
+            .map_prefix("/old", "/new")
+            .build()
+            .unwrap();
+        assert_eq!(options.len, 1024);
+        assert_eq!(options.min_len, 2);
+        assert_eq!((options.min_depth, options.max_depth), (Some(1), Some(3)));
+        assert_eq!(options.exclude.len(), 1);
+    }
+
+    #[test]
+    fn refuses_invalid_options() {
+        let invalid = [
+            ScanOptions::builder().len(0),
+            ScanOptions::builder().len(10).min_len(11),
+            ScanOptions::builder().from_xattr(""),
+            ScanOptions::builder().min_depth(3).max_depth(2),
+            ScanOptions::builder().map_prefix("", "/new"),
+            ScanOptions::builder().rewrite("", "x"),
+            ScanOptions::builder().target_must_be_under("/nonexistent/restore-symlink"),
+        ];
+        for builder in invalid {
+            assert!(builder.clone().build().is_err(), "{builder:?} was accepted");
+        }
+    }
+
+    /// A tree with a stub at every level down to `a/b`, a file that is no
+    /// stub and the target they name
+    fn fixture_tree() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("a/b")).unwrap();
+        std::fs::write(tmp.path().join("target"), "first\nsecond").unwrap();
+        std::fs::write(tmp.path().join("stub"), "target").unwrap();
+        std::fs::write(tmp.path().join("a/stub"), "../target").unwrap();
+        std::fs::write(tmp.path().join("a/b/stub"), "../../target").unwrap();
+        tmp
+    }
+
+    /// Paths below `root` yielded by a scan with `options`, with what they look like
+    fn scanned(root: &Path, options: &ScanOptions) -> Vec<(PathBuf, String)> {
+        let mut scanned: Vec<_> = scan(root, options)
+            .map(|candidate| {
+                let kind = match candidate.classification {
+                    Classification::Convertible { target } => {
+                        format!("-> {}", target.to_string_lossy())
+                    }
+                    Classification::MultiplePaths => "multiple paths".to_owned(),
+                    other => format!("{other:?}"),
+                };
+                let path = candidate.path.strip_prefix(root).unwrap().to_owned();
+                (path, kind)
+            })
+            .collect();
+        scanned.sort();
+        scanned
+    }
+
+    #[test]
+    fn yields_every_file_of_a_tree_classified() {
+        let tmp = fixture_tree();
+
+        let scanned = scanned(tmp.path(), &ScanOptions::default());
+
+        let expected = [
+            ("a/b/stub", "-> ../../target"),
+            ("a/stub", "-> ../target"),
+            ("stub", "-> target"),
+            ("target", "multiple paths"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(path, kind)| (PathBuf::from(path), kind.to_string()))
+            .collect();
+        assert_eq!(scanned, expected);
+    }
+
+    #[test]
+    fn stops_descending_at_the_max_depth() {
+        let tmp = fixture_tree();
+
+        for (max_depth, deepest) in [(1, 1), (2, 2), (3, 3)] {
+            let options = ScanOptions::builder().max_depth(max_depth).build().unwrap();
+            let depths: Vec<usize> = scan(tmp.path(), &options)
+                .map(|candidate| candidate.depth)
+                .collect();
+            assert_eq!(
+                depths.iter().max(),
+                Some(&deepest),
+                "--max-depth {max_depth}"
+            );
+        }
+        let options = ScanOptions::builder().max_depth(1).build().unwrap();
+        let paths: Vec<_> = scanned(tmp.path(), &options)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, [Path::new("stub"), Path::new("target")]);
+    }
+
+    #[test]
+    fn excludes_entries_gitignored_or_matching_a_glob() {
+        let tmp = fixture_tree();
+        std::fs::create_dir(tmp.path().join("target-dir")).unwrap();
+        std::fs::write(tmp.path().join(".gitignore"), "/target-dir/\nb/\n").unwrap();
+        std::fs::write(tmp.path().join("target-dir/stub"), "../target").unwrap();
+        std::fs::write(tmp.path().join("notes.md"), "target").unwrap();
+        let options = ScanOptions::builder()
+            .exclude(Glob::new("*.md").unwrap())
+            .build()
+            .unwrap();
+
+        // Excluded entries are yielded as such, without descending into dirs
+        let excluded = |options: &ScanOptions| -> Vec<PathBuf> {
+            scanned(tmp.path(), options)
+                .into_iter()
+                .filter(|(_, kind)| kind == "Excluded")
+                .map(|(path, _)| path)
+                .collect()
+        };
+        assert_eq!(
+            excluded(&options),
+            [
+                Path::new(".gitignore"),
+                Path::new("a/b"),
+                Path::new("notes.md"),
+                Path::new("target-dir")
+            ]
+        );
+        let options = ScanOptions::builder().gitignore(false).build().unwrap();
+        assert_eq!(excluded(&options), [Path::new(".gitignore")]);
+    }
+
+    #[test]
+    fn resumes_from_the_path_given() {
+        let tmp = fixture_tree();
+        let options = ScanOptions::builder()
+            .sorted(true)
+            .resume_from(tmp.path().join("a/stub"))
+            .build()
+            .unwrap();
+
+        let paths: Vec<_> = scanned(tmp.path(), &options)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(
+            paths,
+            [Path::new("a/stub"), Path::new("stub"), Path::new("target")]
+        );
+    }
+
+    #[test]
+    fn leaves_out_files_above_the_min_depth() {
+        let tmp = fixture_tree();
+
+        let options = ScanOptions::builder().min_depth(2).build().unwrap();
+        let paths: Vec<_> = scanned(tmp.path(), &options)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, [Path::new("a/b/stub"), Path::new("a/stub")]);
+    }
+
+    #[test]
+    fn leaves_out_files_not_modified_since_the_time_given() {
+        let tmp = fixture_tree();
+        let day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+        for old in ["stub", "a/stub"] {
+            std::fs::File::options()
+                .write(true)
+                .open(tmp.path().join(old))
+                .unwrap()
+                .set_modified(day_ago)
+                .unwrap();
+        }
+
+        let options = ScanOptions::builder()
+            .modified_since(SystemTime::now() - Duration::from_secs(60 * 60))
+            .build()
+            .unwrap();
+        let paths: Vec<_> = scanned(tmp.path(), &options)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, [Path::new("a/b/stub"), Path::new("target")]);
+    }
+
+    /// What a stub holding `content` looks like, in a dir also holding the
+    /// file `target` and the empty dir `dir`
+    fn classified(content: &[u8], options: &ScanOptions) -> Classification {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("target"), "content").unwrap();
+        std::fs::create_dir(tmp.path().join("dir")).unwrap();
+        let stub = tmp.path().join("stub");
+        std::fs::write(&stub, content).unwrap();
+        inspect(&stub, options).classification
+    }
+
+    /// Target a stub holding `content` is linked to, see [`classified`]
+    fn linked_to(content: &[u8], options: &ScanOptions) -> Option<OsString> {
+        match classified(content, options) {
+            Classification::Convertible { target } => Some(target),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn strips_a_trailing_comment() {
+        let options = ScanOptions::builder().strip_comment('#').build().unwrap();
+
+        for content in ["target # restored", "target#", "target"] {
+            assert_eq!(
+                linked_to(content.as_bytes(), &options),
+                Some("target".into()),
+                "{content}"
+            );
+        }
+        let options = ScanOptions::builder()
+            .strip_comment('#')
+            .first_line(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            linked_to(b"target # first\nsecond", &options),
+            Some("target".into())
+        );
+        assert_eq!(
+            linked_to(b"target # restored", &ScanOptions::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_urls_but_file_urls_on_request() {
+        for content in [
+            "http://example.com/target",
+            "https://example.com",
+            "ftp://host/x",
+        ] {
+            assert!(
+                matches!(
+                    classified(content.as_bytes(), &ScanOptions::default()),
+                    Classification::Url { .. }
+                ),
+                "{content}"
+            );
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("target");
+        std::fs::write(&target, "content").unwrap();
+        let stub = tmp.path().join("stub");
+        std::fs::write(&stub, format!("file://{}", target.display())).unwrap();
+        assert!(matches!(
+            inspect(&stub, &ScanOptions::default()).classification,
+            Classification::Url { .. }
+        ));
+        let options = ScanOptions::builder().strip_file_url(true).build().unwrap();
+        assert!(matches!(
+            inspect(&stub, &options).classification,
+            Classification::Convertible { target: linked } if linked == target.as_os_str()
+        ));
+    }
+
+    #[test]
+    fn flattens_chains_of_stubs_and_rejects_loops() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("a/b")).unwrap();
+        std::fs::write(tmp.path().join("target"), "content").unwrap();
+        std::fs::write(tmp.path().join("stub"), "a/first").unwrap();
+        std::fs::write(tmp.path().join("a/first"), "b/second").unwrap();
+        std::fs::write(tmp.path().join("a/b/second"), "../../target").unwrap();
+        std::fs::write(tmp.path().join("loop"), "a/b/there").unwrap();
+        std::fs::write(tmp.path().join("a/b/there"), "back").unwrap();
+        std::fs::write(tmp.path().join("a/b/back"), "there").unwrap();
+        let options = ScanOptions::builder().flatten(true).build().unwrap();
+
+        assert!(matches!(
+            inspect(&tmp.path().join("stub"), &options).classification,
+            Classification::Convertible { target } if target == "target"
+        ));
+        assert!(matches!(
+            inspect(&tmp.path().join("stub"), &ScanOptions::default()).classification,
+            Classification::Convertible { target } if target == "a/first"
+        ));
+        assert!(matches!(
+            inspect(&tmp.path().join("loop"), &options).classification,
+            Classification::BrokenChain { reason, .. } if reason.contains("loops")
+        ));
+    }
+
+    #[test]
+    fn rejects_targets_longer_than_the_limit() {
+        let long = "a/".repeat(2500);
+        let options = ScanOptions::builder().len(8192).build().unwrap();
+        assert!(matches!(
+            classified(long.as_bytes(), &options),
+            Classification::TargetTooLong { len: 5000 }
+        ));
+
+        let options = ScanOptions::builder().max_target_len(5).build().unwrap();
+        assert!(matches!(
+            classified(b"target", &options),
+            Classification::TargetTooLong { len: 6 }
+        ));
+        assert_eq!(linked_to(b"dir", &options), Some("dir".into()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skips_targets_that_cannot_be_read_on_request() {
+        use std::os::unix::{fs::PermissionsExt, net::UnixListener};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let locked = tmp.path().join("locked");
+        std::fs::write(&locked, "content").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Sockets cannot be opened even by root, who reads the locked file regardless
+        let _listener = UnixListener::bind(tmp.path().join("socket")).unwrap();
+        let options = ScanOptions::builder()
+            .require_readable(true)
+            .build()
+            .unwrap();
+        let inspected = |target: &str, options: &ScanOptions| {
+            let stub = tmp.path().join("stub");
+            std::fs::write(&stub, target).unwrap();
+            inspect(&stub, options).classification
+        };
+
+        assert!(matches!(
+            inspected("socket", &options),
+            Classification::TargetUnreadable { .. }
+        ));
+        assert!(matches!(
+            inspected("socket", &ScanOptions::default()),
+            Classification::Convertible { .. }
+        ));
+        if std::fs::File::open(&locked).is_err() {
+            assert!(matches!(
+                inspected("locked", &options),
+                Classification::TargetUnreadable { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn rewrites_every_occurrence_in_order() {
+        let rewrites = |pairs: &[(&str, &str)]| -> Vec<(OsString, OsString)> {
+            pairs
+                .iter()
+                .map(|(from, to)| (from.into(), to.into()))
+                .collect()
+        };
+        let link = OsStr::new("/old/root/old/file");
+
+        assert_eq!(
+            rewrite(link, &rewrites(&[("/old/root", "/new/root")])),
+            "/new/root/old/file"
+        );
+        assert_eq!(
+            rewrite(link, &rewrites(&[("old", "mid"), ("mid", "new")])),
+            "/new/root/new/file"
+        );
+        assert_eq!(rewrite(link, &rewrites(&[("absent", "x")])), link);
+
+        let options = ScanOptions::builder()
+            .rewrite("old", "target")
+            .build()
+            .unwrap();
+        assert_eq!(linked_to(b"old", &options), Some("target".into()));
+    }
+
+    #[test]
+    fn rewrites_regex_matches_with_capture_groups_after_literals() {
+        let pattern = Regex::new("^/mnt/(\\w+)/v1/").unwrap();
+        assert_eq!(
+            rewrite_regex(
+                OsStr::new("/mnt/data/v1/file"),
+                &[(pattern, "/srv/$1/".into())]
+            ),
+            "/srv/data/file"
+        );
+
+        // Only matches once the literal rewrite is applied
+        let options = ScanOptions::builder()
+            .rewrite("get", "get-7")
+            .rewrite_regex(Regex::new("^(\\w+)-\\d+$").unwrap(), "tar$1")
+            .build()
+            .unwrap();
+        assert_eq!(linked_to(b"get", &options), Some("target".into()));
+    }
+
+    #[test]
+    fn rejects_control_characters_inside_targets() {
+        for content in ["tar\tget", "tar\x1bget", "tar\rget"] {
+            assert!(
+                matches!(
+                    classified(content.as_bytes(), &ScanOptions::default()),
+                    Classification::ControlCharacters
+                ),
+                "{content:?}"
+            );
+        }
+        // Surrounding whitespace is no sign of corruption
+        assert!(matches!(
+            classified(b"\ttarget\r\n", &ScanOptions::default()),
+            Classification::TargetMissing { .. }
+        ));
+        let options = ScanOptions::builder().strict_path(false).build().unwrap();
+        assert!(matches!(
+            classified(b"tar\tget", &options),
+            Classification::TargetMissing { .. }
+        ));
+    }
+
+    #[test]
+    fn decodes_contents_from_the_encoding_given() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("café"), "content").unwrap();
+        let stub = tmp.path().join("stub");
+        std::fs::write(&stub, b"caf\xe9").unwrap();
+        let options = ScanOptions::builder()
+            .encoding(encoding_rs::WINDOWS_1252)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            inspect(&stub, &options).classification,
+            Classification::Convertible { target } if target == "café"
+        ));
+        assert!(!matches!(
+            inspect(&stub, &ScanOptions::default()).classification,
+            Classification::Convertible { .. }
+        ));
+    }
+
+    #[test]
+    fn skips_several_paths_unless_taking_the_first_line() {
+        let content = b"target\ndir\n";
+        assert!(matches!(
+            classified(content, &ScanOptions::default()),
+            Classification::MultiplePaths
+        ));
+        let options = ScanOptions::builder().first_line(true).build().unwrap();
+        assert_eq!(linked_to(content, &options), Some("target".into()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn takes_backslashes_as_separators_on_request() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("dir")).unwrap();
+        std::fs::write(tmp.path().join("dir/file"), "content").unwrap();
+        let stub = tmp.path().join("stub");
+        std::fs::write(&stub, "dir\\file").unwrap();
+        let options = ScanOptions::builder()
+            .normalize_separators(true)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            inspect(&stub, &options).classification,
+            Classification::Convertible { target } if target == "dir/file"
+        ));
+        // A backslash is part of the name otherwise
+        assert!(matches!(
+            inspect(&stub, &ScanOptions::default()).classification,
+            Classification::TargetMissing { .. }
+        ));
+        std::fs::write(tmp.path().join("dir\\file"), "content").unwrap();
+        assert!(matches!(
+            inspect(&stub, &ScanOptions::default()).classification,
+            Classification::Convertible { target } if target == "dir\\file"
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn finds_targets_differing_by_case_on_request() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("foo")).unwrap();
+        std::fs::write(tmp.path().join("foo/bar"), "content").unwrap();
+        let stub = tmp.path().join("stub");
+        std::fs::write(&stub, "Foo/BAR").unwrap();
+        let options = ScanOptions::builder()
+            .ignore_case_targets(true)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            inspect(&stub, &options).classification,
+            Classification::Convertible { target } if target == "foo/bar"
+        ));
+        // Linux filesystems tell names differing by case apart
+        assert!(matches!(
+            inspect(&stub, &ScanOptions::default()).classification,
+            Classification::TargetMissing { .. }
+        ));
+    }
+
+    #[test]
+    fn trims_the_characters_given_and_whitespace() {
+        let options = ScanOptions::builder().trim_chars(";\0").build().unwrap();
+
+        for content in [&b"target;"[..], b";;target\0\0", b" ;target; \t"] {
+            assert_eq!(
+                linked_to(content, &options),
+                Some("target".into()),
+                "{content:?}"
+            );
+        }
+        assert_eq!(linked_to(b"target;", &ScanOptions::default()), None);
+    }
+
+    #[test]
+    fn accepts_only_targets_under_the_allowed_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let allowed = tmp.path().join("allowed");
+        std::fs::create_dir_all(allowed.join("sub")).unwrap();
+        std::fs::create_dir(tmp.path().join("other")).unwrap();
+        std::fs::write(allowed.join("target"), "content").unwrap();
+        std::fs::write(tmp.path().join("other/target"), "content").unwrap();
+        let options = ScanOptions::builder()
+            .target_must_be_under(&allowed)
+            .build()
+            .unwrap();
+        let inspected = |content: &str| {
+            let stub = allowed.join("stub");
+            std::fs::write(&stub, content).unwrap();
+            inspect(&stub, &options).classification
+        };
+
+        assert!(matches!(
+            inspected("target"),
+            Classification::Convertible { .. }
+        ));
+        assert!(matches!(
+            inspected("../other/target"),
+            Classification::OutsideAllowedRoots { .. }
+        ));
+        assert!(matches!(
+            inspected("sub/../../other/target"),
+            Classification::OutsideAllowedRoots { .. }
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn handles_symlinks_by_the_policy_asked_for() {
+        let tmp = tempfile::tempdir().unwrap();
+        let real = tmp.path().join("real");
+        let root = tmp.path().join("root");
+        std::fs::create_dir_all(&real).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(real.join("target"), "first\nsecond").unwrap();
+        std::fs::write(real.join("stub"), "target").unwrap();
+        std::os::unix::fs::symlink("../real", root.join("dir-link")).unwrap();
+        std::os::unix::fs::symlink("../real/stub", root.join("file-link")).unwrap();
+
+        let walked = |on_symlink| -> Vec<(PathBuf, String)> {
+            let options = ScanOptions::builder()
+                .on_symlink(on_symlink)
+                .build()
+                .unwrap();
+            let mut walked: Vec<_> = scan(&root, &options)
+                .map(|candidate| {
+                    let kind = match candidate.classification {
+                        Classification::Convertible { .. } => "stub",
+                        Classification::Symlink { .. } => "symlink",
+                        _ => "other",
+                    };
+                    (candidate.path, kind.to_owned())
+                })
+                .collect();
+            walked.sort();
+            walked.dedup();
+            walked
+        };
+        let expected = |walked: &[(PathBuf, &str)]| -> Vec<(PathBuf, String)> {
+            walked
+                .iter()
+                .map(|(path, kind)| (path.clone(), kind.to_string()))
+                .collect()
+        };
+
+        assert_eq!(
+            walked(OnSymlink::Skip),
+            expected(&[
+                (root.join("dir-link"), "symlink"),
+                (root.join("file-link"), "symlink")
+            ])
+        );
+        assert_eq!(
+            walked(OnSymlink::Follow),
+            expected(&[
+                (root.join("dir-link/stub"), "stub"),
+                (root.join("dir-link/target"), "other"),
+                (root.join("file-link"), "symlink")
+            ])
+        );
+        // Both links lead to the real stub, yielded under its real path
+        assert_eq!(
+            walked(OnSymlink::ConvertTarget),
+            expected(&[(real.join("stub"), "stub"), (real.join("target"), "other")])
+        );
+    }
+
+    #[test]
+    fn walks_deep_dir_chains_on_a_small_stack() {
+        let tmp = tempfile::tempdir().unwrap();
+        let deepest = (0..1000).fold(tmp.path().to_owned(), |dir, _| dir.join("d"));
+        std::fs::create_dir_all(&deepest).unwrap();
+        std::fs::write(
+            deepest.join("stub"),
+            tmp.path().as_os_str().as_encoded_bytes(),
+        )
+        .unwrap();
+
+        let root = tmp.path().to_owned();
+        let found = std::thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(move || {
+                let options = ScanOptions::default();
+                scan(&root, &options)
+                    .filter(|candidate| {
+                        matches!(candidate.classification, Classification::Convertible { .. })
+                    })
+                    .map(|candidate| candidate.depth)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(found, [1001]);
+    }
+
+    #[test]
+    fn classifies_ahead_only_files_passing_the_filters() {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in ["kept", "notes.md", ".hidden"] {
+            std::fs::write(tmp.path().join(name), "target").unwrap();
+        }
+        let options = ScanOptions::builder()
+            .parallel(true)
+            .skip_hidden(true)
+            .exclude(Glob::new("*.md").unwrap())
+            .build()
+            .unwrap();
+        let mut scan = Scan {
+            ops: &RealFs,
+            root: None,
+            root_dev: None,
+            base: tmp.path().to_owned(),
+            dirs: Vec::new(),
+            entered: HashSet::new(),
+            options: &options,
+        };
+
+        let metadata = std::fs::metadata(tmp.path()).unwrap();
+        scan.enter(tmp.path().to_owned(), 0, metadata);
+
+        let classified: Vec<_> = scan.dirs[0].classified.keys().collect();
+        assert_eq!(classified, [&tmp.path().join("kept")]);
+    }
+
+    #[test]
+    fn streaming_holds_no_entries_of_a_huge_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        for i in 0..10_000 {
+            std::fs::write(tmp.path().join(format!("file{i}")), "target").unwrap();
+        }
+        let buffered = |options: &ScanOptions| {
+            let mut scan = Scan {
+                ops: &RealFs,
+                root: None,
+                root_dev: None,
+                base: tmp.path().to_owned(),
+                dirs: Vec::new(),
+                entered: HashSet::new(),
+                options,
+            };
+            let metadata = std::fs::metadata(tmp.path()).unwrap();
+            scan.enter(tmp.path().to_owned(), 0, metadata);
+            let dir = &scan.dirs[0];
+            let buffered = match &dir.entries {
+                Entries::Buffered(entries) => entries.len(),
+                Entries::Streaming { .. } => 0,
+            };
+            buffered + dir.classified.len()
+        };
+
+        let options = ScanOptions::builder().sorted(true).build().unwrap();
+        assert_eq!(buffered(&options), 10_000);
+        // Sorting and classifying ahead are what would need the whole dir
+        let options = ScanOptions::builder()
+            .streaming(true)
+            .sorted(true)
+            .parallel(true)
+            .build()
+            .unwrap();
+        assert_eq!(buffered(&options), 0);
+        assert_eq!(scan(tmp.path(), &options).count(), 10_000);
+    }
+
+    #[test]
+    fn lists_dirs_through_ops() {
+        let tmp = tempfile::tempdir().unwrap();
+        let listed = MemFs::new();
+        listed.create_dir_all(tmp.path()).unwrap();
+        listed
+            .write(&tmp.path().join("only-listed"), b"x", 0o644)
+            .unwrap();
+
+        let options = ScanOptions::default();
+        let candidates: Vec<_> = scan_with(&listed, tmp.path(), &options).collect();
+
+        // The entry exists only in memory, whose metadata cannot be read
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].path, tmp.path().join("only-listed"));
+        assert!(matches!(
+            &candidates[0].classification,
+            Classification::Error(error) if error.kind() == io::ErrorKind::Unsupported
+        ));
+    }
+}
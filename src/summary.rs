@@ -0,0 +1,227 @@
+//! What happened to each candidate, and totals over a run.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::scan::Classification;
+
+/// Why a candidate was left as it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    TooBig,
+    TooSmall,
+    Empty,
+    Unreadable,
+    NotUtf8,
+    ControlCharacters,
+    MultiplePaths,
+    Url,
+    TargetTooLong,
+    RelativeTooDeep,
+    TargetMissing,
+    TargetUnreadable,
+    OutsideAllowedRoots,
+    Collision,
+    Stale,
+    Excluded,
+    Symlink,
+    Declined,
+    OtherOwner,
+    UnsafeParent,
+    LfsPointer,
+}
+
+impl SkipReason {
+    /// Why a candidate classified as `classification` is skipped, if it is
+    /// neither convertible, a directory nor an error
+    pub fn of(classification: &Classification) -> Option<SkipReason> {
+        Some(match classification {
+            Classification::TooBig { .. } => SkipReason::TooBig,
+            Classification::TooSmall { .. } => SkipReason::TooSmall,
+            Classification::Empty => SkipReason::Empty,
+            Classification::Unreadable(_) => SkipReason::Unreadable,
+            Classification::NotUtf8 => SkipReason::NotUtf8,
+            Classification::ControlCharacters => SkipReason::ControlCharacters,
+            Classification::MultiplePaths => SkipReason::MultiplePaths,
+            Classification::Url { .. } => SkipReason::Url,
+            Classification::TargetTooLong { .. } => SkipReason::TargetTooLong,
+            Classification::RelativeTooDeep { .. } => SkipReason::RelativeTooDeep,
+            Classification::TargetMissing { .. } => SkipReason::TargetMissing,
+            Classification::TargetUnreadable { .. } => SkipReason::TargetUnreadable,
+            Classification::OutsideAllowedRoots { .. } => SkipReason::OutsideAllowedRoots,
+            Classification::Excluded => SkipReason::Excluded,
+            Classification::Symlink { .. } => SkipReason::Symlink,
+            Classification::OtherOwner { .. } => SkipReason::OtherOwner,
+            Classification::LfsPointer { .. } => SkipReason::LfsPointer,
+            Classification::Convertible { .. }
+            | Classification::BrokenChain { .. }
+            | Classification::Directory { .. }
+            | Classification::Other
+            | Classification::Error(_) => return None,
+        })
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::TooBig => "too big",
+            SkipReason::TooSmall => "too small",
+            SkipReason::Empty => "empty",
+            SkipReason::Unreadable => "unreadable",
+            SkipReason::NotUtf8 => "not UTF-8",
+            SkipReason::ControlCharacters => "control characters",
+            SkipReason::MultiplePaths => "multiple paths",
+            SkipReason::Url => "looks like URL",
+            SkipReason::TargetTooLong => "target too long",
+            SkipReason::RelativeTooDeep => "relative target too deep",
+            SkipReason::TargetMissing => "target missing",
+            SkipReason::TargetUnreadable => "target unreadable",
+            SkipReason::OutsideAllowedRoots => "outside allowed roots",
+            SkipReason::Collision => "name collision",
+            SkipReason::Stale => "changed meanwhile",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Symlink => "already symlink",
+            SkipReason::Declined => "declined",
+            SkipReason::OtherOwner => "other owner",
+            SkipReason::UnsafeParent => "world-writable dir",
+            SkipReason::LfsPointer => "LFS pointer",
+        })
+    }
+}
+
+/// What happened to one candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Converted,
+    Deleted,
+    /// Turned back from a symlink into the file it was made from
+    Restored,
+    Skipped(SkipReason),
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Converted => f.write_str("converted"),
+            Outcome::Deleted => f.write_str("deleted"),
+            Outcome::Restored => f.write_str("restored"),
+            Outcome::Skipped(reason) => write!(f, "skipped: {reason}"),
+            Outcome::Failed => f.write_str("failed"),
+        }
+    }
+}
+
+/// Outcome totals over a run.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub converted: usize,
+    pub deleted: usize,
+    pub restored: usize,
+    pub failed: usize,
+    /// Whether the run was stopped early, leaving an unknown number of entries alone
+    pub stopped_early: bool,
+    /// Total size of the stubs that were converted
+    pub converted_bytes: u64,
+    skipped: BTreeMap<SkipReason, usize>,
+    /// Outcome of every path, when created with [`Summary::recording`]
+    outcomes: Option<Vec<(PathBuf, Outcome)>>,
+    /// Told about every outcome as it is added, see [`Summary::with_observer`]
+    observer: Option<fn(&Path, Outcome)>,
+}
+
+impl Summary {
+    /// A summary that also remembers the outcome of every path
+    pub fn recording() -> Self {
+        Summary {
+            outcomes: Some(Vec::new()),
+            ..Summary::default()
+        }
+    }
+
+    /// This summary, calling `observer` with every outcome as it is added
+    pub fn with_observer(self, observer: fn(&Path, Outcome)) -> Self {
+        Summary {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    pub fn add(&mut self, path: &Path, outcome: Outcome) {
+        if let Some(observer) = self.observer {
+            observer(path, outcome);
+        }
+        if let Some(outcomes) = &mut self.outcomes {
+            outcomes.push((path.to_owned(), outcome));
+        }
+        match outcome {
+            Outcome::Converted => self.converted += 1,
+            Outcome::Deleted => self.deleted += 1,
+            Outcome::Restored => self.restored += 1,
+            Outcome::Skipped(reason) => *self.skipped.entry(reason).or_default() += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped.values().sum()
+    }
+
+    /// Recorded outcomes in the order they were added, empty unless recording
+    pub fn outcomes(&self) -> &[(PathBuf, Outcome)] {
+        self.outcomes.as_deref().unwrap_or_default()
+    }
+
+    /// Skip counts per reason, leaving out reasons that never occurred
+    pub fn skipped_by_reason(&self) -> impl Iterator<Item = (SkipReason, usize)> + '_ {
+        self.skipped.iter().map(|(reason, count)| (*reason, *count))
+    }
+
+    /// The totals as a single-line JSON object, for a run that took `elapsed`
+    pub fn to_json(&self, elapsed: Duration) -> String {
+        let reasons: Vec<_> = self
+            .skipped_by_reason()
+            .map(|(reason, count)| format!("\"{reason}\":{count}"))
+            .collect();
+        format!(
+            "{{\"converted\":{},\"deleted\":{},\"restored\":{},\"skipped\":{},\"failed\":{},\"stopped_early\":{},\
+             \"skipped_by_reason\":{{{}}},\"bytes_reclaimed\":{},\"elapsed_seconds\":{:.3}}}",
+            self.converted,
+            self.deleted,
+            self.restored,
+            self.skipped(),
+            self.failed,
+            self.stopped_early,
+            reasons.join(","),
+            self.converted_bytes,
+            elapsed.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_holds_every_total() {
+        let mut summary = Summary::default();
+        summary.add(Path::new("a"), Outcome::Converted);
+        summary.add(Path::new("b"), Outcome::Skipped(SkipReason::MultiplePaths));
+        summary.add(Path::new("c"), Outcome::Skipped(SkipReason::MultiplePaths));
+        summary.add(Path::new("d"), Outcome::Failed);
+        summary.converted_bytes = 12;
+
+        assert_eq!(
+            summary.to_json(Duration::from_millis(1500)),
+            "{\"converted\":1,\"deleted\":0,\"restored\":0,\"skipped\":2,\"failed\":1,\"stopped_early\":false,\
+             \"skipped_by_reason\":{\"multiple paths\":2},\"bytes_reclaimed\":12,\
+             \"elapsed_seconds\":1.500}"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! A real filesystem failing on demand, for tests of retrying and cleaning up.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use restore_symlink::fs_ops::{DirEntries, FsOps, RealFs};
+
+/// Calls [`TestFs`] can make fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Symlink,
+    Rename,
+}
+
+/// The real filesystem, except that the first `failures` calls of `op`
+/// fail with `kind`, remembering the renames made
+pub struct TestFs {
+    op: Op,
+    kind: io::ErrorKind,
+    failures: usize,
+    calls: AtomicUsize,
+    renamed: Mutex<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl TestFs {
+    pub fn failing(op: Op, kind: io::ErrorKind, failures: usize) -> Self {
+        TestFs {
+            op,
+            kind,
+            failures,
+            calls: Default::default(),
+            renamed: Default::default(),
+        }
+    }
+
+    /// The real filesystem, only remembering the renames made
+    pub fn recording() -> Self {
+        Self::failing(Op::Rename, io::ErrorKind::Other, 0)
+    }
+
+    /// Sources and destinations of the successful renames, in the order they were made
+    pub fn renamed(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.renamed.lock().unwrap().clone()
+    }
+
+    /// Number of times `op` was called so far
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    fn call(&self, op: Op) -> io::Result<()> {
+        if op != self.op {
+            return Ok(());
+        }
+        let calls = self.calls.fetch_add(1, Ordering::Relaxed);
+        if calls < self.failures {
+            return Err(self.kind.into());
+        }
+        Ok(())
+    }
+}
+
+impl FsOps for TestFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        RealFs.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        RealFs.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<DirEntries<'_>> {
+        RealFs.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove_dir_all(path)
+    }
+
+    fn symlink(&self, target: &OsStr, link: &Path) -> io::Result<()> {
+        self.call(Op::Symlink)?;
+        RealFs.symlink(target, link)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        RealFs.hard_link(original, link)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        RealFs.copy(from, to)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.call(Op::Rename)?;
+        RealFs.rename(from, to)?;
+        self.renamed
+            .lock()
+            .unwrap()
+            .push((from.to_owned(), to.to_owned()));
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        RealFs.read_link(path)
+    }
+
+    fn write(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        RealFs.write(path, content, mode)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        RealFs.create_dir_all(path)
+    }
+
+    fn preserve(
+        &self,
+        link: &Path,
+        metadata: &Metadata,
+        xattrs: &[(OsString, Vec<u8>)],
+    ) -> io::Result<()> {
+        RealFs.preserve(link, metadata, xattrs)
+    }
+}
@@ -0,0 +1,91 @@
+//! Rendering results as an indented tree, like the `tree` command does.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+};
+
+use restore_symlink::summary::Outcome;
+
+use crate::output;
+
+#[derive(Default)]
+struct Node {
+    outcome: Option<Outcome>,
+    children: BTreeMap<OsString, Node>,
+}
+
+/// Lines of a tree of `outcomes` below `root`, each leaf annotated with its outcome
+pub fn render(root: &Path, outcomes: &[(PathBuf, Outcome)]) -> Vec<String> {
+    let mut tree = Node::default();
+    for (path, outcome) in outcomes {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let mut node = &mut tree;
+        for component in relative.components() {
+            if let Component::Normal(name) = component {
+                node = node.children.entry(name.to_owned()).or_default();
+            }
+        }
+        node.outcome = Some(*outcome);
+    }
+
+    let mut lines = vec![annotated(&output::text(root), tree.outcome)];
+    render_children(&tree, "", &mut lines);
+    lines
+}
+
+fn render_children(node: &Node, prefix: &str, lines: &mut Vec<String>) {
+    let mut children = node.children.iter().peekable();
+    while let Some((name, child)) = children.next() {
+        let last = children.peek().is_none();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        lines.push(format!(
+            "{prefix}{branch}{}",
+            annotated(&output::text(name), child.outcome)
+        ));
+        render_children(child, &format!("{prefix}{indent}"), lines);
+    }
+}
+
+fn annotated(name: &str, outcome: Option<Outcome>) -> String {
+    match outcome {
+        Some(outcome) => format!("{name} [{outcome}]"),
+        None => name.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use restore_symlink::summary::SkipReason;
+
+    use super::*;
+
+    #[test]
+    fn indents_children_and_annotates_leaves() {
+        let outcomes = [
+            (PathBuf::from("root/a/stub"), Outcome::Converted),
+            (PathBuf::from("root/a/b/stub"), Outcome::Failed),
+            (
+                PathBuf::from("root/notes"),
+                Outcome::Skipped(SkipReason::MultiplePaths),
+            ),
+        ];
+
+        assert_eq!(
+            render(Path::new("root"), &outcomes),
+            [
+                "root",
+                "├── a",
+                "│   ├── b",
+                "│   │   └── stub [failed]",
+                "│   └── stub [converted]",
+                "└── notes [skipped: multiple paths]",
+            ]
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Converting stubs as they appear, once the first pass is done.
+//!
+//! Directories are watched with inotify. Files written or moved in are
+//! gathered until no event came for `--watch-debounce`, then the roots are
+//! walked again with only those files let through, so they meet the same
+//! filters and checks as in a full run.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use restore_symlink::{
+    convert::Converter,
+    scan::{scan, Classification, ScanOptions},
+    summary::{Outcome, Summary},
+};
+
+use crate::{output::report, print_io_error, process_candidate, report_batch, stop_early, Args};
+
+/// Events telling that a file may have become a stub or a dir appeared
+const WATCHED_EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+
+/// Size of the buffer events are read into, room for many events with long names
+const EVENT_BUFFER: usize = 64 * 1024;
+
+/// An inotify instance with the dirs it watches
+struct Watcher {
+    fd: libc::c_int,
+    dirs: HashMap<libc::c_int, PathBuf>,
+    watched: HashSet<PathBuf>,
+}
+
+/// What the events read so far ask to look at again
+#[derive(Default)]
+struct Changes {
+    files: HashSet<PathBuf>,
+    /// Events were dropped by the kernel, so everything is looked at again
+    overflowed: bool,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.files.is_empty() && !self.overflowed
+    }
+}
+
+impl Watcher {
+    fn new() -> io::Result<Self> {
+        // SAFETY: inotify_init1 only takes flags and returns a new fd or -1.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watcher {
+            fd,
+            dirs: HashMap::new(),
+            watched: HashSet::new(),
+        })
+    }
+
+    /// Start watching the dir at `path`, returning whether it was not watched yet
+    fn add(&mut self, path: &Path) -> io::Result<bool> {
+        if self.watched.contains(path) {
+            return Ok(false);
+        }
+        let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+        let mask = WATCHED_EVENTS | libc::IN_ONLYDIR;
+        // SAFETY: `self.fd` is the inotify fd owned by this watcher and `c_path`
+        // a NUL-terminated path that outlives the call.
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, path.to_owned());
+        self.watched.insert(path.to_owned());
+        Ok(true)
+    }
+
+    /// Wait up to `timeout_ms` for events, -1 waiting as long as it takes,
+    /// adding what they tell to `changes` and returning whether there were any
+    fn read(&mut self, timeout_ms: libc::c_int, changes: &mut Changes) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `poll` is a single valid pollfd, matching the count of 1.
+        match unsafe { libc::poll(&mut poll, 1, timeout_ms) } {
+            0 => return Ok(false),
+            ready if ready < 0 => {
+                let error = io::Error::last_os_error();
+                return match error.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(error),
+                };
+            }
+            _ => {}
+        }
+
+        let mut buffer = vec![0u8; EVENT_BUFFER];
+        // SAFETY: `buffer` is writable for the `buffer.len()` bytes read may fill.
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if len < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(error),
+            };
+        }
+        self.take_events(&buffer[..len as usize], changes);
+        Ok(true)
+    }
+
+    /// Add what the inotify events in `events` tell to `changes`
+    ///
+    /// An event cut short by the end of `events` ends them.
+    fn take_events(&mut self, events: &[u8], changes: &mut Changes) {
+        let header = size_of::<libc::inotify_event>();
+        let mut at = 0;
+        while at + header <= events.len() {
+            // SAFETY: the `header` bytes at `at` are within `events`, and
+            // read_unaligned does not need them aligned for the struct.
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(events[at..].as_ptr().cast()) };
+            let Some(name) = events.get(at + header..at + header + event.len as usize) else {
+                break;
+            };
+            let name = &name[..name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len())];
+            at += header + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                changes.overflowed = true;
+            } else if event.mask & libc::IN_IGNORED != 0 {
+                if let Some(dir) = self.dirs.remove(&event.wd) {
+                    self.watched.remove(&dir);
+                }
+            } else if let Some(dir) = self.dirs.get(&event.wd) {
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+                if is_dir || event.mask & libc::IN_CREATE == 0 {
+                    changes.files.insert(dir.join(OsStr::from_bytes(name)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // SAFETY: `self.fd` is owned by this watcher and closed only here.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Convert the stubs written or moved below `roots` from now on, until
+/// `deadline` or the run is stopped early
+///
+/// Dirs the walk enters are watched, including those created later. Roots
+/// that are files are not watched, and a dir renamed while watched is still
+/// looked at under its old path.
+pub fn watch(
+    converter: &Converter,
+    roots: &[PathBuf],
+    options: &ScanOptions,
+    args: &Args,
+    deadline: Option<Instant>,
+    summary: &mut Summary,
+) {
+    let Some(first_root) = roots.first() else {
+        return;
+    };
+    let mut watcher = match Watcher::new() {
+        Ok(watcher) => watcher,
+        Err(error) => {
+            print_io_error(first_root, &format!("cannot watch for changes: {error}"));
+            summary.add(first_root, Outcome::Failed);
+            return;
+        }
+    };
+    let options = ScanOptions {
+        report_dirs: true,
+        parallel: false,
+        ..options.clone()
+    };
+    let mut changes = Changes::default();
+    for dir in rescan(
+        &mut watcher,
+        roots,
+        &options,
+        &changes,
+        converter,
+        args,
+        summary,
+    ) {
+        if let Err(error) = watcher.add(&dir) {
+            print_io_error(&dir, &format!("cannot watch it: {error}"));
+            summary.add(&dir, Outcome::Failed);
+        }
+    }
+    if args.verbose > 0 {
+        report!("Watching {} dirs for new stubs", watcher.dirs.len())
+    }
+
+    let debounce = args.watch_debounce.as_millis().min(i32::MAX as u128) as libc::c_int;
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                let left = left.as_millis().min(i32::MAX as u128) as libc::c_int;
+                if changes.is_empty() {
+                    left
+                } else {
+                    left.min(debounce)
+                }
+            }
+            None if changes.is_empty() => -1,
+            None => debounce,
+        };
+        match watcher.read(timeout, &mut changes) {
+            Ok(true) => continue,
+            Ok(false) if changes.is_empty() => continue,
+            Ok(false) => {}
+            Err(error) => {
+                print_io_error(first_root, &format!("cannot watch for changes: {error}"));
+                summary.add(first_root, Outcome::Failed);
+                break;
+            }
+        }
+
+        let batch = std::mem::take(&mut changes);
+        for dir in rescan(
+            &mut watcher,
+            roots,
+            &options,
+            &batch,
+            converter,
+            args,
+            summary,
+        ) {
+            watch_dir(&mut watcher, &dir, &mut changes, summary);
+        }
+        if stop_early(summary, args) {
+            break;
+        }
+    }
+}
+
+/// Watch the dir at `dir` that appeared meanwhile, queueing the files
+/// already in it as they were written before the watch could see them
+fn watch_dir(watcher: &mut Watcher, dir: &Path, changes: &mut Changes, summary: &mut Summary) {
+    match watcher.add(dir) {
+        Ok(true) => {
+            if let Ok(entries) = dir.read_dir() {
+                changes
+                    .files
+                    .extend(entries.flatten().map(|entry| entry.path()));
+            }
+        }
+        Ok(false) => {}
+        Err(error) => {
+            print_io_error(dir, &format!("cannot watch it: {error}"));
+            summary.add(dir, Outcome::Failed);
+        }
+    }
+}
+
+/// Walk `roots` again, converting the files in `changes` and returning the dirs entered
+///
+/// Before the first event, `changes` is empty and only the dirs are walked.
+fn rescan(
+    watcher: &mut Watcher,
+    roots: &[PathBuf],
+    options: &ScanOptions,
+    changes: &Changes,
+    converter: &Converter,
+    args: &Args,
+    summary: &mut Summary,
+) -> Vec<PathBuf> {
+    let options = if changes.overflowed {
+        options.clone()
+    } else {
+        let only_files = match &options.only_files {
+            Some(files) => files.intersection(&changes.files).cloned().collect(),
+            None => changes.files.clone(),
+        };
+        ScanOptions {
+            only_files: Some(only_files),
+            ..options.clone()
+        }
+    };
+
+    let mut dirs = Vec::new();
+    for root in roots {
+        for candidate in scan(root, &options) {
+            match candidate.classification {
+                Classification::Directory { .. } => {
+                    if !watcher.watched.contains(&candidate.path) {
+                        dirs.push(candidate.path);
+                    }
+                    continue;
+                }
+                // Symlinks turn up as the conversions just made are moved in place.
+                Classification::Symlink { .. } => continue,
+                Classification::Excluded
+                    if !changes.overflowed && !changes.files.contains(&candidate.path) =>
+                {
+                    continue
+                }
+                _ => {}
+            }
+            let processed = process_candidate(converter, candidate, args);
+            let Some(outcome) = processed.outcome else {
+                continue;
+            };
+            if outcome == Outcome::Converted {
+                summary.converted_bytes += processed.len;
+            }
+            summary.add(&processed.path, outcome);
+            report_batch(summary, outcome, args);
+            if stop_early(summary, args) {
+                return dirs;
+            }
+        }
+    }
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bytes of an inotify event for the watch `wd` with `mask` and `name`
+    /// padded to `len` bytes
+    fn event(wd: libc::c_int, mask: u32, name: &[u8], len: u32) -> Vec<u8> {
+        let header = libc::inotify_event {
+            wd,
+            mask,
+            cookie: 0,
+            len,
+        };
+        // SAFETY: inotify_event is plain integers without padding, so all its
+        // bytes are initialized.
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(
+                (&header as *const libc::inotify_event).cast::<u8>(),
+                size_of::<libc::inotify_event>(),
+            )
+        }
+        .to_vec();
+        bytes.extend_from_slice(name);
+        bytes.resize(bytes.len() + len as usize - name.len(), 0);
+        bytes
+    }
+
+    fn watcher() -> Watcher {
+        Watcher {
+            fd: -1,
+            dirs: HashMap::from([(1, PathBuf::from("dir"))]),
+            watched: HashSet::from([PathBuf::from("dir")]),
+        }
+    }
+
+    #[test]
+    fn takes_the_names_of_written_and_moved_files() {
+        let mut events = event(1, libc::IN_CLOSE_WRITE, b"stub", 16);
+        events.extend(event(1, libc::IN_MOVED_TO, b"other", 8));
+        let mut changes = Changes::default();
+
+        watcher().take_events(&events, &mut changes);
+
+        let files = HashSet::from([PathBuf::from("dir/stub"), PathBuf::from("dir/other")]);
+        assert_eq!(changes.files, files);
+    }
+
+    #[test]
+    fn stops_at_an_event_cut_short() {
+        let mut events = event(1, libc::IN_CLOSE_WRITE, b"stub", 16);
+        let cut = event(1, libc::IN_CLOSE_WRITE, b"cut", 16);
+        events.extend_from_slice(&cut[..cut.len() - 8]);
+        let mut changes = Changes::default();
+
+        watcher().take_events(&events, &mut changes);
+
+        assert_eq!(changes.files, HashSet::from([PathBuf::from("dir/stub")]));
+    }
+}
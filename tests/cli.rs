@@ -0,0 +1,898 @@
+//! Runs of the built program, for what shows in its output and exit code.
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+/// Run the program in `dir` with `args`, ignoring config files
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_restore_symlink"))
+        .current_dir(dir)
+        .arg("--no-config")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Run the program in `dir` with `args` like [`run`], writing `input` to its stdin
+fn run_with_input(dir: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_restore_symlink"))
+        .current_dir(dir)
+        .arg("--no-config")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// A tree at `dir/tree` of stubs to `tree/target` and files that are no stubs, over a few dirs
+fn write_tree(dir: &Path) {
+    let tree = dir.join("tree");
+    fs::create_dir(&tree).unwrap();
+    fs::write(tree.join("target"), "content").unwrap();
+    for i in 0..600 {
+        let sub = tree.join(format!("dir{}", i % 5));
+        fs::create_dir_all(&sub).unwrap();
+        let content = if i % 4 == 0 {
+            "first\nsecond"
+        } else {
+            "../target"
+        };
+        fs::write(sub.join(format!("file{i}")), content).unwrap();
+    }
+}
+
+#[test]
+fn stable_walk_order_prints_the_same_with_any_jobs() {
+    let (serial, parallel) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    write_tree(serial.path());
+    write_tree(parallel.path());
+    let args = ["-rv", "--parallel-walk-order", "stable", "tree"];
+
+    let serial = run(serial.path(), &[&["--jobs", "1"][..], &args].concat());
+    let parallel = run(parallel.path(), &[&["--jobs", "4"][..], &args].concat());
+
+    assert!(serial.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&parallel.stdout),
+        String::from_utf8_lossy(&serial.stdout)
+    );
+    let converted: Vec<&str> = std::str::from_utf8(&serial.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix("Converted to symlink: "))
+        .collect();
+    assert_eq!(converted.len(), 450);
+    assert!(converted.is_sorted());
+}
+
+#[test]
+fn effective_options_show_config_file_values() {
+    let tmp = tempfile::tempdir().unwrap();
+    let config = tmp.path().join("config/restore-symlink");
+    fs::create_dir_all(&config).unwrap();
+    fs::write(
+        config.join("config.toml"),
+        "len = 42\nexclude = [\"*.md\"]\n",
+    )
+    .unwrap();
+    fs::create_dir(tmp.path().join("tree")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_restore_symlink"))
+        .current_dir(tmp.path())
+        .env("XDG_CONFIG_HOME", tmp.path().join("config"))
+        .args(["-vv", "--dry-run", "tree"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let effective: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "Effective options:")
+        .skip(1)
+        .take_while(|line| line.starts_with("  "))
+        .collect();
+    assert!(
+        effective.contains(&"  Stub size: 1 to 42 bytes"),
+        "{stdout}"
+    );
+    assert!(effective.contains(&"  Exclude: *.md"), "{stdout}");
+    assert!(!stdout.contains("Args {"), "{stdout}");
+}
+
+#[test]
+fn json_format_prints_an_object_per_event_then_the_totals() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    fs::write(tree.join("target"), "content").unwrap();
+    fs::write(tree.join("stub"), "target").unwrap();
+    fs::write(tree.join("list"), "first\n\"second\"").unwrap();
+
+    let output = run(tmp.path(), &["--format", "json", "--len", "16", "tree"]);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    let summary = lines.pop().unwrap();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            r#"{"event":"converted","path":"tree/stub","target":"target"}"#,
+            r#"{"event":"skipped","path":"tree/list","reason":"multiple paths"}"#,
+            r#"{"event":"skipped","path":"tree/target","reason":"target missing"}"#,
+        ]
+    );
+    assert!(
+        summary.starts_with(
+            r#"{"event":"summary","converted":1,"deleted":0,"restored":0,"skipped":2,"failed":0,"stopped_early":false,"skipped_by_reason":{"multiple paths":1,"target missing":1},"bytes_reclaimed":6,"elapsed_seconds":"#
+        ),
+        "{summary}"
+    );
+}
+
+/// A dir `tree` in a fresh dir holding a stub to a file next to it
+fn stub_tree() -> tempfile::TempDir {
+    let tmp = tempfile::tempdir().unwrap();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    fs::write(tree.join("target"), "content").unwrap();
+    fs::write(tree.join("stub"), "target").unwrap();
+    tmp
+}
+
+#[test]
+fn check_exits_with_stubs_found_and_changes_nothing() {
+    let tmp = stub_tree();
+
+    let output = run(tmp.path(), &["--check", "tree"]);
+
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(fs::read(tmp.path().join("tree/stub")).unwrap(), b"target");
+}
+
+#[test]
+fn check_exits_successfully_without_stubs() {
+    let tmp = stub_tree();
+    assert!(run(tmp.path(), &["tree"]).status.success());
+
+    let output = run(tmp.path(), &["--check", "tree"]);
+
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn exit_codes_tell_failures_from_usage_errors() {
+    let tmp = stub_tree();
+
+    assert_eq!(run(tmp.path(), &["missing"]).status.code(), Some(1));
+    assert_eq!(
+        run(tmp.path(), &["--len", "0", "tree"]).status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        run(tmp.path(), &["--max-skips", "0", "tree"]).status.code(),
+        Some(4)
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn undo_turns_journaled_links_back_into_their_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = stub_tree();
+    let stub = tmp.path().join("tree/stub");
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o640)).unwrap();
+
+    assert!(run(tmp.path(), &["--journal", "journal", "tree"])
+        .status
+        .success());
+    assert!(fs::symlink_metadata(&stub).unwrap().is_symlink());
+
+    let output = run(tmp.path(), &["undo", "journal"]);
+
+    assert!(output.status.success(), "{output:?}");
+    let metadata = fs::symlink_metadata(&stub).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    assert_eq!(fs::read(&stub).unwrap(), b"target");
+}
+
+#[test]
+fn show_target_state_tells_existing_from_missing_targets() {
+    let tmp = stub_tree();
+    fs::write(tmp.path().join("tree/later"), "missing/file.txt").unwrap();
+
+    let output = run(tmp.path(), &["--show-target-state", "--force", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "Converted to symlink: tree/later -> missing/file.txt [target missing]",
+            "Converted to symlink: tree/stub -> target [target ok]",
+        ]
+    );
+}
+
+#[test]
+fn converts_the_top_of_the_current_dir_unless_recursive() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+    let is_link = |path: &str| fs::symlink_metadata(tree.join(path)).unwrap().is_symlink();
+
+    assert!(run(&tree, &["."]).status.success());
+    assert!(is_link("stub"));
+    assert!(!is_link("sub/stub"));
+
+    assert!(run(&tree, &["-r", "."]).status.success());
+    assert!(is_link("sub/stub"));
+}
+
+#[test]
+fn reports_stubs_differing_only_by_case() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::write(tree.join("File.txt"), "target").unwrap();
+    fs::write(tree.join("file.txt"), "target").unwrap();
+    let is_link = |name: &str| fs::symlink_metadata(tree.join(name)).unwrap().is_symlink();
+
+    let output = run(tmp.path(), &["tree"]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("name differs only by case from"),
+        "{stderr}"
+    );
+    assert!(!is_link("File.txt") && !is_link("file.txt"));
+
+    assert!(run(tmp.path(), &["--on-collision", "first", "tree"])
+        .status
+        .success());
+    assert!(is_link("File.txt") != is_link("file.txt"));
+}
+
+#[test]
+fn deletes_empty_files_only_when_asked() {
+    let tmp = stub_tree();
+    let placeholder = tmp.path().join("tree/placeholder");
+    fs::write(&placeholder, "").unwrap();
+
+    assert!(run(tmp.path(), &["tree"]).status.success());
+    assert!(placeholder.exists());
+
+    let output = run(tmp.path(), &["--delete-empty", "tree"]);
+
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line == "Deleted empty placeholder: tree/placeholder"));
+    assert!(!placeholder.exists());
+}
+
+#[test]
+fn log_file_records_conversions_and_errors() {
+    let tmp = stub_tree();
+    fs::write(tmp.path().join("tree/notes"), "first\nsecond").unwrap();
+
+    let output = run(tmp.path(), &["--log-file", "log", "tree", "missing"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let log = fs::read_to_string(tmp.path().join("log")).unwrap();
+    let records: Vec<&str> = log
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect();
+    assert!(
+        records.contains(&"INFO  converted tree/stub -> target"),
+        "{log}"
+    );
+    assert!(
+        records.contains(&"INFO  skipped tree/notes (multiple paths)"),
+        "{log}"
+    );
+    assert!(
+        records
+            .iter()
+            .any(|record| record.starts_with("ERROR Cannot convert 'missing': ")),
+        "{log}"
+    );
+}
+
+#[test]
+fn verbose_summary_breaks_skips_down_by_reason() {
+    let tmp = stub_tree();
+    assert!(run(tmp.path(), &["tree"]).status.success());
+    let tree = tmp.path().join("tree");
+    fs::write(tree.join("empty"), "").unwrap();
+    fs::write(tree.join("big"), "a".repeat(600)).unwrap();
+    fs::write(tree.join("missing"), "nothere").unwrap();
+    fs::write(tree.join("notes.md"), "target").unwrap();
+
+    let output = run(tmp.path(), &["-v", "--exclude", "*.md", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.lines().any(|line| line
+            == "Skipped: too big 1, empty 1, target missing 2, excluded 1, already symlink 1"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn set_target_links_to_the_target_given_whatever_the_content() {
+    let tmp = stub_tree();
+    let file = tmp.path().join("tree/notes");
+    fs::write(&file, "not a path\nat all").unwrap();
+
+    let args = ["--dry-run", "--set-target", "elsewhere", "tree/notes"];
+    assert!(run(tmp.path(), &args).status.success());
+    assert!(fs::symlink_metadata(&file).unwrap().is_file());
+    let output = run(tmp.path(), &["--set-target", "elsewhere", "tree/notes"]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read_link(&file).unwrap(), Path::new("elsewhere"));
+    assert!(fs::symlink_metadata(tmp.path().join("tree/stub"))
+        .unwrap()
+        .is_file());
+}
+
+#[test]
+fn quiet_if_noop_prints_nothing_once_everything_is_converted() {
+    let tmp = stub_tree();
+
+    let output = run(tmp.path(), &["--quiet-if-noop", "-v", "tree"]);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line == "Converted to symlink: tree/stub -> target"));
+
+    let output = run(tmp.path(), &["--quiet-if-noop", "-v", "tree"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn version_tells_the_target_triple() {
+    let tmp = tempfile::tempdir().unwrap();
+
+    let output = run(tmp.path(), &["--version"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("target: {}", env!("BUILD_TARGET"))),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn report_duplicates_lists_stubs_sharing_a_target() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+    fs::write(tree.join("other"), "content").unwrap();
+    fs::write(tree.join("alone"), "other").unwrap();
+
+    let output = run(tmp.path(), &["-r", "--report-duplicates", "tree"]);
+
+    assert!(output.status.success(), "{output:?}");
+    let target = tree.join("target").canonicalize().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "2 stubs point to {}:\n  tree/sub/stub\n  tree/stub\n",
+            target.display()
+        )
+    );
+    assert!(fs::symlink_metadata(tree.join("stub")).unwrap().is_file());
+}
+
+#[test]
+#[cfg(unix)]
+fn verbose_tells_stubs_that_are_not_utf8() {
+    let tmp = stub_tree();
+    fs::write(tmp.path().join("tree/bytes"), b"caf\xe9").unwrap();
+
+    let output = run(tmp.path(), &["-v", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout
+            .lines()
+            .any(|line| line == "Target of tree/bytes is not valid UTF-8 and does not exist"),
+        "{stdout}"
+    );
+    let output = run(tmp.path(), &["tree"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("UTF-8"));
+}
+
+#[test]
+fn report_io_errors_only_prints_nothing_else() {
+    let (quiet, plain) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    write_tree(quiet.path());
+    write_tree(plain.path());
+    let args = ["-rv", "tree", "missing"];
+
+    let output = run(
+        quiet.path(),
+        &[&["--report-io-errors-only"][..], &args].concat(),
+    );
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let errors: Vec<&str> = stderr.lines().collect();
+    assert_eq!(errors.len(), 1, "{stderr}");
+    assert!(
+        errors[0].starts_with("Cannot convert 'missing': "),
+        "{stderr}"
+    );
+    let output = run(plain.path(), &args);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with("Converted to symlink: ")));
+}
+
+#[test]
+fn max_skips_fails_only_runs_skipping_more() {
+    let tmp = stub_tree();
+    // The target itself is skipped, its content naming no file
+    let args = ["--dry-run", "--max-skips", "1", "tree"];
+
+    assert_eq!(run(tmp.path(), &args).status.code(), Some(0));
+    fs::write(tmp.path().join("tree/list"), "first\nsecond").unwrap();
+    assert_eq!(run(tmp.path(), &args).status.code(), Some(4));
+}
+
+#[test]
+fn report_relative_to_shortens_printed_paths_only() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+
+    let output = run(
+        tmp.path(),
+        &["-r", "--report-relative-to", "tree/sub", "tree"],
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "Converted to symlink: ../stub -> target",
+            "Converted to symlink: stub -> ../target",
+        ]
+    );
+    assert_eq!(
+        fs::read_link(tree.join("sub/stub")).unwrap(),
+        Path::new("../target")
+    );
+    assert_eq!(
+        fs::read_link(tree.join("stub")).unwrap(),
+        Path::new("target")
+    );
+}
+
+#[test]
+fn most_verbose_tells_each_dir_entered_with_its_entries() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+
+    let output = run(tmp.path(), &["-vvv", "-r", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let entering: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("Entering "))
+        .collect();
+    assert_eq!(
+        entering,
+        ["Entering tree (3 entries)", "Entering tree/sub (1 entries)"]
+    );
+    let output = run(tmp.path(), &["-vv", "-r", "tree"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Entering "));
+}
+
+#[test]
+fn interactive_default_answers_an_empty_line() {
+    let tmp = stub_tree();
+    let stub = tmp.path().join("tree/stub");
+
+    let args = ["--interactive", "--interactive-default", "no", "tree"];
+    let output = run_with_input(tmp.path(), &args, "\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("[y/N/a/q/e/s/?]"));
+    assert!(fs::symlink_metadata(&stub).unwrap().is_file());
+
+    let args = ["--interactive", "--interactive-default", "yes", "tree"];
+    let output = run_with_input(tmp.path(), &args, "\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("[Y/n/a/q/e/s/?]"));
+    assert!(fs::symlink_metadata(&stub).unwrap().is_symlink());
+}
+
+#[test]
+fn verbose_summary_reports_throughput_on_request() {
+    let tmp = stub_tree();
+    for name in ["second", "third"] {
+        fs::write(tmp.path().join("tree").join(name), "target").unwrap();
+    }
+
+    let output = run(tmp.path(), &["-v", "--report-throughput", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Throughput: "))
+        .unwrap_or_else(|| panic!("{stdout}"));
+    let numbers: Vec<f64> = line
+        .split([' ', 's'])
+        .filter_map(|word| word.parse().ok())
+        .collect();
+    assert_eq!(numbers.len(), 3, "{line}");
+    // The elapsed time may round to zero, but not the rates
+    assert!(numbers[0] > 0.0 && numbers[1] > 0.0, "{line}");
+    assert!(line.contains(" files/s, ") && line.contains(" bytes/s over "));
+
+    let output = run(tmp.path(), &["--report-throughput", "tree"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Throughput"));
+}
+
+#[test]
+fn lock_file_refuses_a_run_while_another_holds_it() {
+    let tmp = stub_tree();
+    let holder = fs::File::create(tmp.path().join("lock")).unwrap();
+    holder.lock().unwrap();
+
+    let output = run(tmp.path(), &["--lock-file", "lock", "tree"]);
+
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Cannot lock 'lock': another run holds the lock\n"
+    );
+    assert!(fs::symlink_metadata(tmp.path().join("tree/stub"))
+        .unwrap()
+        .is_file());
+
+    drop(holder);
+    assert!(run(tmp.path(), &["--lock-file", "lock", "tree"])
+        .status
+        .success());
+}
+
+#[test]
+fn applying_a_dumped_plan_makes_the_links_planned() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+
+    assert!(run(tmp.path(), &["-r", "--dump-plan", "plan", "tree"])
+        .status
+        .success());
+    assert!(fs::symlink_metadata(tree.join("stub")).unwrap().is_file());
+    let plan = fs::read_to_string(tmp.path().join("plan")).unwrap();
+    let planned: Vec<(&str, &str)> = plan
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            (fields[0], fields[2])
+        })
+        .collect();
+    assert_eq!(planned.len(), 2, "{plan}");
+
+    let output = run(tmp.path(), &["--apply-plan", "plan"]);
+
+    assert!(output.status.success(), "{output:?}");
+    for (path, target) in planned {
+        assert_eq!(fs::read_link(path).unwrap(), Path::new(target));
+    }
+}
+
+#[test]
+fn apply_plan_refuses_stubs_changed_since_planned() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::write(tree.join("other"), "target").unwrap();
+    assert!(run(tmp.path(), &["--dump-plan", "plan", "tree"])
+        .status
+        .success());
+    fs::write(tree.join("other"), "target\n").unwrap();
+
+    let output = run(tmp.path(), &["--apply-plan", "plan"]);
+
+    assert!(fs::symlink_metadata(tree.join("stub"))
+        .unwrap()
+        .is_symlink());
+    assert!(fs::symlink_metadata(tree.join("other")).unwrap().is_file());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("other': content changed since planned, refusing the conversion"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn verbose_interactive_echoes_the_link_confirmed() {
+    let tmp = stub_tree();
+    let resolved = tmp.path().join("tree/target");
+
+    let output = run_with_input(tmp.path(), &["-i", "-v", "tree"], "y\n");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let echo = format!(
+        "Creating symlink: tree/stub -> target (resolved {})",
+        resolved.display()
+    );
+    assert!(stdout.lines().any(|line| line == echo), "{stdout}");
+    assert!(fs::symlink_metadata(tmp.path().join("tree/stub"))
+        .unwrap()
+        .is_symlink());
+}
+
+#[test]
+fn batch_size_prints_running_totals_every_n_conversions() {
+    let tmp = stub_tree();
+    for name in ["second", "third", "fourth", "fifth"] {
+        fs::write(tmp.path().join("tree").join(name), "target").unwrap();
+    }
+
+    let output = run(tmp.path(), &["--batch-size", "2", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let batches: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("Batch "))
+        .collect();
+    assert_eq!(batches.len(), 2, "{stdout}");
+    assert!(batches[0].starts_with("Batch 1: 2 converted, "), "{stdout}");
+    assert!(batches[1].starts_with("Batch 2: 4 converted, "), "{stdout}");
+}
+
+#[test]
+fn summary_format_json_prints_the_totals_last() {
+    let tmp = stub_tree();
+
+    let output = run(tmp.path(), &["--summary-format", "json", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let totals = lines.next_back().unwrap();
+    assert_eq!(
+        lines.collect::<Vec<_>>(),
+        ["Converted to symlink: tree/stub -> target"]
+    );
+    let (fields, elapsed) = totals.split_once(",\"elapsed_seconds\":").unwrap();
+    assert_eq!(
+        fields,
+        r#"{"converted":1,"deleted":0,"restored":0,"skipped":1,"failed":0,"stopped_early":false,"skipped_by_reason":{"target missing":1},"bytes_reclaimed":6"#
+    );
+    assert!(elapsed.strip_suffix('}').unwrap().parse::<f64>().is_ok());
+}
+
+#[test]
+#[cfg(unix)]
+fn warns_about_links_made_into_long_chains() {
+    use std::os::unix::fs::symlink;
+
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    symlink("target", tree.join("first")).unwrap();
+    symlink("first", tree.join("second")).unwrap();
+    fs::write(tree.join("stub"), "second").unwrap();
+    fs::copy(tree.join("stub"), tree.join("copy")).unwrap();
+
+    let output = run(tmp.path(), &["--warn-on-large-targets", "2", "tree/stub"]);
+
+    assert!(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line == "Symlink tree/stub resolves through 3 symlinks, more than 2"),
+        "{output:?}"
+    );
+    let output = run(tmp.path(), &["--warn-on-large-targets", "3", "tree/copy"]);
+    assert!(fs::symlink_metadata(tree.join("copy"))
+        .unwrap()
+        .is_symlink());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("resolves through"));
+}
+
+#[test]
+#[cfg(unix)]
+fn doctor_reports_each_problem_planted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    // Too big to be taken for a stub itself
+    fs::write(tree.join("target"), "a".repeat(600)).unwrap();
+    fs::write(tree.join("stub"), "target").unwrap();
+    std::os::unix::fs::symlink("nowhere", tree.join("broken")).unwrap();
+    fs::write(tree.join("missing"), "nothere").unwrap();
+    fs::write(tree.join("url"), "https://example.com/target").unwrap();
+    fs::write(tree.join("list"), "first\nsecond").unwrap();
+    fs::write(tree.join("control"), "tar\tget").unwrap();
+    fs::write(tree.join("Same"), "target").unwrap();
+    fs::write(tree.join("same"), "target").unwrap();
+
+    let output = run(tmp.path(), &["doctor", "tree"]);
+
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Likely stubs: 3\n\
+         Broken symlinks: 1\n\
+         Stubs with missing targets: 1\n\
+         Suspicious stubs: 3 (URL 1, multiple paths 1, control characters 1)\n\
+         Case collisions: 1\n"
+    );
+    assert!(fs::symlink_metadata(tree.join("stub")).unwrap().is_file());
+
+    for name in ["broken", "missing", "url", "list", "control", "same"] {
+        fs::remove_file(tree.join(name)).unwrap();
+    }
+    assert_eq!(run(tmp.path(), &["doctor", "tree"]).status.code(), Some(0));
+}
+
+#[test]
+#[cfg(unix)]
+fn only_broken_symlinks_repairs_them_from_their_sidecar() {
+    use std::os::unix::fs::symlink;
+
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    symlink("gone", tree.join("broken")).unwrap();
+    fs::write(tree.join("broken.target"), "target").unwrap();
+    symlink("target", tree.join("healthy")).unwrap();
+    fs::write(tree.join("healthy.target"), "stub").unwrap();
+
+    let output = run(tmp.path(), &["--only-broken-symlinks", "tree"]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        fs::read_link(tree.join("broken")).unwrap(),
+        Path::new("target")
+    );
+    assert_eq!(
+        fs::read_link(tree.join("healthy")).unwrap(),
+        Path::new("target")
+    );
+    assert!(fs::symlink_metadata(tree.join("broken.target"))
+        .unwrap()
+        .is_file());
+    assert!(fs::symlink_metadata(tree.join("stub")).unwrap().is_file());
+}
+
+#[test]
+fn base_token_is_replaced_with_the_base_from_the_environment() {
+    let tmp = stub_tree();
+    let base = tmp.path().join("base");
+    fs::create_dir_all(base.join("lib")).unwrap();
+    fs::write(base.join("lib/x"), "content").unwrap();
+    let stub = tmp.path().join("tree/stub");
+    fs::write(&stub, "@BASE@/lib/x").unwrap();
+    let args = ["--no-config", "--base-token", "@BASE@", "tree/stub"];
+
+    let output = Command::new(env!("CARGO_BIN_EXE_restore_symlink"))
+        .current_dir(tmp.path())
+        .env("RESTORE_BASE", &base)
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read_link(&stub).unwrap(), base.join("lib/x"));
+    fs::remove_file(&stub).unwrap();
+    fs::write(&stub, "@BASE@/lib/x").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_restore_symlink"))
+        .current_dir(tmp.path())
+        .env_remove("RESTORE_BASE")
+        .args(args)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+#[cfg(unix)]
+fn output_encoding_escape_shows_bytes_that_are_not_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let tmp = stub_tree();
+    let stub = tmp.path().join("tree").join(OsStr::from_bytes(b"caf\xe9"));
+    fs::write(&stub, "target").unwrap();
+
+    let output = run(tmp.path(), &["--output-encoding", "escape", "-n", "tree"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("tree/caf\\xe9"), "{stdout}");
+    assert!(!stdout.contains('\u{fffd}'));
+    let output = run(tmp.path(), &["-n", "tree"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("tree/caf\u{fffd}"));
+}
+
+#[test]
+fn converted_list_holds_just_the_paths_converted() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/stub"), "../target").unwrap();
+    fs::write(tree.join("missing"), "nowhere").unwrap();
+    fs::write(tree.join("notes"), "first\nsecond").unwrap();
+
+    let output = run(tmp.path(), &["-r", "--converted-list", "list", "tree"]);
+
+    assert!(output.status.success(), "{output:?}");
+    let list = fs::read_to_string(tmp.path().join("list")).unwrap();
+    let mut listed: Vec<&str> = list.lines().collect();
+    listed.sort();
+    assert_eq!(listed, ["tree/stub", "tree/sub/stub"]);
+
+    fs::write(tree.join("again"), "target").unwrap();
+    let output = run(
+        tmp.path(),
+        &[
+            "--converted-list",
+            "list",
+            "--converted-list-null",
+            "tree/again",
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read(tmp.path().join("list")).unwrap(), b"tree/again\0");
+}
+
+#[test]
+fn prune_empty_dirs_removes_only_dirs_the_run_emptied() {
+    let tmp = stub_tree();
+    let tree = tmp.path().join("tree");
+    fs::create_dir_all(tree.join("a/b")).unwrap();
+    fs::write(tree.join("a/b/placeholder"), "").unwrap();
+    fs::create_dir(tree.join("kept")).unwrap();
+
+    let output = run(
+        tmp.path(),
+        &["-r", "--delete-empty", "--prune-empty-dirs", "tree"],
+    );
+
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Removed empty directory: tree/a/b\n"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("Removed empty directory: tree/a\n"),
+        "{stdout}"
+    );
+    assert!(!tree.join("a").exists());
+    assert!(tree.join("kept").is_dir());
+
+    let output = run(
+        tmp.path(),
+        &["-r", "--prune-empty-dirs", "--prune-all-empty", "tree"],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert!(!tree.join("kept").exists());
+    assert!(tree.is_dir());
+}